@@ -0,0 +1,19 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use x3::decoder;
+use x3::x3::Parameters;
+
+//
+// Decode an arbitrary payload, the first two bytes are the number of samples.
+//
+fuzz_target!(|data: &[u8]| {
+  if data.len() < 2 {
+    return;
+  }
+  let samples = u16::from_be_bytes([data[0], data[1]]) as usize;
+  let params = Parameters::default();
+  let mut wav_buf = vec![0i16; samples];
+
+  let _ = decoder::decode_frame(&data[2..], &mut wav_buf, &params, samples);
+});
@@ -0,0 +1,23 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use x3::decoder;
+use x3::x3::{FrameHeader, Parameters};
+
+//
+// Parse an arbitrary frame header, and decode the payload that follows it.
+//
+fuzz_target!(|data: &[u8]| {
+  let header = match decoder::read_frame_header(data) {
+    Ok(header) => header,
+    Err(_) => return,
+  };
+
+  let payload = &data[FrameHeader::LENGTH..];
+  let payload = &payload[0..core::cmp::min(header.payload_len, payload.len())];
+  let samples = header.samples as usize;
+  let params = Parameters::default();
+  let mut wav_buf = vec![0i16; samples];
+
+  let _ = decoder::decode_frame(payload, &mut wav_buf, &params, samples);
+});
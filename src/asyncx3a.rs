@@ -0,0 +1,343 @@
+/**************************************************************************
+ *                                                                        *
+ * Rust implementation of the X3 lossless audio compression protocol.     *
+ *                                                                        *
+ * Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>             *
+ *                                                                        *
+ * This program is free software; you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the           *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.   *
+ *                                                                        *
+ **************************************************************************/
+
+//!
+//! Read and write .x3a (X3 Archive) streams with tokio, such as an archive being uploaded
+//! over HTTP.  The frames are read or written one at a time and are decoded and encoded
+//! with the same code as `X3aReader` and `encodefile`.  Enable with the `tokio` feature.
+//!
+
+// std
+use std::io::ErrorKind;
+
+// externs
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+// this crate
+use crate::bitpacker::BitPacker;
+use crate::crc::crc16;
+use crate::decodefile::{
+  decode_source_frame, frame_channels, read_archive_header, source_index, DecodedX3aFrame, Sources,
+};
+use crate::decoder::{self, DecodeMode};
+use crate::encodefile::create_archive_header;
+use crate::encoder::FrameEncoderState;
+use crate::error::X3Error;
+use crate::metadata::ArchiveMetadata;
+use crate::x3::{self, FrameHeader, Parameters, X3aSpec};
+
+//
+// Read exactly `buf.len()` bytes.
+//
+// Returns false if the end of the stream is reached first.
+//
+async fn read_all<R: AsyncRead + Unpin>(reader: &mut R, buf: &mut [u8]) -> Result<bool, X3Error> {
+  match reader.read_exact(buf).await {
+    Ok(_) => Ok(true),
+    Err(e) if e.kind() == ErrorKind::UnexpectedEof => Ok(false),
+    Err(e) => Err(e.into()),
+  }
+}
+
+///
+/// Read an .x3a (X3 Archive) from an `AsyncRead`, see `decodefile::X3aReader`.
+///
+pub struct AsyncX3aReader<R: AsyncRead + Unpin> {
+  reader: R,
+
+  /// The source id and spec of each stream, and the index of the one being decoded.
+  sources: Sources,
+  selected: usize,
+  metadata: ArchiveMetadata,
+  read_buf: Vec<u8>,
+
+  /// The count of frames that could not be decoded.
+  frame_errors: usize,
+}
+
+impl<R: AsyncRead + Unpin> AsyncX3aReader<R> {
+  ///
+  /// Read the archive header, the frames are read as they are decoded.
+  ///
+  pub async fn new(mut reader: R) -> Result<Self, X3Error> {
+    // <Archive Id> and the header frame, parsed the same as a file
+    let mut header = vec![0u8; x3::Archive::ID.len() + FrameHeader::LENGTH];
+    reader.read_exact(&mut header).await?;
+    let frame_header = FrameHeader::parse(&header[x3::Archive::ID.len()..])?;
+    let mut payload = vec![0u8; frame_header.payload_len];
+    reader.read_exact(&mut payload).await?;
+    header.extend_from_slice(&payload);
+    let (sources, metadata, _) = read_archive_header(&mut &header[..])?;
+
+    Ok(AsyncX3aReader {
+      reader,
+      sources,
+      selected: 0,
+      metadata,
+      read_buf: Vec::with_capacity(x3::Frame::MAX_LENGTH),
+      frame_errors: 0,
+    })
+  }
+
+  /// The spec of the selected source, see `select_source`.
+  pub fn spec(&self) -> &X3aSpec {
+    &self.sources[self.selected].1
+  }
+
+  /// The ids of the sources in the archive, see `X3aReader::source_ids`.
+  pub fn source_ids(&self) -> Vec<u8> {
+    self.sources.iter().map(|(id, _)| *id).collect()
+  }
+
+  /// Select the source that is decoded, the frames of the other sources are skipped.
+  pub fn select_source(&mut self, source_id: u8) -> Result<(), X3Error> {
+    match self.sources.iter().position(|(id, _)| *id == source_id) {
+      Some(i) => {
+        self.selected = i;
+        Ok(())
+      }
+      None => Err(X3Error::InvalidSourceId),
+    }
+  }
+
+  /// All the XML metadata from the archive header.
+  pub fn metadata(&self) -> &ArchiveMetadata {
+    &self.metadata
+  }
+
+  pub fn frame_errors(&self) -> usize {
+    self.frame_errors
+  }
+
+  //
+  // Read the next frame header and its payload into the read buffer.
+  //
+  async fn read_next_frame(&mut self) -> Result<Option<FrameHeader>, X3Error> {
+    let mut header_buf = [0u8; FrameHeader::LENGTH];
+    if !read_all(&mut self.reader, &mut header_buf).await? {
+      return Ok(None);
+    }
+    let header = decoder::parse_frame_header(&header_buf)?;
+
+    self.read_buf.resize(header.payload_len, 0);
+    if !read_all(&mut self.reader, &mut self.read_buf).await? {
+      return Ok(None);
+    }
+    if crc16(&self.read_buf) != header.payload_crc {
+      return Err(X3Error::FrameHeaderInvalidPayloadCRC);
+    }
+    Ok(Some(header))
+  }
+
+  ///
+  /// Decode the next frame of the selected source.  The frames that can't be decoded are
+  /// counted and skipped, the same as `X3aReader::frames`.
+  ///
+  /// ### Returns
+  ///
+  /// * the frame, or `None` when the end of the stream has been reached.
+  ///
+  pub async fn next_frame(&mut self) -> Result<Option<DecodedX3aFrame>, X3Error> {
+    while let Some(header) = self.read_next_frame().await? {
+      if source_index(&self.sources, &header) != Some(self.selected) {
+        continue;
+      }
+      let mut samples = vec![0i16; header.samples as usize * frame_channels(&header)];
+      match decode_source_frame(&header, &self.read_buf, self.spec(), &mut samples, DecodeMode::Wrapping) {
+        Ok(Some(len)) => {
+          samples.truncate(len);
+          return Ok(Some(DecodedX3aFrame { header, samples }));
+        }
+        Ok(None) => (),
+        Err(_) => self.frame_errors += 1,
+      }
+    }
+    Ok(None)
+  }
+
+  ///
+  /// Decode the rest of the selected source.
+  ///
+  pub async fn read_to_end(&mut self) -> Result<Vec<i16>, X3Error> {
+    let mut wav = Vec::new();
+    while let Some(frame) = self.next_frame().await? {
+      wav.extend_from_slice(&frame.samples);
+    }
+    Ok(wav)
+  }
+}
+
+///
+/// Write an .x3a (X3 Archive) to an `AsyncWrite`.  The samples are buffered until a whole
+/// frame can be encoded, so `finish` must be called to write the last frame.  The output is
+/// the same as `encodefile::encode_x3a_to_vec`.
+///
+pub struct AsyncX3aWriter<W: AsyncWrite + Unpin> {
+  writer: W,
+  params: Parameters,
+  sample_rate: u32,
+
+  /// The time of the first sample, see `with_start_time`.
+  start_time: u64,
+
+  /// The samples that have not been encoded yet.
+  buf: Vec<i16>,
+  samples_written: usize,
+  state: FrameEncoderState,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncX3aWriter<W> {
+  ///
+  /// Write the archive header with `metadata`.  The audio is encoded with the sample rate
+  /// and parameters in `metadata`.
+  ///
+  pub async fn new(mut writer: W, metadata: &ArchiveMetadata) -> Result<Self, X3Error> {
+    let sample_rate = metadata.sample_rate().ok_or(X3Error::ArchiveHeaderXMLInvalid)?;
+    let params = metadata.params()?;
+
+    let mut bp = BitPacker::with_capacity(x3::Frame::MAX_LENGTH);
+    create_archive_header(metadata, &mut bp)?;
+    writer.write_all(&bp.into_vec()).await?;
+
+    Ok(AsyncX3aWriter {
+      writer,
+      params,
+      sample_rate,
+      start_time: 0,
+      buf: Vec::new(),
+      samples_written: 0,
+      state: FrameEncoderState::default(),
+    })
+  }
+
+  ///
+  /// The time of the first sample, as for `raw::RawX3Writer::with_start_time`.
+  ///
+  pub fn with_start_time(mut self, start_time: u64) -> Self {
+    self.start_time = start_time;
+    self
+  }
+
+  ///
+  /// Add samples to the archive, each whole frame is encoded and written.
+  ///
+  pub async fn write_samples(&mut self, samples: &[i16]) -> Result<(), X3Error> {
+    self.buf.extend_from_slice(samples);
+    let samples_per_frame = self.params.samples_per_frame();
+    while self.buf.len() >= samples_per_frame {
+      self.write_frame(samples_per_frame).await?;
+    }
+    Ok(())
+  }
+
+  ///
+  /// Encode the samples that are left as a short frame, and flush the writer.
+  ///
+  /// ### Returns
+  ///
+  /// * the writer.
+  ///
+  pub async fn finish(mut self) -> Result<W, X3Error> {
+    if !self.buf.is_empty() {
+      self.write_frame(self.buf.len()).await?;
+    }
+    self.writer.flush().await?;
+    Ok(self.writer)
+  }
+
+  //
+  // Encode the first `samples` samples in the buffer, and write the frames.
+  //
+  async fn write_frame(&mut self, samples: usize) -> Result<(), X3Error> {
+    let (start_time, sample_rate, samples_written) = (self.start_time, self.sample_rate, self.samples_written);
+    let time = |p| x3::sample_time(start_time, sample_rate, samples_written + p);
+    let mut frames = Vec::new();
+    let source_id = x3::Archive::WAV_SOURCE_ID;
+    self.state.encode_frames(&self.buf[0..samples], &self.params, source_id, time, |frame| {
+      frames.extend_from_slice(frame);
+      Ok(())
+    })?;
+    self.writer.write_all(&frames).await?;
+
+    self.samples_written += samples;
+    self.buf.drain(0..samples);
+    Ok(())
+  }
+}
+
+//
+//
+//            #######
+//               #       ######     ####     #####     ####
+//               #       #         #           #      #
+//               #       #####      ####       #       ####
+//               #       #              #      #           #
+//               #       #         #    #      #      #    #
+//               #       ######     ####       #       ####
+//
+//
+
+#[cfg(test)]
+mod tests {
+  use crate::asyncx3a::{AsyncX3aReader, AsyncX3aWriter};
+  use crate::encodefile::encode_x3a_to_vec;
+  use crate::metadata::ArchiveMetadata;
+  use crate::testutil::sine;
+  use crate::x3::{Parameters, X3aSpec};
+
+  #[tokio::test]
+  async fn test_async_round_trip() {
+    let wav = sine(25_001, 0.05, 4000.0, 11);
+    let mut metadata = ArchiveMetadata::new();
+    metadata.set_sample_rate(44100);
+
+    // A small duplex buffer, so the reader and writer take turns
+    let (client, server) = tokio::io::duplex(4096);
+    let writer = async {
+      let mut x3a_writer = AsyncX3aWriter::new(client, &metadata).await.unwrap();
+      for part in wav.chunks(3333) {
+        x3a_writer.write_samples(part).await.unwrap();
+      }
+      x3a_writer.finish().await.unwrap();
+    };
+    let reader = async {
+      let mut x3a_reader = AsyncX3aReader::new(server).await.unwrap();
+      assert_eq!(44100, x3a_reader.spec().sample_rate);
+      x3a_reader.read_to_end().await.unwrap()
+    };
+    let ((), decoded) = tokio::join!(writer, reader);
+    assert!(wav == decoded);
+
+    // The same archive as the one encoded in memory
+    let (client, mut server) = tokio::io::duplex(1 << 20);
+    let mut x3a_writer = AsyncX3aWriter::new(client, &metadata).await.unwrap();
+    x3a_writer.write_samples(&wav).await.unwrap();
+    drop(x3a_writer.finish().await.unwrap());
+    let mut x3a_bytes = Vec::new();
+    tokio::io::AsyncReadExt::read_to_end(&mut server, &mut x3a_bytes).await.unwrap();
+    let spec = X3aSpec {
+      sample_rate: 44100,
+      params: Parameters::default(),
+      channels: 1,
+    };
+    assert_eq!(encode_x3a_to_vec(&wav, &spec).unwrap(), x3a_bytes);
+  }
+}
@@ -22,7 +22,7 @@
 extern crate clap;
 extern crate x3;
 
-use clap::{App, Arg};
+use clap::{App, AppSettings, Arg, SubCommand};
 
 #[derive(PartialEq, Eq)]
 enum AudioFiles {
@@ -45,14 +45,17 @@ fn main() {
     .version("0.3.0")
     .author("Simon Werner <simonwerner@gmail.com>")
     .about("x3 - efficient lossless compression for low entropy audio wav files.")
+    .setting(AppSettings::SubcommandsNegateReqs)
     .arg(
       Arg::with_name("input")
         .short("i")
         .long("input")
         .value_name("FILE")
-        .help("The input file, a .wav or .x3a file")
+        .help("The input file, a .wav or .x3a file.  Several .wav files are encoded as separate sources")
         .required(true)
-        .takes_value(true),
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1),
     )
     .arg(
       Arg::with_name("output")
@@ -63,11 +66,179 @@ fn main() {
         .required(true)
         .takes_value(true),
     )
+    .arg(
+      Arg::with_name("threads")
+        .short("t")
+        .long("threads")
+        .value_name("N")
+        .help("The number of threads used to encode or decode, 0 uses all cores (default: 1)")
+        .takes_value(true),
+    )
+    .arg(
+      Arg::with_name("source")
+        .short("s")
+        .long("source")
+        .value_name("ID")
+        .help("The source id to decode from an .x3a file with several sources (default: 1)")
+        .takes_value(true),
+    )
+    .subcommand(
+      SubCommand::with_name("export")
+        .about("Write each sensor source of an .x3a file to a CSV file")
+        .arg(
+          Arg::with_name("input")
+            .short("i")
+            .long("input")
+            .value_name("FILE")
+            .help("The input .x3a file")
+            .required(true)
+            .takes_value(true),
+        )
+        .arg(
+          Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .value_name("DIR")
+            .help("The directory the CSV files are written to")
+            .required(true)
+            .takes_value(true),
+        ),
+    )
+    .subcommand(
+      SubCommand::with_name("unpack")
+        .about("Unpack a SoundTrap .sud or DTAG .dtg/.bin file to .wav, sensor .csv and log files")
+        .arg(
+          Arg::with_name("input")
+            .short("i")
+            .long("input")
+            .value_name("FILE")
+            .help("The input .sud, .dtg or .bin file")
+            .required(true)
+            .takes_value(true),
+        )
+        .arg(
+          Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .value_name("DIR")
+            .help("The directory the files are written to")
+            .required(true)
+            .takes_value(true),
+        ),
+    )
+    .subcommand(
+      SubCommand::with_name("wrap")
+        .about("Wrap a raw stream of X3 frames, with no archive header, in an .x3a file")
+        .arg(
+          Arg::with_name("input")
+            .short("i")
+            .long("input")
+            .value_name("FILE")
+            .help("The input raw X3 stream")
+            .required(true)
+            .takes_value(true),
+        )
+        .arg(
+          Arg::with_name("output")
+            .short("o")
+            .long("output")
+            .value_name("FILE")
+            .help("The output .x3a file")
+            .required(true)
+            .takes_value(true),
+        )
+        .arg(
+          Arg::with_name("rate")
+            .short("r")
+            .long("rate")
+            .value_name("HZ")
+            .help("The sample rate of the stream")
+            .required(true)
+            .takes_value(true),
+        )
+        .arg(
+          Arg::with_name("channels")
+            .short("c")
+            .long("channels")
+            .value_name("N")
+            .help("The number of channels in each frame (default: 1)")
+            .takes_value(true),
+        )
+        .arg(
+          Arg::with_name("block-len")
+            .short("b")
+            .long("block-len")
+            .value_name("N")
+            .help("The block length the stream was encoded with, with the default codes and thresholds (default: 20)")
+            .takes_value(true),
+        ),
+    )
     .get_matches();
 
-  let in_file = matches.value_of("input").unwrap();
+  if let Some(matches) = matches.subcommand_matches("unpack") {
+    let in_file = matches.value_of("input").unwrap();
+    let out_dir = matches.value_of("output").unwrap();
+    let (out_files, skipped) = if in_file.ends_with(".sud") {
+      x3::sud::sud_to_wav(in_file, out_dir).unwrap()
+    } else if in_file.ends_with(".dtg") || in_file.ends_with(".bin") {
+      x3::dtag::dtg_to_wav(in_file, out_dir).unwrap()
+    } else {
+      panic!("Expecting a .sud, .dtg or .bin file as input: {}", in_file);
+    };
+    for out_file in out_files {
+      println!("Wrote {}", out_file.display());
+    }
+    if skipped > 0 {
+      println!("Skipped {} frames with a bad CRC or that couldn't be decoded", skipped);
+    }
+    return;
+  }
+
+  if let Some(matches) = matches.subcommand_matches("wrap") {
+    let out_file = matches.value_of("output").unwrap();
+    if get_filetype(out_file) != AudioFiles::X3a {
+      panic!("Expecting an .x3a file as output.");
+    }
+    let sample_rate = matches.value_of("rate").unwrap().parse::<u32>().expect("The sample rate must be an integer");
+    let channels = match matches.value_of("channels") {
+      Some(n) => n.parse::<u8>().expect("The number of channels must be an integer from 1 to 255"),
+      None => 1,
+    };
+    let mut params = x3::x3::Parameters::builder();
+    if let Some(n) = matches.value_of("block-len") {
+      params = params.block_len(n.parse::<usize>().expect("The block length must be a positive integer"));
+    }
+    let params = params.build().unwrap();
+    let in_file = matches.value_of("input").unwrap();
+    let skipped = x3::encodefile::raw_to_x3a(in_file, out_file, &params, sample_rate, channels).unwrap();
+    println!("Skipped {} frames with a bad CRC", skipped);
+    return;
+  }
+
+  if let Some(matches) = matches.subcommand_matches("export") {
+    let in_file = matches.value_of("input").unwrap();
+    if get_filetype(in_file) != AudioFiles::X3a {
+      panic!("Expecting an .x3a file as input.");
+    }
+    for csv_file in x3::decodefile::x3a_sensors_to_csv(in_file, matches.value_of("output").unwrap()).unwrap() {
+      println!("Wrote {}", csv_file.display());
+    }
+    return;
+  }
+
+  let in_files: Vec<&str> = matches.values_of("input").unwrap().collect();
+  let in_file = in_files[0];
   let out_file = matches.value_of("output").unwrap();
 
+  let threads = match matches.value_of("threads") {
+    Some(n) => n.parse::<usize>().expect("The number of threads must be a positive integer"),
+    None => 1,
+  };
+
+  let source = matches
+    .value_of("source")
+    .map(|id| id.parse::<u8>().expect("The source id must be an integer from 1 to 255"));
+
   let in_type = get_filetype(in_file);
   let out_type = get_filetype(out_file);
 
@@ -75,8 +246,14 @@ fn main() {
     panic!("Input must be different file type than output.");
   }
 
-  match in_type {
-    AudioFiles::Wav => x3::encodefile::wav_to_x3a(in_file, out_file).unwrap(),
-    AudioFiles::X3a => x3::decodefile::x3a_to_wav(in_file, out_file).unwrap(),
+  if in_files.iter().any(|f| get_filetype(f) != in_type) || (in_type == AudioFiles::X3a && in_files.len() > 1) {
+    panic!("Expecting one .x3a file or one or more .wav files as input.");
+  }
+
+  match (in_type, source) {
+    (AudioFiles::Wav, _) if in_files.len() > 1 => x3::encodefile::wavs_to_x3a(&in_files, out_file).unwrap(),
+    (AudioFiles::Wav, _) => x3::encodefile::wav_to_x3a_parallel(in_file, out_file, threads).unwrap(),
+    (AudioFiles::X3a, Some(id)) => x3::decodefile::x3a_source_to_wav(in_file, id, out_file, threads).unwrap(),
+    (AudioFiles::X3a, None) => x3::decodefile::x3a_to_wav_parallel(in_file, out_file, threads).unwrap(),
   };
 }
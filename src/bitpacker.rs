@@ -29,82 +29,215 @@
 //      ######  #   #   #       #    #  ####  #    # ###### #    #
 //
 
+// std
+use std::io;
+
 #[derive(Debug)]
 pub enum BitPackError {
     NotByteAligned,      // The bytes are not aligned.
     BoundaryReached,     // The soft boundary has been reached.
     ArrayEndReached,     // The end of the array has been reached.
     ExceededBitBoundary, // More bits were read than we expected
+    Io(io::Error),       // Writing the bytes out failed.
+}
+
+/// The number of bits in the accumulator.
+const ACC_BITS: usize = 64;
+
+/// The accumulator is flushed a word at a time.
+const WORD_BITS: usize = 32;
+
+///
+/// Where the packed bytes are written to.
+///
+enum Buffer<'a> {
+    /// A fixed size array, writing past the end is an error.
+    Slice(&'a mut [u8]),
+
+    /// A vector that grows as bytes are written.
+    Vec(Vec<u8>),
+
+    /// A vector that is emptied into the writer each time `flush` is called.
+    Writer(Vec<u8>, &'a mut dyn io::Write),
 }
 
 ///
-/// BitPacker allows individual bits to be written to an array of bytes.
+/// BitPacker allows individual bits to be written to an array of bytes.  The bits are
+/// collected in a 64 bit accumulator and written out a word at a time, so the output
+/// does not need to be zeroed beforehand.
 ///
 pub struct BitPacker<'a> {
-    array: &'a mut [u8],
-    // Bit pointer
+    buf: Buffer<'a>,
+    // Byte pointer, the number of bytes written to `buf`
     p_byte: usize,
-    p_bit: usize,
+    // The number of bytes that have been flushed to the writer
+    flushed: usize,
+    // The lowest `acc_bits` bits of `acc` are yet to be written to `buf`
+    acc: u64,
+    acc_bits: usize,
     // Bookmark
     bm_p_byte: usize,
 }
 
 impl<'a> BitPacker<'a> {
-    pub fn new(array: &'a mut [u8]) -> BitPacker {
+    ///
+    /// Create a BitPacker that writes to a fixed size array.
+    ///
+    pub fn new(array: &'a mut [u8]) -> BitPacker<'a> {
+        BitPacker {
+            buf: Buffer::Slice(array),
+            p_byte: 0,
+            flushed: 0,
+            acc: 0,
+            acc_bits: 0,
+            bm_p_byte: 0,
+        }
+    }
+
+    ///
+    /// Create a BitPacker that writes to a vector, the vector grows as required.
+    ///
+    /// ### Arguments
+    ///
+    /// * `capacity` - The number of bytes to allocate up front.
+    ///
+    pub fn with_capacity(capacity: usize) -> BitPacker<'static> {
         BitPacker {
-            array,
+            buf: Buffer::Vec(Vec::with_capacity(capacity)),
             p_byte: 0,
-            p_bit: 0,
+            flushed: 0,
+            acc: 0,
+            acc_bits: 0,
             bm_p_byte: 0,
         }
     }
 
+    ///
+    /// Create a BitPacker that writes to `writer`.  The bytes are held in memory until
+    /// `flush` is called, this allows the frame header to be written with
+    /// `bookmark_write` once the frame is complete.
+    ///
+    pub fn from_writer(writer: &'a mut dyn io::Write) -> BitPacker<'a> {
+        BitPacker {
+            buf: Buffer::Writer(Vec::new(), writer),
+            p_byte: 0,
+            flushed: 0,
+            acc: 0,
+            acc_bits: 0,
+            bm_p_byte: 0,
+        }
+    }
+
+    ///
+    /// Write bytes to the buffer at the byte pointer.
+    ///
+    #[inline(always)]
+    fn put_bytes(&mut self, bytes: &[u8]) -> Result<(), BitPackError> {
+        let end = self.p_byte + bytes.len();
+        match &mut self.buf {
+            Buffer::Slice(array) => {
+                if end > array.len() {
+                    return Err(BitPackError::ArrayEndReached);
+                }
+                array[self.p_byte..end].copy_from_slice(bytes);
+            }
+            Buffer::Vec(vec) | Buffer::Writer(vec, _) => vec.extend_from_slice(bytes),
+        }
+        self.p_byte = end;
+        Ok(())
+    }
+
+    ///
+    /// Write the top word of the accumulator to the buffer.
+    ///
+    #[inline(always)]
+    fn flush_word(&mut self) -> Result<(), BitPackError> {
+        self.acc_bits -= WORD_BITS;
+        let word = (self.acc >> self.acc_bits) as u32;
+        self.put_bytes(&word.to_be_bytes())
+    }
+
+    ///
+    /// Write all the whole bytes in the accumulator to the buffer.
+    ///
+    fn flush_bytes(&mut self) -> Result<(), BitPackError> {
+        if self.acc_bits >= WORD_BITS {
+            self.flush_word()?;
+        }
+        while self.acc_bits >= 8 {
+            self.acc_bits -= 8;
+            let byte = (self.acc >> self.acc_bits) as u8;
+            self.put_bytes(&[byte])?;
+        }
+        Ok(())
+    }
+
+    ///
+    /// Flush the accumulator, this will fail if the bits are not byte aligned.
+    ///
+    fn flush_aligned(&mut self) -> Result<(), BitPackError> {
+        if !self.acc_bits.is_multiple_of(8) {
+            return Err(BitPackError::NotByteAligned);
+        }
+        self.flush_bytes()
+    }
+
     ///
     /// Save the current position as a bookmark.  Later we will be
     /// able to write an array of bytes to this position.
     ///
-    pub fn bookmark(&mut self) {
+    /// Note: The bit pointer must be byte aligned.
+    ///
+    pub fn bookmark(&mut self) -> Result<(), BitPackError> {
+        self.flush_aligned()?;
         self.bm_p_byte = self.p_byte;
+        Ok(())
     }
 
     ///
-    /// Get the output array from the bookmark, to the last packed bit.
+    /// Get the output array from the bookmark, to the last written byte.
     ///
     pub fn bookmark_get_from(&self) -> &[u8] {
-        &self.array[self.bm_p_byte..self.p_byte]
+        &self.as_bytes()[self.bm_p_byte..]
     }
 
     ///
     /// Get number of bytes from the bookmark to the current pointer.
     ///
     pub fn bookmark_get_offset(&self) -> usize {
-        let offset = if self.p_bit == 0 { 0 } else { 1 };
-        self.p_byte - self.bm_p_byte + offset
+        self.p_byte - self.bm_p_byte + self.acc_bits.div_ceil(8)
     }
 
     ///
     /// Write the array from the bookmark onwards, until array is
-    /// exhausted.
+    /// exhausted.  The bytes must have already been written.
     ///
-    pub fn bookmark_write(&mut self, array: &[u8]) {
-        for (i, value) in array.iter().enumerate() {
-            self.array[self.bm_p_byte + i] = *value;
+    pub fn bookmark_write(&mut self, array: &[u8]) -> Result<(), BitPackError> {
+        let start = self.bm_p_byte;
+        let end = start + array.len();
+        if end > self.p_byte {
+            return Err(BitPackError::BoundaryReached);
+        }
+        match &mut self.buf {
+            Buffer::Slice(buf) => buf[start..end].copy_from_slice(array),
+            Buffer::Vec(buf) | Buffer::Writer(buf, _) => buf[start..end].copy_from_slice(array),
         }
+        Ok(())
     }
 
     ///
     /// Standard write an array
     ///
-    pub fn write_bytes(&mut self, array: &[u8]) {
-        for value in array {
-            self.array[self.p_byte] = *value;
-            self.p_byte += 1;
-        }
+    /// Note: The bit pointer must be byte aligned.
+    ///
+    pub fn write_bytes(&mut self, array: &[u8]) -> Result<(), BitPackError> {
+        self.flush_aligned()?;
+        self.put_bytes(array)
     }
 
     ///
-    /// This operates together with `write_packed_bits`.  It only increments the
-    /// `p_bit` value by 1, also incrementing `p_byte` where necessary.
+    /// Skip `n_bytes` bytes, they are written as zeros and will usually be
+    /// overwritten later using `bookmark_write`.
     ///
     /// Note: The bit pointer must be byte aligned.
     ///
@@ -112,81 +245,116 @@ impl<'a> BitPacker<'a> {
     ///
     /// * `n_bytes` - The number of bytes to increment.
     pub fn inc_counter_n_bytes(&mut self, n_bytes: usize) -> Result<(), BitPackError> {
-        if self.p_bit != 0 {
-            return Err(BitPackError::NotByteAligned);
+        self.flush_aligned()?;
+        for _ in 0..n_bytes {
+            self.put_bytes(&[0])?;
         }
-        self.p_byte += n_bytes;
         Ok(())
     }
 
     ///
     /// Align the packing to the next word, but only if we aren't already aligned.
+    /// All the packed bits will be written out.
     ///
-    pub fn word_align(&mut self) {
-        if self.p_bit != 0 {
-            self.p_byte += 1;
-            self.p_bit = 0;
-        }
-        if self.p_byte % 2 == 1 {
-            self.p_byte += 1;
+    pub fn word_align(&mut self) -> Result<(), BitPackError> {
+        let pad = (8 - self.acc_bits % 8) % 8;
+        self.acc <<= pad;
+        self.acc_bits += pad;
+        self.flush_bytes()?;
+
+        if !self.bytes_written().is_multiple_of(2) {
+            self.put_bytes(&[0])?;
         }
+        Ok(())
     }
 
     ///
-    /// Pack array value into the byte array.  Starting at `p_byte` position of the array and `p_bit` bit offset.
+    /// Pack array value into the byte array, after the last packed bit.
     ///
     /// ### Arguments
     ///
     /// * `value` - The bits that will be written.
-    /// * `num_bits` - The number of bits in `value` that should be written.
+    /// * `num_bits` - The number of bits in `value` that should be written, up to 32.
     ///
     #[inline(always)]
-    pub fn write_bits(&mut self, mut value: usize, num_bits: usize) {
-        let rem_bit = 8 - self.p_bit;
-        let mask = (1 << num_bits) - 1;
-        value &= mask;
-
-        if num_bits == rem_bit {
-            self.array[self.p_byte] |= value as u8;
-            self.p_byte += 1;
-            self.p_bit = 0;
-        } else if num_bits < rem_bit {
-            let shift_l = rem_bit - num_bits;
-            self.array[self.p_byte] |= (value << shift_l) as u8;
-            self.p_bit += num_bits;
-        } else {
-            let shift_r = num_bits - rem_bit;
-            self.array[self.p_byte] |= (value >> shift_r) as u8;
-
-            self.p_bit = 0;
-            self.p_byte += 1;
-
-            self.write_bits(value, shift_r);
+    pub fn write_bits(&mut self, value: usize, num_bits: usize) -> Result<(), BitPackError> {
+        debug_assert!(num_bits <= WORD_BITS);
+
+        if self.acc_bits + num_bits > ACC_BITS {
+            self.flush_word()?;
         }
+        let mask = (1u64 << num_bits) - 1;
+        self.acc = (self.acc << num_bits) | (value as u64 & mask);
+        self.acc_bits += num_bits;
+        Ok(())
     }
 
     ///
-    /// This operates together with `write_packed_bits`.  It allows zero values to be written.  Although
-    /// these are never actually written to the array, the offsets are just managed.
+    /// Write `num_zeros` zero bits.
     ///
     /// ### Arguments
     ///
     /// * `num_zeros` - The number of zeros that should be written.
     ///
     #[inline(always)]
-    pub fn write_packed_zeros(&mut self, num_zeros: usize) {
-        self.p_bit += num_zeros;
-        while self.p_bit >= 8 {
-            self.p_bit -= 8;
-            self.p_byte += 1;
+    pub fn write_packed_zeros(&mut self, mut num_zeros: usize) -> Result<(), BitPackError> {
+        while num_zeros > WORD_BITS {
+            self.write_bits(0, WORD_BITS)?;
+            num_zeros -= WORD_BITS;
         }
+        self.write_bits(0, num_zeros)
     }
 
     ///
-    /// Returns the packed bits as an array.
+    /// Write the completed bytes to the writer, this should be called between frames
+    /// as any bookmark will no longer be valid.  For the other outputs the bytes are
+    /// kept in memory.
+    ///
+    /// Note: The bit pointer must be byte aligned.
+    ///
+    pub fn flush(&mut self) -> Result<(), BitPackError> {
+        self.flush_aligned()?;
+        if let Buffer::Writer(vec, writer) = &mut self.buf {
+            writer.write_all(vec).map_err(BitPackError::Io)?;
+            vec.clear();
+            self.flushed += self.p_byte;
+            self.p_byte = 0;
+            self.bm_p_byte = 0;
+        }
+        Ok(())
+    }
+
+    ///
+    /// The total number of bytes that have been written, including those that have been
+    /// flushed to the writer.
+    ///
+    pub fn bytes_written(&self) -> usize {
+        self.flushed + self.p_byte
+    }
+
+    ///
+    /// Consume the BitPacker, returning the bytes that have been written.
+    ///
+    pub fn into_vec(self) -> Vec<u8> {
+        match self.buf {
+            Buffer::Slice(array) => array[0..self.p_byte].to_vec(),
+            Buffer::Vec(mut vec) | Buffer::Writer(mut vec, _) => {
+                vec.truncate(self.p_byte);
+                vec
+            }
+        }
+    }
+
+    ///
+    /// Returns the bytes that have been written, when writing to a writer only the bytes
+    /// since the last `flush` are returned.  Bits are only guaranteed to have been written
+    /// after `word_align` has been called.
     ///
     pub fn as_bytes(&self) -> &[u8] {
-        &self.array[0..self.p_byte]
+        match &self.buf {
+            Buffer::Slice(array) => &array[0..self.p_byte],
+            Buffer::Vec(vec) | Buffer::Writer(vec, _) => &vec[0..self.p_byte],
+        }
     }
 }
 
@@ -204,98 +372,124 @@ impl<'a> BitPacker<'a> {
 
 #[cfg(test)]
 mod tests {
-    use crate::bitpacker::BitPacker;
+    use crate::bitpacker::{BitPackError, BitPacker};
 
     #[test]
     fn test_write_packed_bits() {
         let inp_arr: &mut [u8] = &mut [0x00, 0x00, 0x00];
         let mut bp = BitPacker::new(inp_arr);
-        bp.p_byte = 1;
-        bp.p_bit = 1;
-        bp.write_bits(0x03, 2);
-        assert_eq!(1, bp.p_byte);
-        assert_eq!(3, bp.p_bit);
-        assert_eq!(&[0x00, 0x60, 0x00], bp.array);
-
-        let inp_arr: &mut [u8] = &mut [0xff, 0x80, 0x00];
-        let mut bp = BitPacker::new(inp_arr);
-        bp.p_byte = 1;
-        bp.p_bit = 1;
-        bp.write_bits(0x03, 2);
-        assert_eq!(1, bp.p_byte);
-        assert_eq!(3, bp.p_bit);
-        assert_eq!(&[0xff, 0xE0, 0x00], bp.array);
+        bp.write_bits(0x00, 9).unwrap();
+        bp.write_bits(0x03, 2).unwrap();
+        bp.word_align().unwrap();
+        assert_eq!(&[0x00, 0x60], bp.as_bytes());
 
         let inp_arr: &mut [u8] = &mut [0x00, 0x00, 0x00];
         let mut bp = BitPacker::new(inp_arr);
-        bp.p_byte = 1;
-        bp.p_bit = 5;
-        bp.write_bits(0x1ff, 9);
-        assert_eq!(2, bp.p_byte);
-        assert_eq!(6, bp.p_bit);
-        assert_eq!(&[0x00, 0x07, 0xfc], bp.array);
-
-        let inp_arr: &mut [u8] = &mut [0xff, 0xfc, 0x00];
+        bp.write_bits(0xff, 8).unwrap();
+        bp.write_bits(0x01, 1).unwrap();
+        bp.write_bits(0x03, 2).unwrap();
+        bp.word_align().unwrap();
+        assert_eq!(&[0xff, 0xE0], bp.as_bytes());
+
+        let inp_arr: &mut [u8] = &mut [0x00, 0x00, 0x00, 0x00];
         let mut bp = BitPacker::new(inp_arr);
-        bp.p_byte = 1;
-        bp.p_bit = 5;
-        bp.write_bits(0x1ff, 9);
-        assert_eq!(2, bp.p_byte);
-        assert_eq!(6, bp.p_bit);
-        assert_eq!(&[0xff, 0xff, 0xfc], bp.array);
+        bp.write_bits(0x00, 13).unwrap();
+        bp.write_bits(0x1ff, 9).unwrap();
+        bp.word_align().unwrap();
+        assert_eq!(&[0x00, 0x07, 0xfc, 0x00], bp.as_bytes());
 
-        let inp_arr: &mut [u8] = &mut [0x00, 0x00, 0x00];
+        let inp_arr: &mut [u8] = &mut [0x00, 0x00, 0x00, 0x00];
         let mut bp = BitPacker::new(inp_arr);
-        bp.p_byte = 0;
-        bp.p_bit = 6;
-        bp.write_bits(0x1f27b, 17);
-        assert_eq!(2, bp.p_byte);
-        assert_eq!(7, bp.p_bit);
-        assert_eq!(&[0x03, 0xe4, 0xf6], bp.array);
-
-        let inp_arr: &mut [u8] = &mut [0xfe, 0x00, 0x00];
+        bp.write_bits(0b000000, 6).unwrap();
+        bp.write_bits(0x1f27b, 17).unwrap();
+        bp.word_align().unwrap();
+        assert_eq!(&[0x03, 0xe4, 0xf6, 0x00], bp.as_bytes());
+
+        let inp_arr: &mut [u8] = &mut [0x00, 0x00, 0x00, 0x00];
         let mut bp = BitPacker::new(inp_arr);
-        bp.p_byte = 0;
-        bp.p_bit = 6;
-        bp.write_bits(0x1f27b, 17);
-        assert_eq!(2, bp.p_byte);
-        assert_eq!(7, bp.p_bit);
-        assert_eq!(&[0xff, 0xe4, 0xf6], bp.array);
+        bp.write_bits(0b111111, 6).unwrap();
+        bp.write_bits(0x1f27b, 17).unwrap();
+        bp.word_align().unwrap();
+        assert_eq!(&[0xff, 0xe4, 0xf6, 0x00], bp.as_bytes());
 
-        let inp_arr: &mut [u8] = &mut [0x00, 0x00, 0x00];
+        let inp_arr: &mut [u8] = &mut [0x00, 0x00, 0x00, 0x00];
         let mut bp = BitPacker::new(inp_arr);
-        bp.p_byte = 1;
-        bp.p_bit = 4;
-        bp.write_bits(0x09, 4);
-        assert_eq!(2, bp.p_byte);
-        assert_eq!(0, bp.p_bit);
-        assert_eq!(&[0x00, 0x09, 0x00], bp.array);
-
-        let inp_arr: &mut [u8] = &mut [0xf0, 0x00, 0x00];
+        bp.write_bits(0xf, 4).unwrap();
+        bp.write_bits(0xffffbe81, 16).unwrap();
+        bp.word_align().unwrap();
+        assert_eq!(&[0xfb, 0xe8, 0x10, 0x00], bp.as_bytes());
+
+        let inp_arr: &mut [u8] = &mut [0x00, 0x00, 0x00, 0x00];
         let mut bp = BitPacker::new(inp_arr);
-        bp.p_byte = 0;
-        bp.p_bit = 4;
-        bp.write_bits(0xffffbe81, 16);
-        assert_eq!(2, bp.p_byte);
-        assert_eq!(4, bp.p_bit);
-        assert_eq!(&[0xfb, 0xe8, 0x10], bp.array);
+        bp.write_bits(0x00, 9).unwrap();
+        bp.write_bits(0xfffffffc, 6).unwrap();
+        bp.word_align().unwrap();
+        assert_eq!(&[0x00, 0x78], bp.as_bytes());
+    }
 
-        let inp_arr: &mut [u8] = &mut [0x00, 0x00, 0x00];
+    #[test]
+    fn test_output_not_zeroed() {
+        let inp_arr: &mut [u8] = &mut [0xaa; 12];
         let mut bp = BitPacker::new(inp_arr);
-        bp.p_byte = 1;
-        bp.p_bit = 1;
-        bp.write_bits(0xfffffffc, 6);
-        assert_eq!(1, bp.p_byte);
-        assert_eq!(7, bp.p_bit);
-        assert_eq!(&[0x00, 0x78, 0x00], bp.array);
+        bp.write_bits(0x01, 1).unwrap();
+        bp.write_packed_zeros(40).unwrap();
+        bp.write_bits(0x7, 3).unwrap();
+        bp.write_bits(0x12345678, 32).unwrap();
+        bp.word_align().unwrap();
+        assert_eq!(&[0x80, 0x00, 0x00, 0x00, 0x00, 0x71, 0x23, 0x45, 0x67, 0x80], bp.as_bytes());
+    }
 
-        let inp_arr: &mut [u8] = &mut [0x00, 0x00, 0x00];
+    #[test]
+    fn test_array_end_reached() {
+        let inp_arr: &mut [u8] = &mut [0x00; 3];
         let mut bp = BitPacker::new(inp_arr);
-        bp.p_byte = 1;
-        bp.p_bit = 2;
-        bp.write_bits(0xfffffffc, 6);
-        assert_eq!(2, bp.p_byte);
-        assert_eq!(0, bp.p_bit);
-        assert_eq!(&[0x00, 0x3c, 0x00], bp.array);
+        bp.write_bits(0x1ffff, 17).unwrap();
+        bp.write_bits(0x1ffff, 17).unwrap();
+        match bp.word_align() {
+            Err(BitPackError::ArrayEndReached) => (),
+            _ => panic!("Expected the end of the array to be reached"),
+        }
+    }
+
+    #[test]
+    fn test_vec_bookmark() {
+        let mut bp = BitPacker::with_capacity(0);
+        bp.write_bytes(&[0x01, 0x02]).unwrap();
+        bp.bookmark().unwrap();
+        bp.inc_counter_n_bytes(2).unwrap();
+        for _ in 0..100 {
+            bp.write_bits(0x5, 3).unwrap();
+        }
+        assert_eq!(40, bp.bookmark_get_offset());
+        bp.word_align().unwrap();
+        bp.bookmark_write(&[0xab, 0xcd]).unwrap();
+
+        let bytes = bp.as_bytes();
+        assert_eq!(42, bytes.len());
+        assert_eq!(&[0x01, 0x02, 0xab, 0xcd, 0xb6, 0xdb, 0x6d], &bytes[0..7]);
+    }
+
+    #[test]
+    fn test_writer_flush() {
+        let mut out: Vec<u8> = Vec::new();
+        {
+            let mut bp = BitPacker::from_writer(&mut out);
+            bp.write_bits(0x7, 3).unwrap();
+            bp.word_align().unwrap();
+            bp.flush().unwrap();
+            assert_eq!(2, bp.bytes_written());
+            assert!(bp.as_bytes().is_empty());
+
+            // The bookmark is relative to the bytes that haven't been flushed
+            bp.write_bytes(&[0x01]).unwrap();
+            bp.bookmark().unwrap();
+            bp.inc_counter_n_bytes(2).unwrap();
+            bp.write_bits(0x1, 1).unwrap();
+            bp.word_align().unwrap();
+            bp.bookmark_write(&[0xab, 0xcd]).unwrap();
+            assert_eq!(6, bp.bytes_written());
+            bp.flush().unwrap();
+        }
+        assert_eq!(&[0xe0, 0x00, 0x01, 0xab, 0xcd, 0x80], &out[..]);
     }
 }
@@ -22,6 +22,9 @@
 // externs
 use crate::byteorder::{BigEndian, ByteOrder};
 
+// this crate
+use crate::error::X3Error;
+
 // TODO: Can we get this from std::u32?
 const BIT_LEN: usize = 32;
 const BYTES_PER_WORD: usize = 4;
@@ -42,7 +45,7 @@ fn read_word(array: &[u8], idx: usize) -> (u32, usize) {
             word |= (array[idx + 1] as u32) << (2 * 8);
         }
         if remaining_idx == 3 {
-            word |= (array[idx + 2] as u32) << (1 * 8);
+            word |= (array[idx + 2] as u32) << 8;
         }
         (word, remaining_idx)
     }
@@ -72,22 +75,47 @@ impl<'a> BitReader<'a> {
         }
     }
 
+    ///
+    /// The number of bits that are yet to be read.
+    ///
+    #[inline(always)]
+    pub fn remaining_bits(&self) -> usize {
+        self.rem_bit + (self.array.len() - self.idx) * 8
+    }
+
+    ///
     /// Increment the bits, load a new byte if required.
+    ///
+    /// ### Arguments
+    ///
+    /// * `n` - The number of bits to skip, less than 32.
+    ///
+    #[inline(always)]
+    pub fn inc_bits(&mut self, n: usize) -> Result<(), X3Error> {
+        // The bits in the leading word are always valid, only check when loading the next
+        if n >= self.rem_bit && n > self.remaining_bits() {
+            return Err(X3Error::FrameDecodeUnexpectedEnd);
+        }
+        self.skip_bits(n);
+        Ok(())
+    }
+
+    //
+    // Increment the bits without checking for the end of the array.
+    //
     #[inline(always)]
-    pub fn inc_bits(&mut self, n: usize) {
+    fn skip_bits(&mut self, n: usize) {
         debug_assert!(n < BIT_LEN);
 
         if n < self.rem_bit {
             self.leading_word <<= n;
             self.rem_bit -= n;
-        } else if n > self.rem_bit {
+        } else {
+            // The next word may be shorter than a whole word at the end of the array
             let rem = n - self.rem_bit;
             self.get_next();
-            self.rem_bit = BIT_LEN - rem;
+            self.rem_bit -= rem;
             self.leading_word <<= rem;
-        } else {
-            //  n == self.rem_bit
-            self.get_next();
         }
     }
 
@@ -100,42 +128,84 @@ impl<'a> BitReader<'a> {
     ///
     /// ### Returns
     ///
-    /// * The unsigned value returned.
+    /// * The unsigned value returned, or `FrameDecodeUnexpectedEnd` if there are fewer
+    ///   than `num_bits` bits left.
     ///
     #[inline(always)]
-    pub fn read_nbits(&mut self, n: usize) -> u32 {
+    pub fn read_nbits(&mut self, n: usize) -> Result<u32, X3Error> {
+        debug_assert!(n > 0 && n < BIT_LEN);
+
         if n <= self.rem_bit {
             let result = self.leading_word >> (BIT_LEN - n);
-            self.inc_bits(n);
-            result
+            self.skip_bits(n);
+            Ok(result)
+        } else if n > self.remaining_bits() {
+            Err(X3Error::FrameDecodeUnexpectedEnd)
         } else {
             let rem = n - self.rem_bit;
             let mut result = self.leading_word >> (BIT_LEN - n);
-            self.inc_bits(self.rem_bit);
+            self.skip_bits(self.rem_bit);
             result |= self.leading_word >> (BIT_LEN - rem);
-            self.inc_bits(rem);
+            self.skip_bits(rem);
+            Ok(result)
+        }
+    }
+
+    ///
+    /// Read the next n bits without incrementing the pointer.  Bits beyond the end of
+    /// the array are read as zeros.
+    ///
+    /// ### Arguments
+    ///
+    /// * `n` - The number of bits to peek at, up to 32.
+    ///
+    #[inline(always)]
+    pub fn peek_nbits(&self, n: usize) -> u32 {
+        debug_assert!(n > 0 && n <= BIT_LEN);
+
+        let result = self.leading_word >> (BIT_LEN - n);
+        if n <= self.rem_bit {
             result
+        } else {
+            match self.peek_next() {
+                Some((word, _)) => result | (word >> (BIT_LEN - n + self.rem_bit)),
+                None => result,
+            }
         }
     }
 
     ///
-    /// Read the number of zeros in a packed bit array.  Loads a new byte if needed.
+    /// Read the number of zeros in a packed bit array.  Loads a new byte if needed.  The
+    /// zeros must be followed by a one, which is not consumed.
+    ///
+    /// ### Arguments
+    ///
+    /// * `max` - The most zeros that are valid, less than 32.
     ///
     /// ### Returns
     ///
-    /// * the number of consectutive zeros found in the array.
+    /// * the number of consectutive zeros found in the array, `OutOfBoundsInverse` if
+    ///   there are more than `max`, or `FrameDecodeUnexpectedEnd` if the array ends first.
     ///
     #[inline(always)]
-    pub fn count_zero_bits(&mut self) -> usize {
+    pub fn count_zero_bits(&mut self, max: usize) -> Result<usize, X3Error> {
+        debug_assert!(max < BIT_LEN);
+
         let mut count = self.leading_word.leading_zeros() as usize;
-        if count > self.rem_bit {
+        if count >= self.rem_bit {
             count = match self.peek_next() {
                 Some((word, _)) => self.rem_bit + word.leading_zeros() as usize,
                 None => self.rem_bit,
             };
+            if count >= self.remaining_bits() {
+                return Err(X3Error::FrameDecodeUnexpectedEnd);
+            }
         }
-        self.inc_bits(count);
-        count
+        if count > max {
+            return Err(X3Error::OutOfBoundsInverse);
+        }
+        self.skip_bits(count);
+        Ok(count)
     }
 
     ///
@@ -190,6 +260,7 @@ impl<'a> BitReader<'a> {
 #[cfg(test)]
 mod tests {
     use crate::bitreader::BitReader;
+    use crate::error::X3Error;
 
     #[test]
     fn test_bitreader_init() {
@@ -216,7 +287,7 @@ mod tests {
 
         // Read first 12 zero
         {
-            let zeros = br.count_zero_bits();
+            let zeros = br.count_zero_bits(31).unwrap();
             assert_eq!(12, zeros);
             assert_eq!(20, br.rem_bit);
             assert_eq!(0xff000000, br.leading_word);
@@ -224,7 +295,7 @@ mod tests {
 
         // Next there are no zeros
         {
-            let zeros = br.count_zero_bits();
+            let zeros = br.count_zero_bits(31).unwrap();
             assert_eq!(0, zeros);
             assert_eq!(20, br.rem_bit);
             assert_eq!(0xff000000, br.leading_word);
@@ -232,26 +303,65 @@ mod tests {
 
         // Skip some bits
         {
-            let byte = br.read_nbits(7);
+            let byte = br.read_nbits(7).unwrap();
             assert_eq!(0x7f, byte);
             assert_eq!(13, br.rem_bit);
             assert_eq!(0x80000000, br.leading_word);
         }
         {
-            let byte = br.read_nbits(1);
+            let byte = br.read_nbits(1).unwrap();
             assert_eq!(0x01, byte);
             assert_eq!(12, br.rem_bit);
             assert_eq!(0x00000000, br.leading_word);
         }
 
-        // Read to the end
+        // The zeros run to the end, there is no terminating one
         {
-            let zeros = br.count_zero_bits();
-            assert_eq!(12, zeros);
+            match br.count_zero_bits(31) {
+                Err(X3Error::FrameDecodeUnexpectedEnd) => (),
+                _ => panic!("Expected the end of the array"),
+            }
+            assert_eq!(12, br.rem_bit);
+        }
+    }
 
-            assert_eq!(0, br.rem_bit);
-            assert_eq!(0x00000000, br.leading_word);
+    #[test]
+    fn test_peek_nbits() {
+        let inp_arr: &mut [u8] = &mut [0x01, 0x23, 0x45, 0x67, 0x89, 0xab];
+        let mut br = BitReader::new(inp_arr);
+
+        assert_eq!(0x0123, br.peek_nbits(16));
+        assert_eq!(0x01234567, br.peek_nbits(32));
+
+        // Peek across the word boundary
+        br.read_nbits(20).unwrap();
+        assert_eq!(0x56789, br.peek_nbits(20));
+        assert_eq!(0x56789, br.read_nbits(20).unwrap());
+
+        // Peek past the end of the array
+        assert_eq!(0xab00, br.peek_nbits(16));
+    }
+
+    #[test]
+    fn test_read_past_end() {
+        let inp_arr: &mut [u8] = &mut [0x01, 0x23, 0x45, 0x67, 0x89, 0xab];
+        let mut br = BitReader::new(inp_arr);
+
+        // The last word only has two bytes
+        br.read_nbits(20).unwrap();
+        br.read_nbits(20).unwrap();
+        assert_eq!(8, br.remaining_bits());
+
+        match br.read_nbits(9) {
+            Err(X3Error::FrameDecodeUnexpectedEnd) => (),
+            _ => panic!("Expected the end of the array"),
+        }
+        match br.inc_bits(9) {
+            Err(X3Error::FrameDecodeUnexpectedEnd) => (),
+            _ => panic!("Expected the end of the array"),
         }
+        assert_eq!(0xab, br.read_nbits(8).unwrap());
+        assert_eq!(0, br.remaining_bits());
     }
 
     #[test]
@@ -268,7 +378,7 @@ mod tests {
 
         // Load next bytes
         {
-            let byte = br.read_nbits(20);
+            let byte = br.read_nbits(20).unwrap();
             assert_eq!(0b00000001001000110100, byte);
             assert_eq!(12, br.rem_bit);
             assert_eq!(0b010101100111 << 20, br.leading_word);
@@ -276,28 +386,28 @@ mod tests {
 
         // Load next bits
         {
-            let bit = br.read_nbits(1);
+            let bit = br.read_nbits(1).unwrap();
             assert_eq!(0b0, bit);
             assert_eq!(0b10101100111000000000000000000000, br.leading_word);
 
-            let bit = br.read_nbits(1);
+            let bit = br.read_nbits(1).unwrap();
             assert_eq!(0b1, bit);
             assert_eq!(0b01011001110000000000000000000000, br.leading_word);
         }
         {
-            let bits = br.read_nbits(5);
+            let bits = br.read_nbits(5).unwrap();
             assert_eq!(0b01011, bits);
             assert_eq!(0b00111000000000000000000000000000, br.leading_word);
 
-            let bits = br.read_nbits(6);
+            let bits = br.read_nbits(6).unwrap();
             assert_eq!(0b001111, bits);
             assert_eq!(0b00010011010101111001101111011110, br.leading_word);
 
-            let bits = br.read_nbits(31);
+            let bits = br.read_nbits(31).unwrap();
             assert_eq!(0x09abcdef, bits);
             assert_eq!(0x01000000, br.leading_word);
 
-            let bits = br.read_nbits(8);
+            let bits = br.read_nbits(8).unwrap();
             assert_eq!(0x01, bits);
             assert_eq!(0, br.leading_word);
         }
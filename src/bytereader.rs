@@ -43,7 +43,7 @@ pub struct ByteReader<'a> {
 }
 
 impl<'a> ByteReader<'a> {
-  pub fn new(array: &'a [u8]) -> ByteReader {
+  pub fn new(array: &'a [u8]) -> ByteReader<'a> {
     ByteReader { array, p_byte: 0 }
   }
 
@@ -96,13 +96,12 @@ impl<'a> ByteReader<'a> {
   /// * `buf` - The array where the bytes will be written to.
   ///
   #[inline(always)]
+  #[allow(clippy::should_implement_trait)]
   pub fn eq(&self, buf: &[u8]) -> bool {
-    let mut p = self.p_byte;
-    for b in buf {
-      if *b != self.array[p] {
+    for (p, b) in buf.iter().enumerate() {
+      if *b != self.array[self.p_byte + p] {
         return false;
       };
-      p += 1;
     }
     true
   }
@@ -21,30 +21,54 @@
 
 // std
 use std::fs::File;
-use std::io::{prelude::*, BufReader};
+use std::io::{prelude::*, BufReader, BufWriter};
 use std::path;
+use std::thread;
 
 // externs
 use crate::hound;
 
 // this crate
-use crate::decoder;
+use crate::decoder::{self, DecodeMode};
 use crate::error;
+use crate::metadata::ArchiveMetadata;
+use crate::sensor::Sensor;
+use crate::wavchunks::WavChunks;
 use crate::{crc, x3};
 
 use crate::x3::{FrameHeader, X3aSpec};
 use error::X3Error;
-use quick_xml::events::Event;
-use quick_xml::Reader;
 
-pub const X3_READ_BUFFER_SIZE: usize = 1024 * 24;
+pub const X3_READ_BUFFER_SIZE: usize = x3::Frame::MAX_LENGTH;
 pub const X3_WRITE_BUFFER_SIZE: usize = X3_READ_BUFFER_SIZE * 8;
 
+/// The number of frames each thread decodes in one pass of `x3a_to_wav_parallel`.
+const FRAMES_PER_THREAD: usize = 16;
+
+/// The samples of a decoded frame, or the error encountered while decoding it.
+pub type DecodedFrame = Result<Vec<i16>, X3Error>;
+
+/// A decoded frame of the selected source, see `X3aReader::frames`.
+pub struct DecodedX3aFrame {
+  pub header: FrameHeader,
+
+  /// The samples, the channels are interleaved.
+  pub samples: Vec<i16>,
+}
+
+// The source id and spec of each stream in an archive.
+pub(crate) type Sources = Vec<(u8, X3aSpec)>;
+
 pub struct X3aReader {
   reader: BufReader<File>,
-  spec: X3aSpec,
+
+  /// The source id and spec of each stream, and the index of the one being decoded.
+  sources: Sources,
+  selected: usize,
+  metadata: ArchiveMetadata,
   remaing_bytes: usize,
   read_buf: [u8; X3_READ_BUFFER_SIZE],
+  mode: DecodeMode,
 
   /// The count of errors.
   /// TODO: Count each type of error
@@ -57,20 +81,80 @@ impl X3aReader {
     let mut remaing_bytes = file.metadata()?.len() as usize;
     let mut reader = BufReader::with_capacity(64 * 1024, file);
 
-    let (spec, header_size) = read_archive_header(&mut reader)?;
+    let (sources, metadata, header_size) = read_archive_header(&mut reader)?;
     remaing_bytes -= header_size;
 
     Ok(Self {
       reader,
-      spec,
+      sources,
+      selected: 0,
+      metadata,
       remaing_bytes,
       read_buf: [0u8; X3_READ_BUFFER_SIZE],
+      mode: DecodeMode::default(),
       frame_errors: 0,
     })
   }
 
+  ///
+  /// The spec of the selected source, see `select_source`.
+  ///
   pub fn spec(&self) -> &X3aSpec {
-    &self.spec
+    &self.sources[self.selected].1
+  }
+
+  ///
+  /// The ids of the sources in the archive, the first is selected when the archive is
+  /// opened.  That is the audio in `<CFG ID="1">` if there is one.
+  ///
+  pub fn source_ids(&self) -> Vec<u8> {
+    self.sources.iter().map(|(id, _)| *id).collect()
+  }
+
+  /// The id of the selected source.
+  pub fn source_id(&self) -> u8 {
+    self.sources[self.selected].0
+  }
+
+  pub fn source_spec(&self, source_id: u8) -> Option<&X3aSpec> {
+    self.sources.iter().find(|(id, _)| *id == source_id).map(|(_, spec)| spec)
+  }
+
+  ///
+  /// Select the source that `decode_next_frame` and `decode_frames_parallel` decode, the
+  /// frames of the other sources are skipped.
+  ///
+  pub fn select_source(&mut self, source_id: u8) -> Result<(), X3Error> {
+    match self.sources.iter().position(|(id, _)| *id == source_id) {
+      Some(i) => {
+        self.selected = i;
+        Ok(())
+      }
+      None => Err(X3Error::InvalidSourceId),
+    }
+  }
+
+  //
+  // The index of the source of a frame.  Archives with one source may not use its id in
+  // the frame headers, so all the frames belong to it.
+  //
+  fn frame_source(&self, header: &FrameHeader) -> Option<usize> {
+    source_index(&self.sources, header)
+  }
+
+  ///
+  /// All the XML metadata from the archive header.
+  ///
+  pub fn metadata(&self) -> &ArchiveMetadata {
+    &self.metadata
+  }
+
+  ///
+  /// Set how samples that overflow are handled, by default they wrap around.  In
+  /// `DecodeMode::Strict` the frames with overflowing samples are counted as errors.
+  ///
+  pub fn set_decode_mode(&mut self, mode: DecodeMode) {
+    self.mode = mode;
   }
 
   fn read_bytes(&mut self, mut buf_len: usize) -> std::io::Result<()> {
@@ -83,14 +167,14 @@ impl X3aReader {
 
   fn read_frame_header(&mut self) -> Result<FrameHeader, X3Error> {
     self.read_bytes(x3::FrameHeader::LENGTH)?;
-    decoder::read_frame_header(&self.read_buf[0..x3::FrameHeader::LENGTH])
+    decoder::parse_frame_header(&self.read_buf[0..x3::FrameHeader::LENGTH])
   }
 
   fn read_frame_payload(&mut self, header: &FrameHeader) -> Result<(), X3Error> {
     self.read_bytes(header.payload_len)?;
 
     let payload = &self.read_buf[0..header.payload_len];
-    let crc = crc::crc16(&payload);
+    let crc = crc::crc16(payload);
     if crc != header.payload_crc {
       return Err(X3Error::FrameHeaderInvalidPayloadCRC);
     }
@@ -98,7 +182,14 @@ impl X3aReader {
     Ok(())
   }
 
-  pub fn decode_next_frame(&mut self, wav_buf: &mut [i16; X3_WRITE_BUFFER_SIZE]) -> Result<Option<usize>, X3Error> {
+  ///
+  /// Read the next frame header and its payload into the read buffer.
+  ///
+  /// ### Returns
+  ///
+  /// * the frame header, or `None` when the end of the file has been reached.
+  ///
+  fn read_next_frame(&mut self) -> Result<Option<FrameHeader>, X3Error> {
     // We have reached the end of the file
     if self.remaing_bytes <= x3::FrameHeader::LENGTH {
       return Ok(None);
@@ -106,7 +197,6 @@ impl X3aReader {
 
     // Get the header details
     let frame_header = self.read_frame_header()?;
-    let samples = frame_header.samples as usize;
     if self.remaing_bytes < frame_header.payload_len {
       return Ok(None);
     }
@@ -118,24 +208,456 @@ impl X3aReader {
 
     // Get the Payload
     self.read_frame_payload(&frame_header)?;
-    let x3_bytes = &mut self.read_buf[0..frame_header.payload_len];
+
+    Ok(Some(frame_header))
+  }
+
+  //
+  // Read the frames up to the next one from the selected source.
+  //
+  fn read_next_selected_frame(&mut self) -> Result<Option<FrameHeader>, X3Error> {
+    while let Some(frame_header) = self.read_next_frame()? {
+      if self.frame_source(&frame_header) == Some(self.selected) {
+        return Ok(Some(frame_header));
+      }
+    }
+    Ok(None)
+  }
+
+  ///
+  /// Decode the next frame of the selected source, the channels are interleaved.
+  ///
+  pub fn decode_next_frame(&mut self, wav_buf: &mut [i16; X3_WRITE_BUFFER_SIZE]) -> Result<Option<usize>, X3Error> {
+    let frame_header = match self.read_next_selected_frame()? {
+      Some(frame_header) => frame_header,
+      None => return Ok(None),
+    };
+    self.decode_frame(&frame_header, self.selected, wav_buf)
+  }
+
+  ///
+  /// Decode the next frame of any source, for demultiplexing an archive with several
+  /// sources.  The frames of sources that are not in the archive header are skipped.
+  ///
+  /// ### Returns
+  ///
+  /// * the source id of the frame and the number of samples decoded, or `None` when the end
+  ///   of the file has been reached.
+  ///
+  pub fn decode_next_source_frame(
+    &mut self,
+    wav_buf: &mut [i16; X3_WRITE_BUFFER_SIZE],
+  ) -> Result<Option<(u8, usize)>, X3Error> {
+    let source_ids = self.source_ids();
+    self.decode_next_frame_from(&source_ids, wav_buf)
+  }
+
+  ///
+  /// Decode the next frame of one of `source_ids`, the same as `decode_next_source_frame`.
+  /// The frames of the other sources are skipped without being decoded.
+  ///
+  pub fn decode_next_frame_from(
+    &mut self,
+    source_ids: &[u8],
+    wav_buf: &mut [i16; X3_WRITE_BUFFER_SIZE],
+  ) -> Result<Option<(u8, usize)>, X3Error> {
+    while let Some(frame_header) = self.read_next_frame()? {
+      let source = match self.frame_source(&frame_header) {
+        Some(source) if source_ids.contains(&self.sources[source].0) => source,
+        _ => continue,
+      };
+      let samples = self.decode_frame(&frame_header, source, wav_buf)?;
+      return Ok(samples.map(|samples| (self.sources[source].0, samples)));
+    }
+    Ok(None)
+  }
+
+  //
+  // Decode the frame in the read buffer with the parameters of `source`.
+  //
+  fn decode_frame(&mut self, header: &FrameHeader, source: usize, wav_buf: &mut [i16]) -> Result<Option<usize>, X3Error> {
+    let x3_bytes = &self.read_buf[0..header.payload_len];
+    let spec = &self.sources[source].1;
 
     // Do the decoding
-    match decoder::decode_frame(x3_bytes, wav_buf, &self.spec.params, samples) {
+    match decode_source_frame(header, x3_bytes, spec, wav_buf, self.mode) {
       Ok(result) => Ok(result),
-      Err(err) => {
+      Err(_) => {
         self.frame_errors += 1;
-        println!("Frame error: {:?}", err);
         Ok(None)
       }
     }
   }
+
+  ///
+  /// Read up to `num_frames` frames of the selected source ahead and decode them using
+  /// `num_threads` threads.  The frames are returned in the order they appear in the file,
+  /// each with the decoded samples or the error that stopped the frame from being decoded.
+  ///
+  /// ### Arguments
+  ///
+  /// * `num_frames` - the maximum number of frames to read.
+  /// * `num_threads` - the number of threads to decode with, `0` will use all the available cores.
+  ///
+  /// ### Returns
+  ///
+  /// * the decoded frames, this will be empty when the end of the file has been reached.
+  ///
+  pub fn decode_frames_parallel(&mut self, num_frames: usize, num_threads: usize) -> Result<Vec<DecodedFrame>, X3Error> {
+    // Read the frames ahead
+    let mut frames = Vec::with_capacity(num_frames);
+    while frames.len() < num_frames {
+      match self.read_next_selected_frame()? {
+        Some(frame_header) => {
+          let payload = self.read_buf[0..frame_header.payload_len].to_vec();
+          frames.push((frame_header, payload));
+        }
+        None => break,
+      }
+    }
+
+    let num_threads = match num_threads {
+      0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+      n => n,
+    };
+    let frames_per_thread = std::cmp::max(1, frames.len().div_ceil(num_threads));
+    let spec = self.spec();
+    let mode = self.mode;
+
+    let decoded: Vec<DecodedFrame> = thread::scope(|s| {
+      let workers: Vec<_> = frames
+        .chunks(frames_per_thread)
+        .map(|group| {
+          s.spawn(move || {
+            group
+              .iter()
+              .map(|(header, payload)| {
+                let mut wav = vec![0i16; header.samples as usize * frame_channels(header)];
+                let len = decode_source_frame(header, payload, spec, &mut wav, mode)?.unwrap_or(0);
+                wav.truncate(len);
+                Ok(wav)
+              })
+              .collect::<Vec<DecodedFrame>>()
+          })
+        })
+        .collect();
+
+      workers
+        .into_iter()
+        .flat_map(|w| w.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+        .collect()
+    });
+
+    self.frame_errors += decoded.iter().filter(|frame| frame.is_err()).count();
+
+    Ok(decoded)
+  }
+
+  //
+  // Decode the next frame of the selected source into its own buffer.  The frames that
+  // can't be decoded are counted and skipped.
+  //
+  fn next_decoded_frame(&mut self) -> Result<Option<DecodedX3aFrame>, X3Error> {
+    while let Some(header) = self.read_next_selected_frame()? {
+      let mut samples = vec![0i16; header.samples as usize * frame_channels(&header)];
+      if let Some(len) = self.decode_frame(&header, self.selected, &mut samples)? {
+        samples.truncate(len);
+        return Ok(Some(DecodedX3aFrame { header, samples }));
+      }
+    }
+    Ok(None)
+  }
+
+  ///
+  /// The decoded frames of the selected source, each with its frame header.  The frames
+  /// that can't be decoded are counted and skipped, the iterator ends after an error
+  /// reading the file.
+  ///
+  pub fn frames(&mut self) -> impl Iterator<Item = Result<DecodedX3aFrame, X3Error>> + '_ {
+    let mut done = false;
+    std::iter::from_fn(move || {
+      if done {
+        return None;
+      }
+      let frame = self.next_decoded_frame().transpose();
+      done = !matches!(frame, Some(Ok(_)));
+      frame
+    })
+  }
+
+  ///
+  /// The samples of the selected source, see `frames`.
+  ///
+  pub fn samples(&mut self) -> impl Iterator<Item = Result<i16, X3Error>> + '_ {
+    self.frames().flat_map(|frame| {
+      let (samples, err) = match frame {
+        Ok(frame) => (frame.samples, None),
+        Err(err) => (Vec::new(), Some(Err(err))),
+      };
+      samples.into_iter().map(Ok).chain(err)
+    })
+  }
+
+  ///
+  /// Read the samples of the selected source as little endian 16 bit PCM, to pass the
+  /// audio to something that reads raw PCM.
+  ///
+  pub fn into_pcm_reader(self) -> X3aPcmReader {
+    X3aPcmReader {
+      x3a_reader: self,
+      buf: Vec::new(),
+      pos: 0,
+    }
+  }
+}
+
+///
+/// The samples of an archive as little endian 16 bit PCM bytes, see
+/// `X3aReader::into_pcm_reader`.  The errors are `io::ErrorKind::InvalidData`, apart from
+/// the errors reading the file.
+///
+pub struct X3aPcmReader {
+  x3a_reader: X3aReader,
+
+  /// The bytes of the last frame, and the number that have been read.
+  buf: Vec<u8>,
+  pos: usize,
+}
+
+impl X3aPcmReader {
+  pub fn into_inner(self) -> X3aReader {
+    self.x3a_reader
+  }
+}
+
+impl Read for X3aPcmReader {
+  fn read(&mut self, out: &mut [u8]) -> std::io::Result<usize> {
+    if out.is_empty() {
+      return Ok(0);
+    }
+    while self.pos == self.buf.len() {
+      let frame = match self.x3a_reader.next_decoded_frame()? {
+        Some(frame) => frame,
+        None => return Ok(0),
+      };
+      self.buf.clear();
+      self.buf.extend(frame.samples.iter().flat_map(|s| s.to_le_bytes()));
+      self.pos = 0;
+    }
+
+    let len = std::cmp::min(out.len(), self.buf.len() - self.pos);
+    out[0..len].copy_from_slice(&self.buf[self.pos..(self.pos + len)]);
+    self.pos += len;
+    Ok(len)
+  }
+}
+
+//
+// The number of channels in a frame, the frames of older archives have 0 for one channel.
+//
+pub(crate) fn frame_channels(header: &FrameHeader) -> usize {
+  std::cmp::max(1, header.channels as usize)
+}
+
+//
+// Decode the payload of a frame from the source with `spec` into `wav_buf`, the channels
+// are interleaved.  A frame with a different number of channels to the source is an error.
+//
+pub(crate) fn decode_source_frame(
+  header: &FrameHeader,
+  payload: &[u8],
+  spec: &X3aSpec,
+  wav_buf: &mut [i16],
+  mode: DecodeMode,
+) -> Result<Option<usize>, X3Error> {
+  let channels = frame_channels(header);
+  if channels != std::cmp::max(1, spec.channels as usize) {
+    return Err(X3Error::InvalidChannels);
+  }
+  decoder::decode_frame_channels_with_mode(payload, wav_buf, &spec.params, header.samples as usize, channels, mode)
+}
+
+//
+// The index in `sources` of the source of a frame, see `X3aReader::frame_source`.
+//
+pub(crate) fn source_index(sources: &Sources, header: &FrameHeader) -> Option<usize> {
+  if sources.len() == 1 {
+    return Some(0);
+  }
+  sources.iter().position(|(id, _)| *id == header.source_id)
+}
+
+///
+/// A frame of an archive in memory, the payload is borrowed from the archive.
+///
+pub struct X3aFrame<'a> {
+  pub header: FrameHeader,
+  pub payload: &'a [u8],
+}
+
+///
+/// The frames of an archive in memory, in the order they appear, see `x3::Decoder::frames`.
+/// The frame header and payload CRCs are checked.
+///
+pub struct X3aFrames<'a> {
+  bytes: &'a [u8],
+}
+
+impl<'a> Iterator for X3aFrames<'a> {
+  type Item = Result<X3aFrame<'a>, X3Error>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    // We have reached the end of the archive
+    if self.bytes.len() <= x3::FrameHeader::LENGTH {
+      return None;
+    }
+
+    let header = match decoder::parse_frame_header(self.bytes) {
+      Ok(header) => header,
+      Err(err) => {
+        // The rest can't be split into frames
+        self.bytes = &[];
+        return Some(Err(err));
+      }
+    };
+    let frame_len = x3::FrameHeader::LENGTH + header.payload_len;
+    if self.bytes.len() < frame_len {
+      self.bytes = &[];
+      return None;
+    }
+
+    let payload = &self.bytes[x3::FrameHeader::LENGTH..frame_len];
+    self.bytes = &self.bytes[frame_len..];
+    if crc::crc16(payload) != header.payload_crc {
+      return Some(Err(X3Error::FrameHeaderInvalidPayloadCRC));
+    }
+    Some(Ok(X3aFrame { header, payload }))
+  }
+}
+
+impl<'a> x3::Decoder<'a> {
+  ///
+  /// Read the archive header of the archive in `x3_inp`, the frames are read as they are
+  /// decoded.
+  ///
+  pub fn new(x3_inp: &'a [u8]) -> Result<Self, X3Error> {
+    let mut reader = x3_inp;
+    let (sources, metadata, header_size) = read_archive_header(&mut reader)?;
+
+    Ok(x3::Decoder {
+      x3_inp,
+      sources,
+      selected: 0,
+      metadata,
+      header_len: x3::Archive::ID.len() + header_size,
+      frame_errors: 0,
+    })
+  }
+
+  /// The spec of the selected source, see `X3aReader::spec`.
+  pub fn spec(&self) -> &X3aSpec {
+    &self.sources[self.selected].1
+  }
+
+  /// The ids of the sources in the archive, see `X3aReader::source_ids`.
+  pub fn source_ids(&self) -> Vec<u8> {
+    self.sources.iter().map(|(id, _)| *id).collect()
+  }
+
+  /// Select the source that `decode` decodes.
+  pub fn select_source(&mut self, source_id: u8) -> Result<(), X3Error> {
+    match self.sources.iter().position(|(id, _)| *id == source_id) {
+      Some(i) => {
+        self.selected = i;
+        Ok(())
+      }
+      None => Err(X3Error::InvalidSourceId),
+    }
+  }
+
+  /// All the XML metadata from the archive header.
+  pub fn metadata(&self) -> &ArchiveMetadata {
+    &self.metadata
+  }
+
+  /// The count of frames that could not be decoded.
+  pub fn frame_errors(&self) -> usize {
+    self.frame_errors
+  }
+
+  ///
+  /// The frames of all the sources, the payloads are borrowed from the archive without
+  /// being copied.
+  ///
+  pub fn frames(&self) -> X3aFrames<'a> {
+    X3aFrames {
+      bytes: &self.x3_inp[self.header_len..],
+    }
+  }
+
+  ///
+  /// Decode the frames of the selected source, the channels are interleaved.  The frames
+  /// with a bad payload CRC, or that can't be decoded, are counted and skipped.  A bad frame
+  /// header is an error, the rest of the archive can't be split into frames.
+  ///
+  /// ### Returns
+  ///
+  /// * the samples.
+  ///
+  pub fn decode(&mut self) -> Result<Vec<i16>, X3Error> {
+    let mut wav = Vec::new();
+    let mut wav_buf = Vec::new();
+    for frame in self.frames() {
+      let frame = match frame {
+        Ok(frame) => frame,
+        Err(X3Error::FrameHeaderInvalidPayloadCRC) => {
+          self.frame_errors += 1;
+          continue;
+        }
+        Err(err) => return Err(err),
+      };
+      if source_index(&self.sources, &frame.header) != Some(self.selected) {
+        continue;
+      }
+      wav_buf.resize(frame.header.samples as usize * frame_channels(&frame.header), 0);
+      match decode_source_frame(&frame.header, frame.payload, self.spec(), &mut wav_buf, DecodeMode::Wrapping) {
+        Ok(Some(samples)) => wav.extend_from_slice(&wav_buf[0..samples]),
+        Ok(None) => (),
+        Err(_) => self.frame_errors += 1,
+      }
+    }
+    Ok(wav)
+  }
+}
+
+///
+/// Decode an .x3a (X3 Archive) held in memory, see `x3::Decoder` to read the frames one by
+/// one or to decode another source.
+///
+/// ### Arguments
+///
+/// * `x3a_bytes` - the whole archive.
+///
+/// ### Returns
+///
+/// * the spec and samples of the first source, the audio in `<CFG ID="1">`.
+///
+pub fn decode_x3a_bytes(x3a_bytes: &[u8]) -> Result<(X3aSpec, Vec<i16>), X3Error> {
+  let mut x3_decoder = x3::Decoder::new(x3a_bytes)?;
+  let wav = x3_decoder.decode()?;
+  Ok((x3_decoder.spec().clone(), wav))
 }
 
 ///
 /// Read the <Archive Header> from in the input buffer.
 ///
-fn read_archive_header(reader: &mut BufReader<File>) -> Result<(X3aSpec, usize), X3Error> {
+/// ### Returns
+///
+/// * the source id and spec of each source, the audio in `<CFG ID="1">` is first.
+/// * the XML metadata.
+/// * the size of the header in bytes.
+///
+pub(crate) fn read_archive_header<R: Read>(reader: &mut R) -> Result<(Sources, ArchiveMetadata, usize), X3Error> {
   // <Archive Id>
   {
     let mut arc_header = [0u8; x3::Archive::ID.len()];
@@ -149,7 +671,7 @@ fn read_archive_header(reader: &mut BufReader<File>) -> Result<(X3aSpec, usize),
   let header = {
     let mut header_buf = [0u8; x3::FrameHeader::LENGTH];
     reader.read_exact(&mut header_buf)?;
-    decoder::read_frame_header(&mut header_buf)?
+    decoder::read_frame_header(&header_buf)?
   };
 
   // Get the payload
@@ -157,25 +679,50 @@ fn read_archive_header(reader: &mut BufReader<File>) -> Result<(X3aSpec, usize),
   reader.read_exact(&mut payload)?;
   let xml = String::from_utf8_lossy(&payload);
 
-  let (sample_rate, params) = parse_xml(&xml)?;
+  let metadata = ArchiveMetadata::from_xml(&xml)?;
+  let sample_rate = metadata.sample_rate().ok_or(X3Error::ArchiveHeaderXMLInvalid)?;
+  let params = metadata.params()?;
 
-  let header_size = x3::FrameHeader::LENGTH + payload.len();
-
-  Ok((
+  // The audio, then any other sources that are fully described
+  let source_ids = metadata.source_ids();
+  let first_id = match metadata.wav_cfg() {
+    Some(_) => x3::Archive::WAV_SOURCE_ID,
+    None => source_ids.first().copied().unwrap_or(x3::Archive::WAV_SOURCE_ID),
+  };
+  let mut sources = vec![(
+    first_id,
     X3aSpec {
       sample_rate,
       params,
-      channels: header.channels,
+      channels: metadata.channels(),
     },
-    header_size,
-  ))
+  )];
+  for id in source_ids {
+    if sources.iter().any(|(source_id, _)| *source_id == id) {
+      continue;
+    }
+    if let (Some(sample_rate), Ok(params)) = (metadata.source_sample_rate(id), metadata.source_params(id)) {
+      sources.push((
+        id,
+        X3aSpec {
+          sample_rate,
+          params,
+          channels: 1,
+        },
+      ));
+    }
+  }
+
+  let header_size = x3::FrameHeader::LENGTH + payload.len();
+
+  Ok((sources, metadata, header_size))
 }
 
 ///
 /// Convert an .x3a (X3 Archive) file to a .wav file.  
 ///
-/// Note: the x3a can contain some meta data of the recording that may be lost, such as the time
-///       of the recording and surplus XML payload data that has been embedded into the X3A header.
+/// The `LIST/INFO`, `bext` and `iXML` chunks kept in the archive metadata are written to the
+/// wav.  The rest of the XML metadata is not, see `X3aReader::metadata`.
 ///
 /// ### Arguments
 ///
@@ -183,28 +730,183 @@ fn read_archive_header(reader: &mut BufReader<File>) -> Result<(X3aSpec, usize),
 /// * `wav_filename` - the output wav file to write to.  It will be overwritten.
 ///
 pub fn x3a_to_wav<P: AsRef<path::Path>>(x3a_filename: P, wav_filename: P) -> Result<(), X3Error> {
+  let x3a_reader = X3aReader::open(x3a_filename)?;
+  reader_to_wav(x3a_reader, wav_filename)
+}
+
+///
+/// Convert one source of an .x3a (X3 Archive) file with several sources to a .wav file.
+/// The frames of the other sources are skipped.
+///
+/// ### Arguments
+///
+/// * `x3a_filename` - the input X3A file to decode.
+/// * `source_id` - the source to decode, the `<CFG ID="..">` in the archive header.
+/// * `wav_filename` - the output wav file to write to.  It will be overwritten.
+/// * `num_threads` - the number of threads to decode with, `0` will use all the available cores.
+///
+pub fn x3a_source_to_wav<P: AsRef<path::Path>>(
+  x3a_filename: P,
+  source_id: u8,
+  wav_filename: P,
+  num_threads: usize,
+) -> Result<(), X3Error> {
   let mut x3a_reader = X3aReader::open(x3a_filename)?;
+  x3a_reader.select_source(source_id)?;
+  if num_threads == 1 {
+    reader_to_wav(x3a_reader, wav_filename)
+  } else {
+    reader_to_wav_parallel(x3a_reader, wav_filename, num_threads)
+  }
+}
+
+//
+// Decode the selected source of `x3a_reader` to a wav file.
+//
+fn reader_to_wav<P: AsRef<path::Path>>(mut x3a_reader: X3aReader, wav_filename: P) -> Result<(), X3Error> {
+  let mut writer = create_wav_writer(&x3a_reader, &wav_filename)?;
+
+  let mut wav = [0i16; X3_WRITE_BUFFER_SIZE];
+  while let Some(samples) = x3a_reader.decode_next_frame(&mut wav)? {
+    write_samples(&mut writer, &wav, samples)?;
+  }
+
+  finish_wav(&x3a_reader, writer, wav_filename)
+}
+
+///
+/// Convert an .x3a (X3 Archive) file to a .wav file, the frames are decoded by `num_threads`
+/// threads.  The output is the same as `x3a_to_wav`.
+///
+/// ### Arguments
+///
+/// * `x3a_filename` - the input X3A file to decode.
+/// * `wav_filename` - the output wav file to write to.  It will be overwritten.
+/// * `num_threads` - the number of threads to decode with, `0` will use all the available cores.
+///
+pub fn x3a_to_wav_parallel<P: AsRef<path::Path>>(
+  x3a_filename: P,
+  wav_filename: P,
+  num_threads: usize,
+) -> Result<(), X3Error> {
+  if num_threads == 1 {
+    return x3a_to_wav(x3a_filename, wav_filename);
+  }
+
+  let x3a_reader = X3aReader::open(x3a_filename)?;
+  reader_to_wav_parallel(x3a_reader, wav_filename, num_threads)
+}
+
+//
+// Decode the selected source of `x3a_reader` to a wav file using `num_threads` threads.
+//
+fn reader_to_wav_parallel<P: AsRef<path::Path>>(
+  mut x3a_reader: X3aReader,
+  wav_filename: P,
+  num_threads: usize,
+) -> Result<(), X3Error> {
+  let mut writer = create_wav_writer(&x3a_reader, &wav_filename)?;
+
+  let num_frames = std::cmp::max(1, num_threads) * FRAMES_PER_THREAD;
+  'decode: loop {
+    let frames = x3a_reader.decode_frames_parallel(num_frames, num_threads)?;
+    if frames.is_empty() {
+      break;
+    }
+
+    for frame in frames {
+      match frame {
+        Ok(wav) => write_samples(&mut writer, &wav, wav.len())?,
+        // Stop at the first bad frame, the same as `x3a_to_wav`
+        Err(_) => break 'decode,
+      }
+    }
+  }
+
+  finish_wav(&x3a_reader, writer, wav_filename)
+}
+
+///
+/// Write each sensor source of an .x3a (X3 Archive) file to a CSV file, see `Sensor`.  The
+/// files are called `<x3a name>_<sensor name>.csv`, each row has the time in seconds from
+/// the first sample and the sample value.
+///
+/// ### Arguments
+///
+/// * `x3a_filename` - the input X3A file to decode.
+/// * `out_dir` - the directory to write the CSV files to, existing files will be overwritten.
+///
+/// ### Returns
+///
+/// * the CSV files that were written, in the order the sensors appear in the archive header.
+///
+pub fn x3a_sensors_to_csv<P: AsRef<path::Path>, Q: AsRef<path::Path>>(
+  x3a_filename: P,
+  out_dir: Q,
+) -> Result<Vec<path::PathBuf>, X3Error> {
+  let stem = x3a_filename.as_ref().file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+  let mut x3a_reader = X3aReader::open(&x3a_filename)?;
+  let sensors = Sensor::all_from_metadata(x3a_reader.metadata());
+
+  // A CSV file and the number of samples written, for each sensor
+  let mut csv_filenames = Vec::with_capacity(sensors.len());
+  let mut writers = Vec::with_capacity(sensors.len());
+  for sensor in &sensors {
+    let name = match sensor.name.as_str() {
+      "" => format!("source{}", sensor.source_id),
+      name => name.to_string(),
+    };
+    let csv_filename = out_dir.as_ref().join(format!("{}_{}.csv", stem, name));
+    let mut writer = BufWriter::new(File::create(&csv_filename)?);
+    writeln!(writer, "time (s),{} ({})", name, sensor.unit)?;
+    csv_filenames.push(csv_filename);
+    writers.push((writer, 0usize));
+  }
+
+  let source_ids: Vec<u8> = sensors.iter().map(|s| s.source_id).collect();
+  let mut wav = [0i16; X3_WRITE_BUFFER_SIZE];
+  while let Some((source_id, samples)) = x3a_reader.decode_next_frame_from(&source_ids, &mut wav)? {
+    let i = source_ids.iter().position(|id| *id == source_id).unwrap_or_default();
+    let sample_rate = f64::from(sensors[i].sample_rate);
+    let (writer, count) = &mut writers[i];
+    for sample in &wav[0..samples] {
+      writeln!(writer, "{},{}", *count as f64 / sample_rate, sample)?;
+      *count += 1;
+    }
+  }
 
+  for (mut writer, _) in writers {
+    writer.flush()?;
+  }
+
+  Ok(csv_filenames)
+}
+
+fn create_wav_writer<P: AsRef<path::Path>>(
+  x3a_reader: &X3aReader,
+  wav_filename: P,
+) -> Result<hound::WavWriter<std::io::BufWriter<std::fs::File>>, X3Error> {
   let x3_spec = x3a_reader.spec();
   let spec = hound::WavSpec {
-    channels: 1, //x3_spec.channels as u16,
+    channels: std::cmp::max(1, u16::from(x3_spec.channels)),
     sample_rate: x3_spec.sample_rate,
     bits_per_sample: 16,
     sample_format: hound::SampleFormat::Int,
   };
 
-  let mut writer = hound::WavWriter::create(wav_filename, spec)?;
-  let mut wav = [0i16; X3_WRITE_BUFFER_SIZE];
-  loop {
-    match x3a_reader.decode_next_frame(&mut wav)? {
-      Some(samples) => {
-        write_samples(&mut writer, &wav, samples)?;
-      }
-      None => break,
-    }
-  }
+  Ok(hound::WavWriter::create(wav_filename, spec)?)
+}
 
-  Ok(())
+//
+// Finish writing the samples, then add the wav chunks from the archive metadata.
+//
+fn finish_wav<P: AsRef<path::Path>>(
+  x3a_reader: &X3aReader,
+  writer: hound::WavWriter<std::io::BufWriter<std::fs::File>>,
+  wav_filename: P,
+) -> Result<(), X3Error> {
+  writer.finalize()?;
+  WavChunks::from_source_metadata(x3a_reader.metadata(), x3a_reader.source_id()).append_to(wav_filename)
 }
 
 fn write_samples(
@@ -213,91 +915,15 @@ fn write_samples(
   num_samples: usize,
 ) -> Result<(), X3Error> {
   let mut fast_writer = writer.get_i16_writer(num_samples as u32);
-  for i in 0..num_samples {
+  for sample in buf.iter().take(num_samples) {
     unsafe {
-      fast_writer.write_sample_unchecked(buf[i]);
+      fast_writer.write_sample_unchecked(*sample);
     }
   }
   fast_writer.flush()?;
   Ok(())
 }
 
-///
-/// Parse the XML header that contains the parameters for the wav output.
-///
-fn parse_xml(xml: &str) -> Result<(u32, x3::Parameters), X3Error> {
-  let mut reader = Reader::from_str(xml);
-  reader.trim_text(true);
-
-  let mut buf = Vec::new();
-  let mut fs = Vec::with_capacity(3);
-  let mut bl = Vec::with_capacity(3);
-  let mut codes = Vec::with_capacity(3);
-  let mut th = Vec::with_capacity(3);
-
-  // The `Reader` does not implement `Iterator` because it outputs borrowed data (`Cow`s)
-  loop {
-    match reader.read_event(&mut buf) {
-      Ok(Event::Start(ref e)) => match e.name() {
-        b"FS" => fs.push(reader.read_text(e.name(), &mut Vec::new()).unwrap()),
-        b"BLKLEN" => bl.push(reader.read_text(e.name(), &mut Vec::new()).unwrap()),
-        b"CODES" => codes.push(reader.read_text(e.name(), &mut Vec::new()).unwrap()),
-        b"T" => th.push(reader.read_text(e.name(), &mut Vec::new()).unwrap()),
-        _ => (),
-      },
-      Ok(Event::Eof) => break, // exits the loop when reaching end of file
-      Err(e) => {
-        println!(
-          "Error reading X3 Archive header (XML) at position {}: {:?}",
-          reader.buffer_position(),
-          e
-        );
-        return Err(X3Error::ArchiveHeaderXMLInvalid);
-      }
-      _ => (), // There are several other `Event`s we do not consider here
-    }
-
-    // if we don't keep a borrow elsewhere, we can clear the buffer to keep memory usage low
-    buf.clear();
-  }
-  println!("sample rate: {}", fs[0]);
-  println!("block length: {}", bl[0]);
-  println!("Rice codes: {}", codes[0]);
-  println!("thresholds: {}", th[0]);
-
-  let sample_rate = fs[0].parse::<u32>().unwrap();
-  let block_len = bl[0].parse::<u32>().unwrap();
-  let mut rice_code_ids = Vec::new();
-  for word in codes[0].split(',') {
-    match word {
-      "RICE0" => rice_code_ids.push(0),
-      "RICE1" => rice_code_ids.push(1),
-      "RICE2" => rice_code_ids.push(2),
-      "RICE3" => rice_code_ids.push(3),
-      "BFP" => (),
-      _ => return Err(X3Error::ArchiveHeaderXMLRiceCode),
-    };
-  }
-  let thresholds: Vec<usize> = th[0].split(',').map(|s| s.parse::<usize>().unwrap()).collect();
-
-  let mut rc_array: [usize; 3] = [0; 3];
-  let mut th_array: [usize; 3] = [0; 3];
-
-  #[allow(clippy::manual_memcpy)]
-  for i in 0..3 {
-    rc_array[i] = rice_code_ids[i];
-    th_array[i] = thresholds[i];
-  }
-  let params = x3::Parameters::new(
-    block_len as usize,
-    x3::Parameters::DEFAULT_BLOCKS_PER_FRAME,
-    rc_array,
-    th_array,
-  )?;
-
-  Ok((sample_rate, params))
-}
-
 //
 //
 //            #######
@@ -312,10 +938,107 @@ fn parse_xml(xml: &str) -> Result<(u32, x3::Parameters), X3Error> {
 
 #[cfg(test)]
 mod tests {
+  use crate::decodefile::{X3aReader, X3_WRITE_BUFFER_SIZE};
+  use crate::encodefile::{wav_to_x3a, wav_to_x3a_with_metadata};
+  use crate::metadata::{ArchiveMetadata, XmlElement};
+  use crate::testutil::{sine, temp_path};
+  use crate::x3::Parameters;
+
   // use crate::decodefile::x3a_to_wav;
 
   // #[test]
   // fn test_decode_x3a_file() {
   //   x3a_to_wav("~/tmp/test.x3a", "~/tmp/test.wav").unwrap();
   // }
+
+  #[test]
+  fn test_decode_frames_parallel() {
+    let wav_filename = temp_path("decode_frames_parallel.wav");
+    let x3a_filename = temp_path("decode_frames_parallel.x3a");
+
+    // Write a wav with several frames
+    let spec = hound::WavSpec {
+      channels: 1,
+      sample_rate: 44100,
+      bits_per_sample: 16,
+      sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(&wav_filename, spec).unwrap();
+    for sample in sine(123_457, 0.01, 3000.0, 7) {
+      writer.write_sample(sample).unwrap();
+    }
+    writer.finalize().unwrap();
+    wav_to_x3a(&wav_filename, &x3a_filename).unwrap();
+
+    // Decode sequentially
+    let mut expected = Vec::new();
+    {
+      let mut reader = X3aReader::open(&x3a_filename).unwrap();
+      let mut wav = [0i16; X3_WRITE_BUFFER_SIZE];
+      while let Some(samples) = reader.decode_next_frame(&mut wav).unwrap() {
+        expected.push(wav[0..samples].to_vec());
+      }
+    }
+
+    // Decode in parallel, with batches that don't line up with the number of frames
+    let mut reader = X3aReader::open(&x3a_filename).unwrap();
+    let mut decoded = Vec::new();
+    loop {
+      let frames = reader.decode_frames_parallel(5, 3).unwrap();
+      if frames.is_empty() {
+        break;
+      }
+      decoded.extend(frames.into_iter().map(|frame| frame.unwrap()));
+    }
+
+    std::fs::remove_file(&wav_filename).unwrap();
+    std::fs::remove_file(&x3a_filename).unwrap();
+
+    assert_eq!(13, expected.len());
+    assert_eq!(expected, decoded);
+  }
+
+  #[test]
+  fn test_metadata_re_encode() {
+    let wav_filename = temp_path("metadata_re_encode.wav");
+    let x3a_filename = temp_path("metadata_re_encode.x3a");
+    let x3a_filename2 = temp_path("metadata_re_encode_2.x3a");
+
+    let spec = hound::WavSpec {
+      channels: 1,
+      sample_rate: 32000,
+      bits_per_sample: 16,
+      sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(&wav_filename, spec).unwrap();
+    for sample in sine(5000, 0.05, 500.0, 1) {
+      writer.write_sample(sample).unwrap();
+    }
+    writer.finalize().unwrap();
+
+    // Metadata with a device, a gain and non-default parameters
+    let mut metadata = ArchiveMetadata::new();
+    metadata.set_params(&Parameters::builder().block_len(32).build().unwrap());
+    let cfg = metadata.wav_cfg_mut();
+    cfg.set_child(XmlElement::new("DEV").with_attribute("ID", "4321").with_text("hydrophone"));
+    cfg.set_child(XmlElement::new("GAIN").with_attribute("UNIT", "dB").with_text("6"));
+    wav_to_x3a_with_metadata(&wav_filename, &x3a_filename, &metadata, 1).unwrap();
+
+    let reader = X3aReader::open(&x3a_filename).unwrap();
+    assert_eq!(32000, reader.spec().sample_rate);
+    assert_eq!(32, reader.spec().params.block_len);
+    let read_metadata = reader.metadata().clone();
+    assert_eq!("hydrophone", read_metadata.wav_cfg().unwrap().child("DEV").unwrap().text);
+    assert_eq!(Some(32000), read_metadata.sample_rate());
+
+    // Re-encoding keeps all the metadata
+    wav_to_x3a_with_metadata(&wav_filename, &x3a_filename2, &read_metadata, 2).unwrap();
+    let reader2 = X3aReader::open(&x3a_filename2).unwrap();
+    assert_eq!(&read_metadata, reader2.metadata());
+
+    std::fs::remove_file(&wav_filename).unwrap();
+    assert_eq!(std::fs::read(&x3a_filename).unwrap(), std::fs::read(&x3a_filename2).unwrap());
+    std::fs::remove_file(&x3a_filename).unwrap();
+    std::fs::remove_file(&x3a_filename2).unwrap();
+  }
 }
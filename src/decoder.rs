@@ -22,6 +22,8 @@
 use crate::bitreader::BitReader;
 use crate::crc;
 use crate::error;
+#[cfg(feature = "fast-rice")]
+use crate::ricetable;
 use crate::x3::{self, FrameHeader};
 
 use byteorder::{BigEndian, ByteOrder};
@@ -33,22 +35,85 @@ pub enum FrameTest {
   NotFrame,
 }
 
+///
+/// How the decoder handles a sample that overflows the 16 bit range.  This can only
+/// happen when the x3 data is corrupt.
+///
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DecodeMode {
+  /// The samples wrap around, the same as the reference C implementation.
+  #[default]
+  Wrapping,
+
+  /// The frame fails to decode with `X3Error::FrameDecodeSampleOverflow`.
+  Strict,
+}
+
+impl DecodeMode {
+  ///
+  /// Add `diff` to the `sample`, according to the decode mode.
+  ///
+  #[inline(always)]
+  pub fn add(self, sample: i16, diff: i16) -> Result<i16, X3Error> {
+    match self {
+      DecodeMode::Wrapping => Ok(sample.wrapping_add(diff)),
+      DecodeMode::Strict => sample.checked_add(diff).ok_or(X3Error::FrameDecodeSampleOverflow),
+    }
+  }
+}
+
+///
+/// Decode the payload of a frame, samples that overflow will wrap around.
+///
+/// ### Arguments
+///
+/// * `x3_bytes` - the frame payload.
+/// * `wav_buf` - where the wav data will be written to.
+/// * `params` - the audio properties.
+/// * `samples` - the number of samples in the frame.
+///
 pub fn decode_frame(
-  x3_bytes: &mut [u8],
+  x3_bytes: &[u8],
+  wav_buf: &mut [i16],
+  params: &x3::Parameters,
+  samples: usize,
+) -> Result<Option<usize>, X3Error> {
+  decode_frame_with_mode(x3_bytes, wav_buf, params, samples, DecodeMode::Wrapping)
+}
+
+///
+/// Decode the payload of a frame, `mode` sets how overflowing samples are handled.
+///
+pub fn decode_frame_with_mode(
+  x3_bytes: &[u8],
   wav_buf: &mut [i16],
   params: &x3::Parameters,
   samples: usize,
+  mode: DecodeMode,
 ) -> Result<Option<usize>, X3Error> {
+  if samples == 0 {
+    return Ok(Some(0));
+  }
+  if samples > wav_buf.len() {
+    return Err(X3Error::FrameDecodeInvalidNTOGO);
+  }
+  if params.block_len == 0 || params.block_len > x3::Parameters::MAX_BLOCK_LENGTH {
+    return Err(X3Error::FrameDecodeInvalidBlockLength);
+  }
+  if x3_bytes.len() < 2 {
+    return Err(X3Error::FrameDecodeUnexpectedEnd);
+  }
+
   let mut last_wav = BigEndian::read_i16(x3_bytes);
   let mut p_wav = 0;
-  wav_buf[p_wav] = last_wav as i16;
+  wav_buf[p_wav] = last_wav;
   p_wav += 1;
-  let br = &mut BitReader::new(&mut x3_bytes[2..]);
+  let br = &mut BitReader::new(&x3_bytes[2..]);
   let mut remaining_samples = samples - 1;
 
   while remaining_samples > 0 {
     let block_len = core::cmp::min(remaining_samples, params.block_len);
-    decode_block(br, &mut wav_buf[p_wav..(p_wav + block_len)], &mut last_wav, &params)?;
+    decode_block(br, &mut wav_buf[p_wav..(p_wav + block_len)], &mut last_wav, params, mode)?;
 
     remaining_samples -= block_len;
     p_wav += block_len;
@@ -58,63 +123,140 @@ pub fn decode_frame(
 }
 
 ///
-/// Parse the frame header and return the payload.  The Frame header and payload
-/// contain CRCs, theses will be checked and errors returned if the CRC does not
-/// match.
+/// Decode the payload of a frame with several channels, the samples are written to
+/// `wav_buf` interleaved.  Samples that overflow will wrap around, see
+/// `decode_frame_channels_with_mode`.
 ///
 /// ### Arguments
 ///
-/// * `br` - the data to decode as a BitReader.
+/// * `x3_bytes` - the frame payload.
+/// * `wav_buf` - where the wav data will be written to.
+/// * `params` - the audio properties.
+/// * `samples` - the number of samples of each channel in the frame.
+/// * `channels` - the number of channels in the frame.
 ///
-pub fn read_frame_header(bytes: &[u8]) -> Result<FrameHeader, X3Error> {
-  if bytes.len() < FrameHeader::LENGTH {
-    return Err(X3Error::FrameDecodeUnexpectedEnd);
+/// ### Returns
+///
+/// * the number of samples written to `wav_buf`, for all the channels.
+///
+pub fn decode_frame_channels(
+  x3_bytes: &[u8],
+  wav_buf: &mut [i16],
+  params: &x3::Parameters,
+  samples: usize,
+  channels: usize,
+) -> Result<Option<usize>, X3Error> {
+  decode_frame_channels_with_mode(x3_bytes, wav_buf, params, samples, channels, DecodeMode::Wrapping)
+}
+
+///
+/// Decode the payload of a frame with several channels, `mode` sets how overflowing
+/// samples are handled.
+///
+pub fn decode_frame_channels_with_mode(
+  x3_bytes: &[u8],
+  wav_buf: &mut [i16],
+  params: &x3::Parameters,
+  samples: usize,
+  channels: usize,
+  mode: DecodeMode,
+) -> Result<Option<usize>, X3Error> {
+  if channels <= 1 {
+    return decode_frame_with_mode(x3_bytes, wav_buf, params, samples, mode);
   }
-  // Calc header CRC
-  let header_crc = crc::crc16(&bytes[0..FrameHeader::P_HEADER_CRC]);
-  let expected_header_crc = BigEndian::read_u16(&bytes[FrameHeader::P_HEADER_CRC..]);
-  if expected_header_crc != header_crc {
-    return Err(X3Error::FrameHeaderInvalidHeaderCRC);
+  if samples == 0 {
+    return Ok(Some(0));
   }
-
-  // Read <Frame Key>
-  let x3_archive_key = BigEndian::read_u16(&bytes[0..2]);
-  if x3_archive_key != FrameHeader::KEY {
-    return Err(X3Error::FrameHeaderInvalidKey);
+  if samples * channels > wav_buf.len() {
+    return Err(X3Error::FrameDecodeInvalidNTOGO);
+  }
+  if params.block_len == 0 || params.block_len > x3::Parameters::MAX_BLOCK_LENGTH {
+    return Err(X3Error::FrameDecodeInvalidBlockLength);
+  }
+  if x3_bytes.len() < 2 * channels {
+    return Err(X3Error::FrameDecodeUnexpectedEnd);
   }
 
-  // <Source Id>
-  // Currently just skip it
-  let source_id = bytes[FrameHeader::P_SOURCE_ID];
+  // The first sample of each channel
+  let mut last_wavs = vec![0i16; channels];
+  BigEndian::read_i16_into(&x3_bytes[0..2 * channels], &mut last_wavs);
+  wav_buf[0..channels].copy_from_slice(&last_wavs);
 
-  // <Num Channels>
-  let channels = bytes[FrameHeader::P_CHANNELS];
-  if channels > 1 {
-    return Err(X3Error::MoreThanOneChannel);
+  let br = &mut BitReader::new(&x3_bytes[2 * channels..]);
+  let block: &mut [i16] = &mut vec![0i16; params.block_len];
+  let mut p_wav = 1;
+  let mut remaining_samples = samples - 1;
+
+  // A block of each channel in turn
+  while remaining_samples > 0 {
+    let block_len = core::cmp::min(remaining_samples, params.block_len);
+    for (ch, last_wav) in last_wavs.iter_mut().enumerate() {
+      decode_block(br, &mut block[0..block_len], last_wav, params, mode)?;
+      for (i, sample) in block[0..block_len].iter().enumerate() {
+        wav_buf[(p_wav + i) * channels + ch] = *sample;
+      }
+    }
+
+    remaining_samples -= block_len;
+    p_wav += block_len;
   }
 
-  // <Num Samples>
-  let samples = BigEndian::read_u16(&bytes[FrameHeader::P_SAMPLES..]);
+  Ok(Some(samples * channels))
+}
 
-  // <Payload Length>
-  let payload_len = BigEndian::read_u16(&bytes[FrameHeader::P_PAYLOAD_SIZE..]) as usize;
-  if payload_len >= x3::Frame::MAX_LENGTH {
-    return Err(X3Error::FrameLength);
+///
+/// Decode a whole frame, the frame header and its payload.  Both of the CRCs are checked,
+/// see `encoder::encode_frame_to`.
+///
+/// ### Arguments
+///
+/// * `frame` - the bytes of the frame.
+/// * `params` - the parameters the frame was encoded with.
+///
+/// ### Returns
+///
+/// * the samples, the channels are interleaved.
+///
+pub fn decode_frame_bytes(frame: &[u8], params: &x3::Parameters) -> Result<Vec<i16>, X3Error> {
+  let header = FrameHeader::parse(frame)?;
+  let payload = frame
+    .get(FrameHeader::LENGTH..(FrameHeader::LENGTH + header.payload_len))
+    .ok_or(X3Error::FrameDecodeUnexpectedEnd)?;
+  if crc::crc16(payload) != header.payload_crc {
+    return Err(X3Error::FrameHeaderInvalidPayloadCRC);
   }
 
-  // <Time>
-  // Skip time
+  let samples = header.samples as usize;
+  let channels = std::cmp::max(1, header.channels as usize);
+  let mut wav = vec![0i16; samples * channels];
+  let len = decode_frame_channels(payload, &mut wav, params, samples, channels)?.unwrap_or(0);
+  wav.truncate(len);
+  Ok(wav)
+}
 
-  // <Payload CRC>
-  let payload_crc = BigEndian::read_u16(&bytes[FrameHeader::P_PAYLOAD_CRC..]);
+///
+/// Parse the frame header and return the payload.  The Frame header and payload
+/// contain CRCs, theses will be checked and errors returned if the CRC does not
+/// match.
+///
+/// ### Arguments
+///
+/// * `br` - the data to decode as a BitReader.
+///
+pub fn read_frame_header(bytes: &[u8]) -> Result<FrameHeader, X3Error> {
+  let header = parse_frame_header(bytes)?;
+  if header.channels > 1 {
+    return Err(X3Error::MoreThanOneChannel);
+  }
+  Ok(header)
+}
 
-  Ok(FrameHeader {
-    source_id,
-    samples,
-    channels,
-    payload_len,
-    payload_crc,
-  })
+///
+/// Parse the frame header, the same as `read_frame_header`, frames with more than one
+/// channel are accepted.  See `decode_frame_channels`.
+///
+pub fn parse_frame_header(bytes: &[u8]) -> Result<FrameHeader, X3Error> {
+  FrameHeader::parse(bytes)
 }
 
 ///
@@ -126,89 +268,125 @@ pub fn read_frame_header(bytes: &[u8]) -> Result<FrameHeader, X3Error> {
 /// * `br` - the data to decode as a BitReader.
 /// * `wav` - where the wav data will be written to.
 /// * `last_wav` - the last value of the previous block.
-/// * `block_len` - how many bytes the decoded block will be.
 /// * `params` - the audio properties.
+/// * `mode` - how overflowing samples are handled.
 ///
 pub fn decode_block(
   br: &mut BitReader,
   wav: &mut [i16],
   last_wav: &mut i16,
   params: &x3::Parameters,
+  mode: DecodeMode,
 ) -> Result<(), X3Error> {
-  let ftype = br.read_nbits(2) as usize;
+  let ftype = br.read_nbits(2)? as usize;
   match ftype {
-    0 => decode_bpf_block(br, wav, last_wav),
-    1 => decode_ricecode_block_r1(br, wav, last_wav, params, ftype),
-    2 | 3 => decode_ricecode_block_r2r3(br, wav, last_wav, params, ftype),
+    0 => decode_bpf_block(br, wav, last_wav, mode),
+    1..=3 => decode_ricecode_block(br, wav, last_wav, params.rice_codes[ftype - 1], mode),
     _ => Err(X3Error::FrameDecodeInvalidFType),
   }
 }
 
-fn decode_ricecode_block_r1(
+#[cfg(feature = "fast-rice")]
+#[inline(always)]
+fn decode_ricecode_block(
   br: &mut BitReader,
   wav: &mut [i16],
   last_wav: &mut i16,
-  params: &x3::Parameters,
-  ftype: usize,
+  code: &x3::RiceCode,
+  mode: DecodeMode,
+) -> Result<(), X3Error> {
+  ricetable::decode_ricecode_block(br, wav, last_wav, code, mode)
+}
+
+#[cfg(not(feature = "fast-rice"))]
+#[inline(always)]
+fn decode_ricecode_block(
+  br: &mut BitReader,
+  wav: &mut [i16],
+  last_wav: &mut i16,
+  code: &x3::RiceCode,
+  mode: DecodeMode,
+) -> Result<(), X3Error> {
+  if code.nsubs == 0 {
+    decode_ricecode_block_r1(br, wav, last_wav, code, mode)
+  } else {
+    decode_ricecode_block_r2r3(br, wav, last_wav, code, mode)
+  }
+}
+
+///
+/// Decode a block of Rice codes without suffix bits, i.e. RICE0.  Each codeword is
+/// a run of zeros terminated by a one.
+///
+pub(crate) fn decode_ricecode_block_r1(
+  br: &mut BitReader,
+  wav: &mut [i16],
+  last_wav: &mut i16,
+  code: &x3::RiceCode,
+  mode: DecodeMode,
 ) -> Result<(), X3Error> {
-  let code = params.rice_codes[ftype - 1];
   let mut lw = *last_wav;
-  for b in 0..wav.len() {
-    let i = br.count_zero_bits();
-    br.read_nbits(1); // skip the next bit
+  for wav_value in wav.iter_mut() {
+    // The longest codeword is checked by `count_zero_bits`, `i` is always in range
+    let i = br.count_zero_bits(code.inv_len - 1)?;
+    br.inc_bits(1)?; // skip the next bit
 
     // Table lookup to convert to a signed number
-    if i >= code.inv_len {
-      return Err(X3Error::OutOfBoundsInverse);
-    }
-    lw += unsafe { code.inv.get_unchecked(i) };
-    let wav_value = unsafe { wav.get_unchecked_mut(b) };
+    lw = mode.add(lw, code.inv[i])?;
     *wav_value = lw;
   }
   *last_wav = lw;
   Ok(())
 }
 
-fn decode_ricecode_block_r2r3(
+///
+/// Decode a block of Rice codes with `code.nsubs` suffix bits.  Each codeword is a run of
+/// zeros terminated by a one, followed by the suffix.
+///
+pub(crate) fn decode_ricecode_block_r2r3(
   br: &mut BitReader,
   wav: &mut [i16],
   last_wav: &mut i16,
-  params: &x3::Parameters,
-  ftype: usize,
+  code: &x3::RiceCode,
+  mode: DecodeMode,
 ) -> Result<(), X3Error> {
-  let code = params.rice_codes[ftype - 1];
-  let nb = if ftype == 2 { 2 } else { 4 };
+  let nb = code.nsubs + 1;
   let level = 1 << code.nsubs;
+  let max_zeros = (code.inv_len - 1) / level;
   let mut lw = *last_wav;
-  for b in 0..wav.len() {
-    let n = br.count_zero_bits() as i16;
-    let r = br.read_nbits(nb) as i16;
-    let i = (r + level * (n - 1)) as usize;
-    if i >= code.inv_len {
-      return Err(X3Error::OutOfBoundsInverse);
-    }
-    lw += unsafe { code.inv.get_unchecked(i) };
-    let wav_value = unsafe { wav.get_unchecked_mut(b) };
+  for wav_value in wav.iter_mut() {
+    let n = br.count_zero_bits(max_zeros)?;
+    let r = br.read_nbits(nb)? as usize;
+
+    // `r` includes the terminating one, so it is at least `level`
+    let i = match (r + level * n).checked_sub(level) {
+      Some(i) if i < code.inv_len => i,
+      _ => return Err(X3Error::OutOfBoundsInverse),
+    };
+    lw = mode.add(lw, code.inv[i])?;
     *wav_value = lw;
   }
   *last_wav = lw;
   Ok(())
 }
 
+//
+// Sign extend the `num_bits` bit two's complement value `a`.
+//
 fn unsigned_to_i16(a: u16, num_bits: usize) -> i16 {
   let mut a = i32::from(a);
   let neg_thresh = 1 << (num_bits - 1);
   let neg = 1 << num_bits;
-  // Need to convert this to a signed integer
-  if a > neg_thresh {
+  // Need to convert this to a signed integer, the top bit is the sign bit
+  if a >= neg_thresh {
     a -= neg;
   }
   a as i16
 }
 
-fn decode_bpf_block(br: &mut BitReader, wav: &mut [i16], last_wav: &mut i16) -> Result<(), X3Error> {
+fn decode_bpf_block(br: &mut BitReader, wav: &mut [i16], last_wav: &mut i16, mode: DecodeMode) -> Result<(), X3Error> {
   // This is a BFP or pass-through block
-  let num_bits = (br.read_nbits(4) + 1) as usize; // Read the rest of the block header
+  let num_bits = (br.read_nbits(4)? + 1) as usize; // Read the rest of the block header
 
   if num_bits <= 5 {
     // We can't have BPF with length 5 or less.
@@ -218,15 +396,15 @@ fn decode_bpf_block(br: &mut BitReader, wav: &mut [i16], last_wav: &mut i16) ->
   if num_bits == 16 {
     // This is a pass-through block
     for wav_value in wav.iter_mut() {
-      *wav_value = br.read_nbits(16) as i16;
+      *wav_value = br.read_nbits(16)? as i16;
     }
   } else {
     // Otherwise, this is a BFP-encoded block with E + 1 bits/word
     let mut value = *last_wav;
     for wav_value in wav.iter_mut() {
-      let diff = br.read_nbits(num_bits) as u16;
-      value += unsigned_to_i16(diff, num_bits);
-      *wav_value = value as i16;
+      let diff = br.read_nbits(num_bits)? as u16;
+      value = mode.add(value, unsigned_to_i16(diff, num_bits))?;
+      *wav_value = value;
     }
   }
   *last_wav = wav[wav.len() - 1];
@@ -248,9 +426,17 @@ fn decode_bpf_block(br: &mut BitReader, wav: &mut [i16], last_wav: &mut i16) ->
 
 #[cfg(test)]
 mod tests {
+  use crate::bitpacker::BitPacker;
   use crate::bitreader::BitReader;
   use crate::byteorder::{BigEndian, ByteOrder};
-  use crate::decoder::decode_block;
+  use crate::decoder::{
+    decode_block, decode_frame, decode_frame_channels, decode_frame_channels_with_mode, decode_frame_with_mode,
+    parse_frame_header, read_frame_header,
+    DecodeMode,
+  };
+  use crate::error::X3Error;
+  use crate::encoder::{encode_frame, encode_frame_channels};
+  use crate::testutil::{lcg, noise, sine};
   use crate::x3;
 
   #[test]
@@ -268,9 +454,9 @@ mod tests {
     let params = &x3::Parameters::default();
 
     // Skip 6 bits
-    br.read_nbits(6);
+    br.read_nbits(6).unwrap();
 
-    decode_block(&mut br, wav, &mut last_wav, params).unwrap();
+    decode_block(&mut br, wav, &mut last_wav, params, DecodeMode::default()).unwrap();
 
     assert_eq!(expected_wavput, &mut wav[0..expected_wavput.len()]);
   }
@@ -291,9 +477,9 @@ mod tests {
     ];
 
     let mut last_wav = BigEndian::read_i16(&x3_inp[0..2]);
-    let mut br = BitReader::new(&mut x3_inp[2..]);
+    let mut br = BitReader::new(&x3_inp[2..]);
     let params = &x3::Parameters::default();
-    decode_block(&mut br, wav, &mut last_wav, params).unwrap();
+    decode_block(&mut br, wav, &mut last_wav, params, DecodeMode::default()).unwrap();
 
     assert_eq!(expected_wavput, &mut wav[0..expected_wavput.len()]);
   }
@@ -308,9 +494,9 @@ mod tests {
     ];
 
     let mut last_wav = BigEndian::read_i16(&x3_inp[0..2]);
-    let mut br = BitReader::new(&mut x3_inp[2..]);
+    let mut br = BitReader::new(&x3_inp[2..]);
     let params = &x3::Parameters::default();
-    decode_block(&mut br, wav, &mut last_wav, params).unwrap();
+    decode_block(&mut br, wav, &mut last_wav, params, DecodeMode::default()).unwrap();
 
     assert_eq!(expected_wavput, &mut wav[0..expected_wavput.len()]);
   }
@@ -328,9 +514,9 @@ mod tests {
     ];
 
     let mut last_wav = BigEndian::read_i16(&x3_inp[0..2]);
-    let mut br = BitReader::new(&mut x3_inp[2..]);
+    let mut br = BitReader::new(&x3_inp[2..]);
     let params = &x3::Parameters::default();
-    decode_block(&mut br, wav, &mut last_wav, params).unwrap();
+    decode_block(&mut br, wav, &mut last_wav, params, DecodeMode::default()).unwrap();
 
     assert_eq!(expected_wavput, &mut wav[0..expected_wavput.len()]);
   }
@@ -347,10 +533,202 @@ mod tests {
     ];
 
     let mut last_wav = BigEndian::read_i16(&x3_inp[0..2]);
-    let mut br = BitReader::new(&mut x3_inp[2..]);
+    let mut br = BitReader::new(&x3_inp[2..]);
     let params = &x3::Parameters::default();
-    decode_block(&mut br, wav, &mut last_wav, params).unwrap();
+    decode_block(&mut br, wav, &mut last_wav, params, DecodeMode::default()).unwrap();
 
     assert_eq!(expected_wavput, &mut wav[0..expected_wavput.len()]);
   }
+
+  #[test]
+  fn test_decode_frame_corrupt() {
+    // A frame with every block type
+    let wav: Vec<i16> =
+      noise(1000, 0x2468_ace0).iter().enumerate().map(|(i, n)| n >> [15, 13, 11, 6, 0][(i / 40) % 5]).collect();
+    let params = &x3::Parameters::default();
+    let mut bp = BitPacker::with_capacity(0);
+    encode_frame(&wav, &mut 0, &mut bp, params, &mut [0; 6]).unwrap();
+    let frame = bp.into_vec();
+
+    let header = read_frame_header(&frame).unwrap();
+    let payload = &frame[x3::FrameHeader::LENGTH..(x3::FrameHeader::LENGTH + header.payload_len)];
+    let wav_buf = &mut [0i16; 1000];
+    assert_eq!(Some(wav.len()), decode_frame(payload, wav_buf, params, wav.len()).unwrap());
+    assert_eq!(&wav[..], &wav_buf[..]);
+
+    // Truncated payloads must return an error, not panic.  The last byte may be padding.
+    for len in 0..(payload.len() - 1) {
+      assert!(decode_frame(&payload[0..len], wav_buf, params, wav.len()).is_err());
+    }
+
+    // Corrupt payloads may decode to garbage, but must not panic
+    let mut corrupt = payload.to_vec();
+    let mut seed = 0x1357_9bdf;
+    for _ in 0..2000 {
+      lcg(&mut seed);
+      let i = (seed >> 8) as usize % corrupt.len();
+      corrupt[i] ^= 1 << (seed % 8);
+      let _ = decode_frame(&corrupt, wav_buf, params, wav.len());
+      let _ = read_frame_header(&corrupt);
+    }
+  }
+
+  #[test]
+  fn test_decode_mode_overflow() {
+    // A BFP block with 7 bit diffs: +63 then -63
+    let x3_inp: &mut [u8] = &mut [0b0001_1001, 0b1111_1100, 0b0001_0000];
+    let params = &x3::Parameters::default();
+
+    let mut wav = [0i16; 2];
+    let mut last_wav = 32767 - 62;
+    let mut br = BitReader::new(x3_inp);
+    decode_block(&mut br, &mut wav, &mut last_wav, params, DecodeMode::Wrapping).unwrap();
+    assert_eq!([-32768, 32705], wav);
+
+    let mut last_wav = 32767 - 62;
+    let mut br = BitReader::new(x3_inp);
+    match decode_block(&mut br, &mut wav, &mut last_wav, params, DecodeMode::Strict) {
+      Err(X3Error::FrameDecodeSampleOverflow) => (),
+      _ => panic!("Expected the sample to overflow"),
+    }
+
+    // Valid frames decode the same in both modes
+    let mut last_wav = 32767 - 63;
+    let mut br = BitReader::new(x3_inp);
+    decode_block(&mut br, &mut wav, &mut last_wav, params, DecodeMode::Strict).unwrap();
+    assert_eq!([32767, 32704], wav);
+    assert_eq!(
+      Some(1),
+      decode_frame_with_mode(&[0x80, 0x00], &mut wav, params, 1, DecodeMode::Strict).unwrap()
+    );
+  }
+
+  //
+  // Hand build a BFP block of `width` bit diffs, the header holds `width - 1`.
+  //
+  fn bfp_block(width: usize, diffs: &[i32]) -> Vec<u8> {
+    let mut bp = BitPacker::with_capacity(0);
+    bp.write_bits(0, 2).unwrap();
+    bp.write_bits(width - 1, 4).unwrap();
+    for diff in diffs {
+      bp.write_bits(*diff as usize, width).unwrap();
+    }
+    bp.word_align().unwrap();
+    bp.into_vec()
+  }
+
+  #[test]
+  fn test_decode_bfp_widths() {
+    let params = &x3::Parameters::default();
+    for width in 6..=15 {
+      let max = (1 << (width - 1)) - 1;
+      let min = -(1 << (width - 1));
+      let diffs = [max, min, 0, -1, 1, min, max, max, min, 2, -2, min + 1, max - 1];
+      let x3_inp = bfp_block(width, &diffs);
+
+      let mut expected = Vec::new();
+      let mut value = 100i16;
+      for diff in diffs.iter() {
+        value += *diff as i16;
+        expected.push(value);
+      }
+
+      let mut wav = vec![0i16; diffs.len()];
+      let mut last_wav = 100;
+      let mut br = BitReader::new(&x3_inp);
+      decode_block(&mut br, &mut wav, &mut last_wav, params, DecodeMode::Strict).unwrap();
+      assert_eq!(expected, wav, "BFP width {}", width);
+      assert_eq!(value, last_wav);
+    }
+  }
+
+  #[test]
+  fn test_decode_bfp_invalid_widths() {
+    let params = &x3::Parameters::default();
+    for width in 1..=5 {
+      let x3_inp = bfp_block(width, &[0; 4]);
+      let mut wav = [0i16; 4];
+      let mut br = BitReader::new(&x3_inp);
+      match decode_block(&mut br, &mut wav, &mut 0, params, DecodeMode::Strict) {
+        Err(X3Error::FrameDecodeInvalidBPF) => (),
+        _ => panic!("Expected BFP width {} to be invalid", width),
+      }
+    }
+  }
+
+  #[test]
+  fn test_decode_pass_through() {
+    // The header value 15 is a pass-through block of raw 16 bit samples
+    let samples = [i16::MAX, i16::MIN, 0, -1, 1, i16::MIN, i16::MAX];
+    let x3_inp = bfp_block(16, &samples.iter().map(|s| i32::from(*s)).collect::<Vec<i32>>());
+    assert_eq!(0x0f, x3_inp[0] >> 2);
+
+    let params = &x3::Parameters::default();
+    let mut wav = [0i16; 7];
+    let mut last_wav = 1234;
+    let mut br = BitReader::new(&x3_inp);
+    decode_block(&mut br, &mut wav, &mut last_wav, params, DecodeMode::Strict).unwrap();
+    assert_eq!(samples, wav);
+    assert_eq!(i16::MAX, last_wav);
+  }
+
+  #[test]
+  fn test_decode_frame_channels() {
+    // Three channels that each need different block types
+    let amplitudes = [10.0, 900.0, 30000.0];
+    let channels: Vec<Vec<i16>> = (0..3).map(|ch| sine(777, 0.01 * (ch + 1) as f32, amplitudes[ch], 1)).collect();
+    let slices: Vec<&[i16]> = channels.iter().map(|ch| &ch[..]).collect();
+    let params = &x3::Parameters::builder().block_len(16).build().unwrap();
+
+    let mut bp = BitPacker::with_capacity(0);
+    let last_wavs = &mut [0i16; 3];
+    assert_eq!(777, encode_frame_channels(&slices, 5, 0, last_wavs, &mut bp, params, &mut [0; 6]).unwrap());
+    assert_eq!([channels[0][776], channels[1][776], channels[2][776]], *last_wavs);
+    let frame = bp.into_vec();
+
+    // Only the mono frames are read by `read_frame_header`
+    assert!(matches!(read_frame_header(&frame), Err(X3Error::MoreThanOneChannel)));
+    let header = parse_frame_header(&frame).unwrap();
+    assert_eq!((5, 3, 777), (header.source_id, header.channels, header.samples));
+
+    let payload = &frame[x3::FrameHeader::LENGTH..(x3::FrameHeader::LENGTH + header.payload_len)];
+    let wav_buf = &mut [0i16; 3 * 777];
+    assert_eq!(Some(3 * 777), decode_frame_channels(payload, wav_buf, params, 777, 3).unwrap());
+    for (i, sample) in wav_buf.iter().enumerate() {
+      assert_eq!(channels[i % 3][i / 3], *sample);
+    }
+
+    // The channels must be the same length
+    let uneven: [&[i16]; 2] = [&channels[0], &channels[1][0..10]];
+    assert!(matches!(
+      encode_frame_channels(&uneven, 5, 0, &mut [0; 2], &mut BitPacker::with_capacity(0), params, &mut [0; 6]),
+      Err(X3Error::InvalidChannels)
+    ));
+  }
+
+  #[test]
+  fn test_decode_frame_channels_mode() {
+    // Two channels of three samples, each with a BFP block of 7 bit diffs: +63 then -63
+    let mut bp = BitPacker::with_capacity(0);
+    for first in [32767 - 62, 0i16] {
+      bp.write_bits(first as u16 as usize, 16).unwrap();
+    }
+    for _ in 0..2 {
+      bp.write_bits(0, 2).unwrap();
+      bp.write_bits(6, 4).unwrap();
+      bp.write_bits(63, 7).unwrap();
+      bp.write_bits(-63i32 as usize, 7).unwrap();
+    }
+    bp.word_align().unwrap();
+    let payload = bp.into_vec();
+    let params = &x3::Parameters::default();
+
+    let wav_buf = &mut [0i16; 6];
+    decode_frame_channels_with_mode(&payload, wav_buf, params, 3, 2, DecodeMode::Wrapping).unwrap();
+    assert_eq!([32705, 0, -32768, 63, 32705, 0], *wav_buf);
+    assert!(matches!(
+      decode_frame_channels_with_mode(&payload, wav_buf, params, 3, 2, DecodeMode::Strict),
+      Err(X3Error::FrameDecodeSampleOverflow)
+    ));
+  }
 }
@@ -0,0 +1,525 @@
+/**************************************************************************
+ *                                                                        *
+ * Rust implementation of the X3 lossless audio compression protocol.     *
+ *                                                                        *
+ * Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>             *
+ *                                                                        *
+ * This program is free software; you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the           *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.   *
+ *                                                                        *
+ **************************************************************************/
+
+//!
+//! A reader for the `.dtg` and `.bin` files written by DTAG recorders, as read by the
+//! Matlab tools from soundtags.org.  The files hold X3 frames with the same 20 byte header
+//! as the `.x3a` frames, between other data:
+//!
+//! ```text
+//! <Frame Key: "x3"> <Source Id: u8> <Channels: u8> <Samples: u16> <Payload length: u16>
+//! <Time (us): u64> <Header CRC: u16> <Payload CRC: u16>
+//! ```
+//!
+//! The frames with source id 0 hold XML `<CFG ID="..">` elements that describe the frames
+//! of the other sources.  A `<CFG>` with a `<CODEC>` holds X3 compressed samples, with the
+//! sample rate in `<FS>`.  The `WAV` sources are audio, the others are sensors.  The
+//! payloads of the sources without a `<CODEC>`, like the logs, are written out as is.
+//!
+//! The frames are found by their key and header CRC, and the frames with a bad payload CRC
+//! are skipped.
+//!
+
+// std
+use std::fs::File;
+use std::io::{prelude::*, BufReader, BufWriter};
+use std::path;
+
+// externs
+use crate::hound;
+
+// this crate
+use crate::crc::crc16;
+use crate::decoder;
+use crate::error::X3Error;
+use crate::metadata::{ArchiveMetadata, XmlElement};
+use crate::scan;
+use crate::sensor::Sensor;
+use crate::x3::{self, FrameHeader, Parameters};
+
+/// A frame of a DTAG file.
+pub struct DtagFrame {
+  pub header: FrameHeader,
+  pub payload: Vec<u8>,
+}
+
+///
+/// Read the X3 frames of a DTAG file in order.  The configuration is read from the frames
+/// with source id 0 as they are found.
+///
+pub struct DtagReader {
+  reader: BufReader<File>,
+
+  /// The `<CFG>` elements that have been read so far, the last one of each id is kept.
+  metadata: ArchiveMetadata,
+
+  /// The count of frames that were skipped because of a bad payload CRC.
+  crc_errors: usize,
+
+  /// The count of frames that couldn't be decoded.
+  frame_errors: usize,
+}
+
+impl DtagReader {
+  pub fn open<P: AsRef<path::Path>>(dtg_filename: P) -> Result<Self, X3Error> {
+    let file = File::open(dtg_filename)?;
+    Ok(DtagReader {
+      reader: BufReader::with_capacity(64 * 1024, file),
+      metadata: ArchiveMetadata { elements: Vec::new() },
+      crc_errors: 0,
+      frame_errors: 0,
+    })
+  }
+
+  /// The configuration that has been read so far.
+  pub fn metadata(&self) -> &ArchiveMetadata {
+    &self.metadata
+  }
+
+  /// The ids of the sources that have a configuration, in the order they were found.
+  pub fn source_ids(&self) -> Vec<u8> {
+    self.metadata.source_ids()
+  }
+
+  /// The `<CFG>` that describes the frames of `source_id`.
+  pub fn config(&self, source_id: u8) -> Option<&XmlElement> {
+    self.metadata.cfg(source_id)
+  }
+
+  pub fn crc_errors(&self) -> usize {
+    self.crc_errors
+  }
+
+  pub fn frame_errors(&self) -> usize {
+    self.frame_errors
+  }
+
+  /// The sample rate of `source_id`, from `<FS>`.
+  pub fn sample_rate(&self, source_id: u8) -> Option<u32> {
+    self.metadata.source_sample_rate(source_id)
+  }
+
+  /// The X3 parameters of `source_id`, from `<CODEC>`.
+  pub fn params(&self, source_id: u8) -> Result<Parameters, X3Error> {
+    self.metadata.source_params(source_id)
+  }
+
+  /// Whether the frames of `source_id` hold audio, rather than sensor samples.
+  pub fn is_audio(&self, source_id: u8) -> bool {
+    let ftype = self.config(source_id).and_then(|cfg| cfg.attribute("FTYPE"));
+    ftype.is_some_and(|ftype| ftype.eq_ignore_ascii_case("WAV"))
+  }
+
+  ///
+  /// Read the next frame.  The bytes that are not part of a frame are skipped, as are the
+  /// frames with a bad payload CRC.  A file that ends part way through a frame is an error.
+  ///
+  /// ### Returns
+  ///
+  /// * the frame, or `None` when the end of the file has been reached.
+  ///
+  pub fn next_frame(&mut self) -> Result<Option<DtagFrame>, X3Error> {
+    let parse = |bytes: &[u8; FrameHeader::LENGTH]| {
+      let header = decoder::parse_frame_header(bytes).ok()?;
+      let payload_len = header.payload_len;
+      Some((header, payload_len))
+    };
+    loop {
+      let (frame_header, payload) = match scan::next_block(&mut self.reader, parse)? {
+        Some(block) => (block.header, block.payload),
+        None => return Ok(None),
+      };
+      if crc16(&payload) != frame_header.payload_crc {
+        self.crc_errors += 1;
+        continue;
+      }
+
+      if frame_header.source_id == x3::Archive::XML_SOURCE_ID {
+        self.read_configs(&payload)?;
+      }
+      return Ok(Some(DtagFrame {
+        header: frame_header,
+        payload,
+      }));
+    }
+  }
+
+  //
+  // Add the `<CFG>` elements in the XML of a configuration frame.
+  //
+  fn read_configs(&mut self, payload: &[u8]) -> Result<(), X3Error> {
+    let xml = String::from_utf8_lossy(payload);
+    let metadata = ArchiveMetadata::from_xml(xml.trim_end_matches('\0'))?;
+
+    for cfg in metadata.find_all("CFG") {
+      if let Some(id) = cfg.attribute("ID").and_then(|id| id.trim().parse().ok()) {
+        self.metadata.set_cfg(id, cfg.clone());
+      }
+    }
+    Ok(())
+  }
+
+  ///
+  /// Decode the X3 samples in `frame` into `wav_buf`, the channels are interleaved.  A frame
+  /// that can't be decoded is counted by `frame_errors`.
+  ///
+  /// ### Returns
+  ///
+  /// * the number of values that were decoded, the samples times the channels.
+  ///
+  pub fn decode_frame(&mut self, frame: &DtagFrame, wav_buf: &mut [i16]) -> Result<usize, X3Error> {
+    let result = self.decode(frame, wav_buf);
+    if result.is_err() {
+      self.frame_errors += 1;
+    }
+    result
+  }
+
+  fn decode(&self, frame: &DtagFrame, wav_buf: &mut [i16]) -> Result<usize, X3Error> {
+    let params = self.params(frame.header.source_id)?;
+    let samples = frame.header.samples as usize;
+    let channels = frame.header.channels as usize;
+    Ok(decoder::decode_frame_channels(&frame.payload, wav_buf, &params, samples, channels)?.unwrap_or(0))
+  }
+}
+
+// Where the frames of one source are written.
+enum DtagOutput {
+  Wav(hound::WavWriter<BufWriter<File>>),
+  Csv {
+    writer: BufWriter<File>,
+    sample_rate: f64,
+
+    // The time of the first frame (us), and the samples written since
+    first_time: u64,
+    count: usize,
+  },
+  Raw(BufWriter<File>),
+  Skip,
+}
+
+///
+/// Unpack a DTAG `.dtg` or `.bin` file.  The audio sources are decoded to wav files, the
+/// sensor sources to CSV files and the payloads of the other sources, like the logs, are
+/// written as they are, with the padding to a whole word.  The files are called
+/// `<dtg name>.<suffix>`, such as `1234.wav`, `1234.acc.csv` and `1234.log`.  The frames of
+/// the sources without a configuration, or without a sample rate for X3 sources, are
+/// skipped, as are the frames with a bad CRC and the frames that can't be decoded.
+///
+/// Each row of a CSV file has the time in seconds from the first sample, then the value of
+/// each channel.
+///
+/// ### Arguments
+///
+/// * `dtg_filename` - the input `.dtg` or `.bin` file.
+/// * `out_dir` - the directory to write the files to, existing files will be overwritten.
+///
+/// ### Returns
+///
+/// * the files that were written, and the number of frames that were skipped because of a
+///   bad CRC or because they couldn't be decoded.
+///
+pub fn dtg_to_wav<P: AsRef<path::Path>, Q: AsRef<path::Path>>(
+  dtg_filename: P,
+  out_dir: Q,
+) -> Result<(Vec<path::PathBuf>, usize), X3Error> {
+  let stem = dtg_filename.as_ref().file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+  let mut reader = DtagReader::open(&dtg_filename)?;
+
+  let mut filenames = Vec::new();
+  let mut outputs: Vec<(u8, DtagOutput)> = Vec::new();
+  let mut wav = Vec::new();
+  while let Some(frame) = reader.next_frame()? {
+    let id = frame.header.source_id;
+    if id == x3::Archive::XML_SOURCE_ID || reader.config(id).is_none() {
+      continue;
+    }
+
+    let i = match outputs.iter().position(|(output_id, _)| *output_id == id) {
+      Some(i) => i,
+      None => {
+        let output = create_output(&reader, &frame, out_dir.as_ref(), &stem, &mut filenames)?;
+        outputs.push((id, output));
+        outputs.len() - 1
+      }
+    };
+
+    let channels = std::cmp::max(1, frame.header.channels as usize);
+    let samples = match &outputs[i].1 {
+      DtagOutput::Wav(_) | DtagOutput::Csv { .. } => {
+        wav.resize(frame.header.samples as usize * channels, 0);
+        match reader.decode_frame(&frame, &mut wav) {
+          Ok(samples) => samples,
+          Err(_) => continue,
+        }
+      }
+      _ => 0,
+    };
+
+    match &mut outputs[i].1 {
+      DtagOutput::Wav(writer) => {
+        for sample in &wav[0..samples] {
+          writer.write_sample(*sample)?;
+        }
+      }
+      DtagOutput::Csv {
+        writer,
+        sample_rate,
+        first_time,
+        count,
+      } => {
+        // Follow the frame times across gaps, if they are known.  A frame that is earlier
+        // than the first follows on from the last one.
+        if frame.header.time != 0 && *first_time != 0 {
+          if let Some(elapsed) = frame.header.time.checked_sub(*first_time) {
+            *count = (elapsed as f64 * *sample_rate / 1e6).round() as usize;
+          }
+        }
+        for row in wav[0..samples].chunks(channels) {
+          write!(writer, "{}", *count as f64 / *sample_rate)?;
+          for value in row {
+            write!(writer, ",{}", value)?;
+          }
+          writeln!(writer)?;
+          *count += 1;
+        }
+      }
+      DtagOutput::Raw(writer) => writer.write_all(&frame.payload)?,
+      DtagOutput::Skip => (),
+    }
+  }
+
+  for (_, output) in outputs {
+    match output {
+      DtagOutput::Wav(writer) => writer.finalize()?,
+      DtagOutput::Csv { mut writer, .. } | DtagOutput::Raw(mut writer) => writer.flush()?,
+      DtagOutput::Skip => (),
+    }
+  }
+
+  Ok((filenames, reader.crc_errors() + reader.frame_errors()))
+}
+
+//
+// Create the file for the source of `frame`, called `<stem>.<suffix>` or
+// `<stem>.<id>.<suffix>` if that name is taken.
+//
+fn create_output(
+  reader: &DtagReader,
+  frame: &DtagFrame,
+  out_dir: &path::Path,
+  stem: &str,
+  filenames: &mut Vec<path::PathBuf>,
+) -> Result<DtagOutput, X3Error> {
+  let id = frame.header.source_id;
+  let cfg = match reader.config(id) {
+    Some(cfg) => cfg,
+    None => return Ok(DtagOutput::Skip),
+  };
+  let ftype = cfg.attribute("FTYPE").unwrap_or_default().to_ascii_lowercase();
+  let mut suffix = match cfg.attribute("SUFFIX") {
+    Some(suffix) => suffix.to_string(),
+    None => cfg.child("SUFFIX").map(|s| s.text.trim().to_string()).unwrap_or_default(),
+  };
+  if suffix.is_empty() {
+    suffix = ftype;
+  }
+
+  let is_x3 = cfg.child("CODEC").is_some();
+  let sample_rate = reader.sample_rate(id);
+  let channels = std::cmp::max(1, frame.header.channels as u16);
+  if is_x3 && sample_rate.is_none() {
+    return Ok(DtagOutput::Skip);
+  }
+  if is_x3 && !reader.is_audio(id) {
+    suffix.push_str(".csv");
+  }
+
+  let filename = scan::output_filename(out_dir, stem, id, &suffix, filenames);
+
+  let output = match sample_rate {
+    Some(sample_rate) if is_x3 && reader.is_audio(id) => {
+      let spec = hound::WavSpec {
+        channels,
+        sample_rate,
+        bits_per_sample: 16,
+        sample_format: hound::SampleFormat::Int,
+      };
+      DtagOutput::Wav(hound::WavWriter::create(&filename, spec)?)
+    }
+    Some(sample_rate) if is_x3 => {
+      let mut writer = BufWriter::new(File::create(&filename)?);
+      write!(writer, "time (s)")?;
+      for column in csv_columns(reader, id, channels) {
+        write!(writer, ",{}", column)?;
+      }
+      writeln!(writer)?;
+      DtagOutput::Csv {
+        writer,
+        sample_rate: f64::from(sample_rate),
+        first_time: frame.header.time,
+        count: 0,
+      }
+    }
+    _ => DtagOutput::Raw(BufWriter::new(File::create(&filename)?)),
+  };
+  filenames.push(filename);
+  Ok(output)
+}
+
+//
+// The CSV column names of the channels of a sensor source, `<name> (<unit>)` for the
+// sensors described with `<SENSOR>`, or the channel number.
+//
+fn csv_columns(reader: &DtagReader, id: u8, channels: u16) -> Vec<String> {
+  match Sensor::from_metadata(reader.metadata(), id) {
+    Some(sensor) if channels == 1 => vec![format!("{} ({})", sensor.name, sensor.unit)],
+    Some(sensor) => (1..=channels).map(|c| format!("{}{} ({})", sensor.name, c, sensor.unit)).collect(),
+    None => (1..=channels).map(|c| c.to_string()).collect(),
+  }
+}
+
+//
+//
+//            #######
+//               #       ######     ####     #####     ####
+//               #       #         #           #      #
+//               #       #####      ####       #       ####
+//               #       #              #      #           #
+//               #       #         #    #      #      #    #
+//               #       ######     ####       #       ####
+//
+//
+
+#[cfg(test)]
+mod tests {
+  use crate::bitpacker::BitPacker;
+  use crate::dtag::{dtg_to_wav, DtagReader};
+  use crate::encoder::{encode_frame_channels, write_frame_header};
+  use crate::testutil::{sine, temp_path};
+  use crate::x3::Parameters;
+
+  const CONFIG: &str = "<DTAG><CFG ID=\"1\" FTYPE=\"WAV\"><FS>48000</FS><CODEC TYPE=\"X3\"><BLKLEN>16</BLKLEN>\
+                        <CODES N=\"4\">RICE0,RICE1,RICE3,BFP</CODES><T N=\"3\">3,8,20</T></CODEC></CFG>\
+                        <CFG ID=\"2\" FTYPE=\"SENS\" SUFFIX=\"acc\"><FS>10</FS><CODEC TYPE=\"X3\"><BLKLEN>4</BLKLEN>\
+                        <CODES N=\"4\">RICE0,RICE1,RICE3,BFP</CODES><T N=\"3\">3,8,20</T></CODEC></CFG>\
+                        <CFG ID=\"3\" FTYPE=\"TXT\"><SUFFIX>log</SUFFIX></CFG></DTAG>";
+
+  // A frame with the bytes of `payload`, padded to a word.
+  fn raw_frame(source_id: u8, payload: &[u8]) -> Vec<u8> {
+    let mut bp = BitPacker::with_capacity(0);
+    bp.bookmark().unwrap();
+    bp.inc_counter_n_bytes(crate::x3::FrameHeader::LENGTH).unwrap();
+    bp.write_bytes(payload).unwrap();
+    if payload.len() % 2 == 1 {
+      bp.write_bits(0, 8).unwrap();
+    }
+    write_frame_header(&mut bp, 0, source_id, 0, 0).unwrap();
+    bp.into_vec()
+  }
+
+  fn x3_frame(source_id: u8, channels: &[&[i16]], time: u64, params: &Parameters) -> Vec<u8> {
+    let mut bp = BitPacker::with_capacity(0);
+    let mut last_wavs = vec![0i16; channels.len()];
+    encode_frame_channels(channels, source_id, time, &mut last_wavs, &mut bp, params, &mut [0; 6]).unwrap();
+    bp.into_vec()
+  }
+
+  #[test]
+  fn test_dtag_reader() {
+    let wav_params = Parameters::builder().block_len(16).build().unwrap();
+    let sens_params = Parameters::builder().block_len(4).build().unwrap();
+    let wav = sine(9_001, 0.02, 3000.0, 5);
+    let acc: Vec<Vec<i16>> = (0..3).map(|c| (0..30).map(|i| (i * (c + 1) - 20) as i16).collect()).collect();
+    let log = "12:00:00 Start\n12:00:01 Stop.\n";
+
+    // Data that isn't part of a frame, then the configuration
+    let mut dtg = b"DTAG\x00x3 file header".to_vec();
+    dtg.extend(raw_frame(0, CONFIG.as_bytes()));
+    for (i, part) in wav.chunks(4000).enumerate() {
+      dtg.extend(x3_frame(1, &[part], 1_600_000_000_000_000 + i as u64 * 83_333, &wav_params));
+      let acc_part: Vec<&[i16]> = acc.iter().map(|ch| &ch[i * 10..(i + 1) * 10]).collect();
+      dtg.extend(x3_frame(2, &acc_part, 1_600_000_000_000_000 + i as u64 * 1_000_000, &sens_params));
+    }
+
+    // A frame that is earlier than the first follows on from the last one
+    dtg.extend(x3_frame(2, &[&[1; 10], &[2; 10], &[3; 10]], 1_000, &sens_params));
+
+    // An audio frame that is too short for its samples is skipped
+    let mut short = BitPacker::with_capacity(0);
+    short.bookmark().unwrap();
+    short.inc_counter_n_bytes(crate::x3::FrameHeader::LENGTH).unwrap();
+    short.write_bytes(&[0x12, 0x34]).unwrap();
+    write_frame_header(&mut short, 4000, 1, 1, 0).unwrap();
+    dtg.extend(short.into_vec());
+
+    // A frame with a bad payload CRC is skipped, as are the sources without a configuration
+    let mut bad = raw_frame(3, b"lost");
+    *bad.last_mut().unwrap() ^= 1;
+    dtg.extend(bad);
+    dtg.extend(raw_frame(3, log.as_bytes()));
+    dtg.extend(raw_frame(9, b"unknown"));
+
+    let dtg_filename = temp_path("dtag.dtg");
+    std::fs::write(&dtg_filename, dtg).unwrap();
+
+    let mut reader = DtagReader::open(&dtg_filename).unwrap();
+    let mut channels = Vec::new();
+    let mut buf = vec![0; 4000];
+    while let Some(frame) = reader.next_frame().unwrap() {
+      match frame.header.source_id {
+        1 => assert_eq!(reader.decode_frame(&frame, &mut buf).is_ok(), frame.header.time != 0),
+        2 => channels.push(frame.header.channels),
+        _ => (),
+      }
+    }
+    assert_eq!((1, 1), (reader.crc_errors(), reader.frame_errors()));
+    assert_eq!(vec![1, 2, 3], reader.source_ids());
+    assert_eq!(vec![3, 3, 3, 3], channels);
+    assert!(reader.is_audio(1) && !reader.is_audio(2));
+    assert_eq!(Some(10), reader.sample_rate(2));
+    assert_eq!(4, reader.params(2).unwrap().block_len);
+
+    let (filenames, skipped) = dtg_to_wav(&dtg_filename, std::env::temp_dir()).unwrap();
+    let expected = ["wav", "acc.csv", "log"].map(|suffix| dtg_filename.with_extension(suffix));
+    assert_eq!(expected.to_vec(), filenames);
+    assert_eq!(2, skipped);
+
+    let mut wav_reader = hound::WavReader::open(&filenames[0]).unwrap();
+    assert_eq!(48000, wav_reader.spec().sample_rate);
+    let decoded: Vec<i16> = wav_reader.samples::<i16>().map(|s| s.unwrap()).collect();
+    assert!(wav == decoded);
+
+    let csv = std::fs::read_to_string(&filenames[1]).unwrap();
+    let lines: Vec<&str> = csv.lines().collect();
+    assert_eq!(41, lines.len());
+    assert_eq!("time (s),1,2,3", lines[0]);
+    assert_eq!("0,-20,-20,-20", lines[1]);
+    assert_eq!("2.9,9,38,67", lines[30]);
+    assert_eq!("3,1,2,3", lines[31]);
+    assert_eq!(log, std::fs::read_to_string(&filenames[2]).unwrap());
+
+    std::fs::remove_file(&dtg_filename).unwrap();
+    for filename in filenames {
+      std::fs::remove_file(filename).unwrap();
+    }
+  }
+}
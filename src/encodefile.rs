@@ -22,6 +22,7 @@
 // std
 use std::fs::File;
 use std::io::prelude::*;
+use std::io::BufWriter;
 use std::path;
 
 // externs
@@ -31,6 +32,10 @@ use crate::hound;
 use crate::bitpacker::BitPacker;
 use crate::encoder;
 use crate::error;
+use crate::metadata::ArchiveMetadata;
+use crate::raw::RawX3Reader;
+use crate::sensor::Sensor;
+use crate::wavchunks::WavChunks;
 use crate::x3;
 
 use error::X3Error;
@@ -44,84 +49,341 @@ use error::X3Error;
 /// * `x3a_filename` - the output X3A file.  It will be overwritten.
 ///
 pub fn wav_to_x3a<P: AsRef<path::Path>>(wav_filename: P, x3a_filename: P) -> Result<(), X3Error> {
-  let mut reader = hound::WavReader::open(wav_filename).unwrap();
-
-  // Can only handle 16 bit data
-  assert_eq!(reader.spec().bits_per_sample, 16);
+  wav_to_x3a_parallel(wav_filename, x3a_filename, 1)
+}
 
-  // FIXME: We want to be able to handle multiple channels
-  assert_eq!(reader.spec().channels, 1);
+///
+/// Convert a .wav file to an .x3a (X3 Archive) file, the frames are encoded by
+/// `num_threads` threads.  The output is the same as `wav_to_x3a`.
+///
+/// ### Arguments
+///
+/// * `wav_filename` - the input wav file to read.
+/// * `x3a_filename` - the output X3A file.  It will be overwritten.
+/// * `num_threads` - the number of threads to encode with, `0` will use all the available cores.
+///
+pub fn wav_to_x3a_parallel<P: AsRef<path::Path>>(
+  wav_filename: P,
+  x3a_filename: P,
+  num_threads: usize,
+) -> Result<(), X3Error> {
+  wav_to_x3a_with_metadata(wav_filename, x3a_filename, &ArchiveMetadata::new(), num_threads)
+}
 
-  let params = x3::Parameters::default();
-  let sample_rate = reader.spec().sample_rate;
+///
+/// Convert a .wav file to an .x3a (X3 Archive) file, with `metadata` in the archive header.
+/// The wav is encoded with the parameters in `metadata`, and the sample rate in `metadata`
+/// is set from the wav.  All the other metadata is written as is, so the metadata from an
+/// `X3aReader` can be used to re-encode an archive.
+///
+/// The `LIST/INFO`, `bext` and `iXML` chunks of the wav are added to the metadata, and the
+/// Broadcast WAV origination time is used for the time of each frame.
+///
+/// ### Arguments
+///
+/// * `wav_filename` - the input wav file to read.
+/// * `x3a_filename` - the output X3A file.  It will be overwritten.
+/// * `metadata` - the XML metadata for the archive header.
+/// * `num_threads` - the number of threads to encode with, `0` will use all the available cores.
+///
+pub fn wav_to_x3a_with_metadata<P: AsRef<path::Path>>(
+  wav_filename: P,
+  x3a_filename: P,
+  metadata: &ArchiveMetadata,
+  num_threads: usize,
+) -> Result<(), X3Error> {
+  let (chunks, sample_rate, samples) = read_wav(wav_filename)?;
 
-  // FIXME: This is pretty memory inefficient.  Should process bit by bit
-  let samples = reader.samples::<i16>().map(|x| x.unwrap()).collect::<Vec<i16>>();
-  let first_channel = x3::Channel::new(0, &samples[0..], sample_rate, params);
+  let params = metadata.params()?;
+  let mut metadata = metadata.clone();
+  metadata.set_sample_rate(sample_rate);
+  chunks.set_metadata(&mut metadata);
+  let start_time = WavChunks::from_metadata(&metadata).start_time(sample_rate);
 
-  let num_samples = first_channel.wav.len();
-  let mut x3_out = vec![0u8; num_samples * 2];
+  let first_channel = x3::Channel::new(0, &samples[0..], sample_rate, params).with_start_time(start_time);
 
-  let bp = &mut BitPacker::new(&mut x3_out);
+  // The frames are written to disk as they are completed
+  let mut writer = BufWriter::new(File::create(x3a_filename)?);
+  let bp = &mut BitPacker::from_writer(&mut writer);
 
   // Output file header
-  create_archive_header(&first_channel, bp)?;
+  create_archive_header(&metadata, bp)?;
+  bp.flush()?;
+
+  if num_threads == 1 {
+    encoder::encode(&[&first_channel], bp)?;
+  } else {
+    encoder::encode_parallel(&[&first_channel], bp, num_threads)?;
+  }
+
+  // Write the remaining bytes to disk
+  bp.flush()?;
+  writer.flush()?;
+
+  Ok(())
+}
+
+///
+/// Convert several .wav files to one .x3a (X3 Archive) file.  Each wav is a source, the
+/// first has source id 1, the second 2 and so on.  Each source is described by the `<CFG>`
+/// with its source id, with its own sample rate and parameters.  Use
+/// `decodefile::x3a_source_to_wav` to get the wavs back.
+///
+/// ### Arguments
+///
+/// * `wav_filenames` - the input wav files to read, up to 255.
+/// * `x3a_filename` - the output X3A file.  It will be overwritten.
+///
+pub fn wavs_to_x3a<P: AsRef<path::Path>>(wav_filenames: &[P], x3a_filename: P) -> Result<(), X3Error> {
+  wavs_to_x3a_with_metadata(wav_filenames, x3a_filename, &ArchiveMetadata::new())
+}
+
+///
+/// Convert several .wav files to one .x3a (X3 Archive) file, with `metadata` in the archive
+/// header.  See `wavs_to_x3a` and `wav_to_x3a_with_metadata`.  A source is encoded with
+/// the parameters in its `<CFG>`, or with the parameters of source 1 if it has none.
+///
+/// ### Arguments
+///
+/// * `wav_filenames` - the input wav files to read, up to 255.
+/// * `x3a_filename` - the output X3A file.  It will be overwritten.
+/// * `metadata` - the XML metadata for the archive header.
+///
+pub fn wavs_to_x3a_with_metadata<P: AsRef<path::Path>>(
+  wav_filenames: &[P],
+  x3a_filename: P,
+  metadata: &ArchiveMetadata,
+) -> Result<(), X3Error> {
+  if wav_filenames.len() > u8::MAX as usize {
+    return Err(X3Error::InvalidSourceId);
+  }
+
+  let mut metadata = metadata.clone();
+  let mut wavs = Vec::with_capacity(wav_filenames.len());
+  let mut sources = Vec::with_capacity(wav_filenames.len());
+  for (i, wav_filename) in wav_filenames.iter().enumerate() {
+    let source_id = (i + 1) as u8;
+    let (chunks, sample_rate, samples) = read_wav(wav_filename)?;
+
+    let params = metadata.source_params(source_id).or_else(|_| metadata.params())?;
+    metadata.set_source_params(source_id, &params);
+    metadata.set_source_sample_rate(source_id, sample_rate);
+    chunks.set_source_metadata(&mut metadata, source_id);
+    let start_time = WavChunks::from_source_metadata(&metadata, source_id).start_time(sample_rate);
+
+    wavs.push(samples);
+    sources.push((source_id, sample_rate, params, start_time));
+  }
+
+  let channels: Vec<x3::Channel> = sources
+    .into_iter()
+    .zip(wavs.iter())
+    .map(|((source_id, sample_rate, params, start_time), samples)| {
+      x3::Channel::new(0, samples, sample_rate, params)
+        .with_start_time(start_time)
+        .with_source_id(source_id)
+    })
+    .collect();
+  let sources: Vec<&x3::Channel> = channels.iter().collect();
+
+  write_x3a(x3a_filename, &metadata, &sources)
+}
+
+///
+/// Convert a .wav file to an .x3a (X3 Archive) file, with sensor streams next to the
+/// audio.  The audio is source 1, so each sensor needs a different source id.  The sensors
+/// are taken to start at the same time as the audio.
+///
+/// ### Arguments
+///
+/// * `wav_filename` - the input wav file to read.
+/// * `x3a_filename` - the output X3A file.  It will be overwritten.
+/// * `sensors` - each sensor and its samples.
+///
+pub fn wav_to_x3a_with_sensors<P: AsRef<path::Path>>(
+  wav_filename: P,
+  x3a_filename: P,
+  sensors: &[(&Sensor, &[i16])],
+) -> Result<(), X3Error> {
+  let (chunks, sample_rate, samples) = read_wav(wav_filename)?;
 
-  encoder::encode(&[&first_channel], bp)?;
+  let mut metadata = ArchiveMetadata::new();
+  metadata.set_sample_rate(sample_rate);
+  chunks.set_metadata(&mut metadata);
+  let start_time = WavChunks::from_metadata(&metadata).start_time(sample_rate);
 
-  // Write to disk
-  let mut file = File::create(x3a_filename)?;
-  file.write_all(bp.as_bytes())?;
+  let audio = x3::Channel::new(0, &samples, sample_rate, metadata.params()?).with_start_time(start_time);
+  let mut channels = vec![audio];
+  for (sensor, samples) in sensors {
+    sensor.set_metadata(&mut metadata);
+    channels.push(sensor.channel(samples).with_start_time(start_time));
+  }
+  let sources: Vec<&x3::Channel> = channels.iter().collect();
+
+  write_x3a(x3a_filename, &metadata, &sources)
+}
+
+///
+/// Write an .x3a (X3 Archive) file with `metadata` in the archive header, and the frames of
+/// each of the `sources` interleaved in time order.  Each source should be described by the
+/// `<CFG>` with its source id in `metadata`, see `ArchiveMetadata::cfg_mut` and
+/// `Sensor::set_metadata`.
+///
+/// ### Arguments
+///
+/// * `x3a_filename` - the output X3A file.  It will be overwritten.
+/// * `metadata` - the XML metadata for the archive header.
+/// * `sources` - the streams to encode, each with a different source id.
+///
+pub fn write_x3a<P: AsRef<path::Path>>(
+  x3a_filename: P,
+  metadata: &ArchiveMetadata,
+  sources: &[&x3::Channel],
+) -> Result<(), X3Error> {
+  let mut writer = BufWriter::new(File::create(x3a_filename)?);
+  let bp = &mut BitPacker::from_writer(&mut writer);
+
+  create_archive_header(metadata, bp)?;
+  bp.flush()?;
+
+  encoder::encode_sources(sources, bp)?;
+
+  bp.flush()?;
+  writer.flush()?;
 
   Ok(())
 }
 
+///
+/// Wrap a raw stream of X3 frames, with no archive id or XML header, in an .x3a (X3
+/// Archive) file.  The frames are copied as they are, after their CRCs are checked, and the
+/// frames with a bad header or payload are left out.  See `raw::RawX3Reader`.
+///
+/// ### Arguments
+///
+/// * `raw_filename` - the input raw X3 stream.
+/// * `x3a_filename` - the output X3A file.  It will be overwritten.
+/// * `params` - the parameters the frames were encoded with.
+/// * `sample_rate` - the number of samples per second.
+/// * `channels` - the number of channels in each frame.
+///
+/// ### Returns
+///
+/// * the number of frames that were left out.
+///
+pub fn raw_to_x3a<P: AsRef<path::Path>>(
+  raw_filename: P,
+  x3a_filename: P,
+  params: &x3::Parameters,
+  sample_rate: u32,
+  channels: u8,
+) -> Result<usize, X3Error> {
+  let raw = std::io::BufReader::with_capacity(64 * 1024, File::open(raw_filename)?);
+  let mut reader = RawX3Reader::new(raw, params.clone(), sample_rate, channels)?;
+
+  let mut metadata = ArchiveMetadata::new();
+  metadata.set_sample_rate(sample_rate);
+  metadata.set_params(params);
+  if channels > 1 {
+    metadata.set_channels(channels);
+  }
+
+  let mut writer = BufWriter::new(File::create(x3a_filename)?);
+  {
+    let bp = &mut BitPacker::from_writer(&mut writer);
+    create_archive_header(&metadata, bp)?;
+    bp.flush()?;
+  }
+
+  while reader.next_frame()?.is_some() {
+    writer.write_all(reader.frame_bytes())?;
+  }
+  writer.flush()?;
+
+  Ok(reader.frame_errors())
+}
+
+///
+/// Encode `wav` as an .x3a (X3 Archive) held in memory, see `decodefile::decode_x3a_bytes`.
+///
+/// ### Arguments
+///
+/// * `wav` - the samples.
+/// * `spec` - the sample rate and parameters, the audio must be one channel.
+///
+/// ### Returns
+///
+/// * the bytes of the archive.
+///
+pub fn encode_x3a_to_vec(wav: &[i16], spec: &x3::X3aSpec) -> Result<Vec<u8>, X3Error> {
+  if spec.channels > 1 {
+    return Err(X3Error::MoreThanOneChannel);
+  }
+
+  let mut metadata = ArchiveMetadata::new();
+  metadata.set_sample_rate(spec.sample_rate);
+  metadata.set_params(&spec.params);
+
+  let mut bp = BitPacker::with_capacity(wav.len());
+  create_archive_header(&metadata, &mut bp)?;
+
+  let channel = x3::Channel::new(0, wav, spec.sample_rate, spec.params.clone());
+  encoder::encode_sources_with_stats(&[&channel], &mut bp)?;
+
+  Ok(bp.into_vec())
+}
+
+//
+// Read a mono 16 bit wav, with its metadata chunks and sample rate.  Other formats return
+// `X3Error::UnsupportedWav` or `X3Error::MoreThanOneChannel`.
+//
+fn read_wav<P: AsRef<path::Path>>(wav_filename: P) -> Result<(WavChunks, u32, Vec<i16>), X3Error> {
+  let mut reader = hound::WavReader::open(&wav_filename)?;
+  let spec = reader.spec();
+
+  // Can only handle 16 bit data
+  if spec.bits_per_sample != 16 || spec.sample_format != hound::SampleFormat::Int {
+    return Err(X3Error::UnsupportedWav);
+  }
+
+  // FIXME: We want to be able to handle multiple channels
+  if spec.channels != 1 {
+    return Err(X3Error::MoreThanOneChannel);
+  }
+
+  // FIXME: This is pretty memory inefficient.  Should process bit by bit
+  let samples = reader.samples::<i16>().collect::<Result<Vec<i16>, _>>()?;
+  let chunks = WavChunks::read(&wav_filename)?;
+
+  Ok((chunks, spec.sample_rate, samples))
+}
+
 //
 // Write <Archive Header> to the BitPacker output.
 //
-fn create_archive_header(ch: &x3::Channel, bp: &mut BitPacker) -> Result<(), X3Error> {
+pub(crate) fn create_archive_header(metadata: &ArchiveMetadata, bp: &mut BitPacker) -> Result<(), X3Error> {
   // <Archive Id>
-  bp.write_bytes(x3::Archive::ID);
+  bp.write_bytes(x3::Archive::ID)?;
 
   // Make space for the header
-  bp.bookmark();
+  bp.bookmark()?;
   bp.inc_counter_n_bytes(x3::FrameHeader::LENGTH)?;
 
-  let xml: &str = &[
-    // "<X3A>",
-    // "<?xml version=\"1.0\" encoding=\"US-ASCII\" ?>",
-    "<X3ARCH PROG=\"x3new.m\" VERSION=\"2.0\" />",
-    "<CFG ID=\"0\" FTYPE=\"XML\" />",
-    "<CFG ID=\"1\" FTYPE=\"WAV\">",
-    &format!("<FS UNIT=\"Hz\">{}</FS>", ch.sample_rate),
-    "<SUFFIX>wav</SUFFIX>",
-    "<CODEC TYPE=\"X3\" VERS=\"2\">",
-    &format!("<BLKLEN>{}</BLKLEN>", ch.params.block_len),
-    &format!(
-      "<CODES N=\"4\">RICE{},RICE{},RICE{},BFP</CODES>",
-      ch.params.codes[0], ch.params.codes[1], ch.params.codes[2]
-    ),
-    "<FILTER>DIFF</FILTER>",
-    "<NBITS>16</NBITS>",
-    &format!(
-      "<T N=\"3\">{},{},{}</T>",
-      ch.params.thresholds[0], ch.params.thresholds[1], ch.params.thresholds[2]
-    ),
-    "</CODEC>",
-    "</CFG>",
-    // "</X3A>",
-  ]
-  .concat();
+  // The XML is the payload of the header frame
+  let xml = metadata.to_xml();
+  if xml.len() >= x3::Frame::MAX_LENGTH {
+    return Err(X3Error::FrameLength);
+  }
 
   // <XML MetaData>
-  bp.write_bytes(xml.as_bytes());
+  bp.write_bytes(xml.as_bytes())?;
   if xml.len() % 2 == 1 {
     // Align to the nearest word
-    bp.write_bits(0, 8);
+    bp.write_bits(0, 8)?;
   }
 
   // <Frame Header>
-  encoder::write_frame_header(bp, 0, 0)?;
+  encoder::write_frame_header(bp, 0, x3::Archive::XML_SOURCE_ID, 0, 0)?;
 
   Ok(())
 }
@@ -19,8 +19,8 @@
  *                                                                        *
  **************************************************************************/
 
-// externs
-use crate::byteorder::{BigEndian, ByteOrder};
+// std
+use std::thread;
 
 // This crate
 use crate::bitpacker::BitPacker;
@@ -42,23 +42,220 @@ pub fn encode(channels: &[&x3::Channel], bp: &mut BitPacker) -> Result<(), X3Err
   if channels.len() > 1 {
     return Err(X3Error::MoreThanOneChannel);
   }
-  let ch = &channels[0];
-  let mut num_samples = ch.wav.len();
+  encode_sources(channels, bp)
+}
 
-  let samples_per_frame = ch.params.block_len * ch.params.blocks_per_frame;
+///
+/// Encode several streams into one archive, each with its own source id, sample rate and
+/// parameters.  The frames of the sources are interleaved in time order, a frame from the
+/// source that is furthest behind is written next.  With one source the output is the same
+/// as `encode`.
+///
+/// Each source should be described by the `<CFG>` with its source id in the archive header,
+/// see `ArchiveMetadata::cfg_mut`.
+///
+/// ### Arguments
+///
+/// * `sources` - The streams to encode, each must have a different `source_id`.
+/// * `bp` - A `BitPacker` where the compressed data will be written to.
+///
+pub fn encode_sources(sources: &[&x3::Channel], bp: &mut BitPacker) -> Result<(), X3Error> {
+  let stats = encode_sources_with_stats(sources, bp)?;
+  print_stats(&stats);
 
-  let mut last_wav: i16 = 0;
+  Ok(())
+}
+
+///
+/// Encode several streams into one archive, the same as `encode_sources`.  The statistics
+/// are returned rather than printed.
+///
+/// ### Returns
+///
+/// * the number of samples encoded with each type of block, see `FrameEncoderState::stats`.
+///
+pub fn encode_sources_with_stats(sources: &[&x3::Channel], bp: &mut BitPacker) -> Result<[usize; 6], X3Error> {
+  check_source_ids(sources)?;
+
+  // The next sample to encode and the last sample of the previous frame, for each source
+  let mut positions = vec![0usize; sources.len()];
+  let mut last_wavs = vec![0i16; sources.len()];
   let stats: &mut [usize; 6] = &mut [0; 6];
 
-  let mut p = 0;
-  while num_samples > 0 {
-    let encode_num_samples = core::cmp::min(num_samples, samples_per_frame);
-    let wav = &ch.wav[p..(p + encode_num_samples)];
-    encode_frame(wav, &mut last_wav, bp, &ch.params, stats)?;
-    p += samples_per_frame;
-    num_samples -= encode_num_samples;
+  loop {
+    // The source with the earliest unencoded sample
+    let next = (0..sources.len())
+      .filter(|&i| positions[i] < sources[i].wav.len())
+      .min_by_key(|&i| (source_time(sources[i], positions[i]), i));
+    let i = match next {
+      Some(i) => i,
+      None => break,
+    };
+
+    let ch = sources[i];
+    let p = positions[i];
+    let samples_per_frame = ch.params.samples_per_frame();
+    let end = core::cmp::min(ch.wav.len(), p + samples_per_frame);
+
+    // A frame may be closed early to keep it under `Frame::MAX_LENGTH` bytes
+    let mut q = p;
+    while q < end {
+      q += encode_channel_frame(ch, q, end - q, &mut last_wavs[i], bp, stats)?;
+      bp.flush()?;
+    }
+    positions[i] = end;
+  }
+
+  Ok(*stats)
+}
+
+//
+// Check that no source uses the XML header's source id, and that no two sources share one.
+//
+fn check_source_ids(sources: &[&x3::Channel]) -> Result<(), X3Error> {
+  for (i, ch) in sources.iter().enumerate() {
+    if ch.source_id == x3::Archive::XML_SOURCE_ID || sources[..i].iter().any(|c| c.source_id == ch.source_id) {
+      return Err(X3Error::InvalidSourceId);
+    }
+  }
+  Ok(())
+}
+
+//
+// The time of `ch.wav[sample]` in microseconds, for ordering the frames of several
+// sources.  Sources without a start time start at 0.
+//
+fn source_time(ch: &x3::Channel, sample: usize) -> u128 {
+  let offset = match ch.sample_rate {
+    0 => 0,
+    rate => sample as u128 * 1_000_000 / u128::from(rate),
+  };
+  u128::from(ch.start_time) + offset
+}
+
+///
+/// Encode a wav file (represented as `Channels`) using multiple threads.  The frames are
+/// split evenly between `num_threads` workers, each encoding into its own buffer.  The
+/// buffers are then written to `bp` in order, so the output is identical to `encode`.
+///
+/// ### Arguments
+///
+/// * `channels` - The list of channels to encode.  // FIXME: This is currently only one.
+/// * `bp` - A `BitPacker` where the compressed data will be written to.
+/// * `num_threads` - The number of worker threads, `0` will use all the available cores.
+///
+pub fn encode_parallel(channels: &[&x3::Channel], bp: &mut BitPacker, num_threads: usize) -> Result<(), X3Error> {
+  let stats = encode_parallel_with_stats(channels, bp, num_threads)?;
+  print_stats(&stats);
+
+  Ok(())
+}
+
+///
+/// Encode a wav file using multiple threads, the same as `encode_parallel`.  The statistics
+/// are returned rather than printed.
+///
+/// ### Returns
+///
+/// * the number of samples encoded with each type of block, see `FrameEncoderState::stats`.
+///
+pub fn encode_parallel_with_stats(
+  channels: &[&x3::Channel],
+  bp: &mut BitPacker,
+  num_threads: usize,
+) -> Result<[usize; 6], X3Error> {
+  if channels.len() > 1 {
+    return Err(X3Error::MoreThanOneChannel);
+  }
+  check_source_ids(channels)?;
+  let ch: &x3::Channel = match channels.first() {
+    Some(ch) => ch,
+    None => return Err(X3Error::InvalidChannels),
+  };
+  let params = &ch.params;
+
+  let samples_per_frame = params.block_len * params.blocks_per_frame;
+  let frames: Vec<&[i16]> = ch.wav.chunks(samples_per_frame).collect();
+
+  let num_threads = match num_threads {
+    0 => thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+    n => n,
+  };
+  let frames_per_thread = core::cmp::max(1, frames.len().div_ceil(num_threads));
+
+  // The first frame must have the same alignment as it would have in `bp`
+  let first_offset = bp.bytes_written() % 2;
+
+  let results: Vec<Result<EncodedGroup, X3Error>> = thread::scope(|s| {
+    let workers: Vec<_> = frames
+      .chunks(frames_per_thread)
+      .enumerate()
+      .map(|(i, group)| {
+        let offset = if i == 0 { first_offset } else { 0 };
+        let first_sample = i * frames_per_thread * samples_per_frame;
+        s.spawn(move || encode_frame_group(group, ch, first_sample, offset))
+      })
+      .collect();
+
+    workers
+      .into_iter()
+      .map(|w| w.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+      .collect()
+  });
+
+  let mut stats = [0usize; 6];
+  for result in results {
+    let (x3_bytes, group_stats) = result?;
+    bp.write_bytes(&x3_bytes)?;
+    bp.flush()?;
+    for (total, n) in stats.iter_mut().zip(group_stats.iter()) {
+      *total += n;
+    }
   }
 
+  Ok(stats)
+}
+
+// The encoded bytes of a group of frames, and the statistics of the group.
+type EncodedGroup = (Vec<u8>, [usize; 6]);
+
+//
+// Encode a group of consecutive frames into a new buffer, the buffer will start with
+// `offset` bytes of padding that are removed once the frames have been encoded.
+//
+fn encode_frame_group(
+  frames: &[&[i16]],
+  ch: &x3::Channel,
+  first_sample: usize,
+  offset: usize,
+) -> Result<EncodedGroup, X3Error> {
+  let params = &ch.params;
+  let buf_len = offset + frames.iter().map(|f| x3::Frame::max_length(f.len(), params.block_len)).sum::<usize>();
+  let mut stats = [0usize; 6];
+
+  let mut bp = BitPacker::with_capacity(buf_len);
+  bp.inc_counter_n_bytes(offset)?;
+
+  let mut last_wav: i16 = 0;
+  let mut sample = first_sample;
+  for wav in frames {
+    let mut p = 0;
+    while p < wav.len() {
+      p += encode_channel_frame(ch, sample + p, wav.len() - p, &mut last_wav, &mut bp, &mut stats)?;
+    }
+    sample += wav.len();
+  }
+
+  let mut x3_out = bp.into_vec();
+  x3_out.drain(..offset);
+
+  Ok((x3_out, stats))
+}
+
+//
+// Print the percentage of samples encoded by each block type.
+//
+fn print_stats(stats: &[usize; 6]) {
   let t = (stats[0] + stats[1] + stats[2] + stats[3] + stats[4] + stats[5]) as f32;
   println!(
     "\nStatistics:\n  Rice-0: {:.4}%\n  Rice-1: {:.4}%\n  Rice-2: {:.4}%\n  Rice-3: {:.4}%\n  BFP: {:.4}%\n  Pass-through {:.4}%\n",
@@ -69,8 +266,6 @@ pub fn encode(channels: &[&x3::Channel], bp: &mut BitPacker) -> Result<(), X3Err
     (stats[4] as f32 / t) * 100.0,
     (stats[5] as f32 / t) * 100.0
   );
-
-  Ok(())
 }
 
 ///
@@ -80,56 +275,37 @@ pub fn encode(channels: &[&x3::Channel], bp: &mut BitPacker) -> Result<(), X3Err
 ///
 /// * `bp` - A `BitPacker` where the frame data will be written to.
 /// * `num_samples` - The number of samples that are contained in the wav.
-/// * `id` -  The source id.
+/// * `source_id` -  The source id, the `<CFG ID="..">` that describes the frame.
+/// * `channels` - The number of channels in the frame, 0 for the XML header frame.
+/// * `time` - The time of the first sample, microseconds since the Unix epoch or 0 if unknown.
 ///
-pub fn write_frame_header(bp: &mut BitPacker, num_samples: usize, id: u8) -> Result<(), X3Error> {
-  let header: &mut [u8; x3::FrameHeader::LENGTH] = &mut [0u8; x3::FrameHeader::LENGTH];
-
+pub fn write_frame_header(
+  bp: &mut BitPacker,
+  num_samples: usize,
+  source_id: u8,
+  channels: u8,
+  time: u64,
+) -> Result<(), X3Error> {
   // frame_len = header.len + payload.len
   let frame_len = bp.bookmark_get_offset();
   let payload_len = frame_len - x3::FrameHeader::LENGTH;
 
-  // <Frame Key> = "x3"
-  let mut p = 0;
-  BigEndian::write_u16(&mut header[p..], x3::FrameHeader::KEY);
-  p += 2;
-
-  // <Source Id> = The source Id
-  header[p] = id;
-  p += 1;
-
-  // <Num Channels> = The number of channels
-  header[p] = id;
-  p += 1;
-  // FIXME: Should write the `channel.id` value
-  // BigEndian::write_u8(&mut header[p..], channel.id);
-
-  // <Num Samples> = The number of uncompressed samples.
-  BigEndian::write_u16(&mut header[p..], num_samples as u16);
-  p += 2;
-
-  // <Payload Length> = The number of compressed bytes.
-  BigEndian::write_u16(&mut header[p..], payload_len as u16);
-  p += 2;
-
-  // <Time> = The timestamp of the first sample in the frame.
-  // FIXME: Need to add the time
-  p += 8;
-
-  // <Header CRC> = CRC of the frame header
-  let header_crc = crc16(&header[0..x3::FrameHeader::P_HEADER_CRC]);
-  BigEndian::write_u16(&mut header[p..], header_crc as u16);
-  p += 2;
-
-  // <Payload CRC> = CRC of the payload
+  // <Payload CRC> = CRC of the payload, the payload must be written out first
+  bp.word_align()?;
   let frame = bp.bookmark_get_from();
   let payload = &frame[x3::FrameHeader::LENGTH..(x3::FrameHeader::LENGTH + payload_len)];
-  let payload_crc = crc16(payload);
-  BigEndian::write_u16(&mut header[p..], payload_crc as u16);
+
+  let header = x3::FrameHeader {
+    source_id,
+    samples: num_samples as u16,
+    channels,
+    payload_len,
+    time,
+    payload_crc: crc16(payload),
+  };
 
   // Write it back to the bit stream
-  bp.word_align();
-  bp.bookmark_write(header);
+  bp.bookmark_write(&header.to_bytes())?;
 
   Ok(())
 }
@@ -145,51 +321,330 @@ pub fn write_frame_header(bp: &mut BitPacker, num_samples: usize, id: u8) -> Res
 /// * `params` - The audio parameters.
 /// * `stats` - Used for statistics which get printed out at the end.
 ///
+/// The frame is closed early, before a block that could take the payload to
+/// `Frame::MAX_LENGTH` bytes, so the number of samples that were encoded is returned.  The
+/// rest of `wav` should be encoded in the following frames.
+///
+/// An empty `wav` does not write a frame.  The frame header can only hold up to
+/// `u16::MAX` samples, `X3Error::FrameLength` is returned for longer frames.
+///
 pub fn encode_frame(
   wav: &[i16],
   last_wav: &mut i16,
   bp: &mut BitPacker,
   params: &x3::Parameters,
   stats: &mut [usize; 6],
-) -> Result<(), X3Error> {
-  // pack first sample for each channel
+) -> Result<usize, X3Error> {
+  encode_frame_with_time(wav, 0, last_wav, bp, params, stats)
+}
+
+///
+/// Encode one frame, the same as `encode_frame`, with `time` as the time of the first
+/// sample in the frame header.
+///
+/// ### Arguments
+/// * `wav` - the raw audio data.
+/// * `time` - the time of `wav[0]` in microseconds since the Unix epoch, 0 if unknown.
+/// * `last_wav` - the last wav value from the previous frame.
+/// * `bp` - Where the output x3 compressed bits will be written to.
+/// * `params` - The audio parameters.
+/// * `stats` - Used for statistics which get printed out at the end.
+///
+pub fn encode_frame_with_time(
+  wav: &[i16],
+  time: u64,
+  last_wav: &mut i16,
+  bp: &mut BitPacker,
+  params: &x3::Parameters,
+  stats: &mut [usize; 6],
+) -> Result<usize, X3Error> {
+  encode_source_frame(wav, x3::Archive::WAV_SOURCE_ID, time, last_wav, bp, params, stats)
+}
+
+//
+// Encode one frame of up to `len` samples of `ch`, starting at `ch.wav[sample]`.
+//
+fn encode_channel_frame(
+  ch: &x3::Channel,
+  sample: usize,
+  len: usize,
+  last_wav: &mut i16,
+  bp: &mut BitPacker,
+  stats: &mut [usize; 6],
+) -> Result<usize, X3Error> {
+  let wav = &ch.wav[sample..(sample + len)];
+  encode_source_frame(wav, ch.source_id, ch.sample_time(sample), last_wav, bp, &ch.params, stats)
+}
+
+//
+// Encode one frame, see `encode_frame_with_time`, with `source_id` in the frame header.
+//
+fn encode_source_frame(
+  wav: &[i16],
+  source_id: u8,
+  time: u64,
+  last_wav: &mut i16,
+  bp: &mut BitPacker,
+  params: &x3::Parameters,
+  stats: &mut [usize; 6],
+) -> Result<usize, X3Error> {
+  encode_frame_channels(&[wav], source_id, time, core::slice::from_mut(last_wav), bp, params, stats)
+}
+
+///
+/// Encode one frame with several channels, the same as `encode_frame_with_time`.  The
+/// first sample of each channel is written, then the blocks of the channels are
+/// interleaved, a block of each channel in turn.
+///
+/// ### Arguments
+/// * `channels` - the raw data of each channel, they must all be the same length.
+/// * `source_id` - the source id written to the frame header.
+/// * `time` - the time of the first samples in microseconds since the Unix epoch, 0 if unknown.
+/// * `last_wavs` - the last value of each channel from the previous frame.
+/// * `bp` - Where the output x3 compressed bits will be written to.
+/// * `params` - The audio parameters.
+/// * `stats` - Used for statistics which get printed out at the end.
+///
+/// The number of samples that were encoded from each channel is returned.
+///
+pub fn encode_frame_channels(
+  channels: &[&[i16]],
+  source_id: u8,
+  time: u64,
+  last_wavs: &mut [i16],
+  bp: &mut BitPacker,
+  params: &x3::Parameters,
+  stats: &mut [usize; 6],
+) -> Result<usize, X3Error> {
+  let num_channels = channels.len();
+  let len = channels.first().map_or(0, |ch| ch.len());
+  if num_channels > u8::MAX as usize || last_wavs.len() != num_channels || channels.iter().any(|ch| ch.len() != len) {
+    return Err(X3Error::InvalidChannels);
+  }
+  if len == 0 {
+    return Ok(0);
+  }
+  if len > u16::MAX as usize {
+    return Err(X3Error::FrameLength);
+  }
+
   let mut kx = 0; // input sample pointer
-  let mut rem_samples = wav.len() - 1; // Remaining samples
+  let mut rem_samples = len - 1; // Remaining samples
 
   // Now encode blocks of block_length samples. The last block may have fewer than block_length samples.
   // Data from multiple channels are interleaved on a block-by-block basis.
   let mut block_len = params.block_len;
 
   // Bookmark this location such that we can write the header here
-  bp.bookmark();
+  bp.bookmark()?;
   bp.inc_counter_n_bytes(x3::FrameHeader::LENGTH)?;
 
-  // Write first sample, <Audio State>, as a raw value
-  bp.write_bits(wav[0] as usize, 16);
+  // pack first sample for each channel, <Audio State>, as a raw value
+  for wav in channels {
+    bp.write_bits(wav[0] as usize, 16)?;
+  }
 
-  let wav_diff: &mut [i32] = &mut [0i32; x3::Parameters::MAX_BLOCK_LENGTH];
+  // Scratch space for the diffs of a block, the block length is only known at run time
+  let wav_diff: &mut [i32] = &mut vec![0i32; block_len];
   while rem_samples > 0 {
     block_len = core::cmp::min(block_len, rem_samples); // check block size
-    *last_wav = wav[kx + block_len];
+    if !block_fits(bp, block_len, num_channels) {
+      break;
+    }
 
     // pack the data block for each channel
-    let wav = &wav[kx..(kx + block_len)];
+    for (wav, last_wav) in channels.iter().zip(last_wavs.iter_mut()) {
+      *last_wav = wav[kx + block_len];
+      let wav = &wav[kx..(kx + block_len)];
 
-    let ftype = x3_encode_block(wav, wav_diff, *last_wav, bp, params)?;
+      let ftype = x3_encode_block(wav, wav_diff, *last_wav, bp, params)?;
 
-    stats[ftype] += block_len;
+      stats[ftype] += block_len;
+    }
 
     kx += block_len;
     rem_samples -= block_len;
   }
 
   // Wrap the bit to the next significant bit
-  bp.word_align();
+  bp.word_align()?;
 
-  // Write the header details
-  write_frame_header(bp, wav.len(), 1)?;
+  // Write the header details, the first sample and each of the blocks
+  let num_samples = kx + 1;
+  write_frame_header(bp, num_samples, source_id, num_channels as u8, time)?;
 
-  Ok(())
+  Ok(num_samples)
+}
+
+///
+/// The state that is carried from one frame to the next when frames are encoded one at a
+/// time with `encode_frame_to`.
+///
+#[derive(Debug, Clone)]
+pub struct FrameEncoderState {
+  /// The last sample of each channel from the previous frame.
+  pub(crate) last_wavs: Vec<i16>,
+
+  /// The number of samples encoded with each type of block.
+  pub(crate) stats: [usize; 6],
+}
+
+impl FrameEncoderState {
+  ///
+  /// The state for frames with `channels` channels, the samples given to `encode_frame_to`
+  /// are interleaved.
+  ///
+  pub fn new(channels: u8) -> Self {
+    FrameEncoderState {
+      last_wavs: vec![0; channels as usize],
+      stats: [0; 6],
+    }
+  }
+
+  pub fn channels(&self) -> u8 {
+    self.last_wavs.len() as u8
+  }
+
+  /// The number of samples encoded with each type of block, see `print_stats`.
+  pub fn stats(&self) -> &[usize; 6] {
+    &self.stats
+  }
+
+  ///
+  /// Encode interleaved samples as frames, for the writers that buffer samples until a
+  /// frame is full.  A frame is closed early to keep it under `Frame::MAX_LENGTH` bytes, and
+  /// the rest of the samples go in the next frame.
+  ///
+  /// ### Arguments
+  ///
+  /// * `wav` - the interleaved samples, a whole number of samples of each channel.
+  /// * `params` - The audio parameters.
+  /// * `source_id` - the source id written to the frame headers.
+  /// * `time` - the time for a frame header, given the index of its first sample in `wav`.
+  /// * `on_frame` - called with the bytes of each frame.
+  ///
+  pub(crate) fn encode_frames<T, F>(
+    &mut self,
+    wav: &[i16],
+    params: &x3::Parameters,
+    source_id: u8,
+    time: T,
+    mut on_frame: F,
+  ) -> Result<(), X3Error>
+  where
+    T: Fn(usize) -> u64,
+    F: FnMut(&[u8]) -> Result<(), X3Error>,
+  {
+    let num_channels = self.last_wavs.len();
+    if num_channels == 0 || !wav.len().is_multiple_of(num_channels) {
+      return Err(X3Error::InvalidChannels);
+    }
+    let samples = wav.len() / num_channels;
+    let channels: Vec<Vec<i16>> =
+      (0..num_channels).map(|c| wav.iter().skip(c).step_by(num_channels).copied().collect()).collect();
+
+    let mut p = 0;
+    while p < samples {
+      let frame: Vec<&[i16]> = channels.iter().map(|ch| &ch[p..]).collect();
+      let mut bp = BitPacker::with_capacity(x3::FrameHeader::LENGTH + 2 * (samples - p) * num_channels);
+      p += encode_frame_channels(&frame, source_id, time(p), &mut self.last_wavs, &mut bp, params, &mut self.stats)?;
+      on_frame(&bp.into_vec())?;
+    }
+    Ok(())
+  }
+}
+
+impl Default for FrameEncoderState {
+  fn default() -> Self {
+    Self::new(1)
+  }
+}
+
+///
+/// The values of the frame header that don't come from the samples.
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeaderOptions {
+  /// The source id, the `<CFG ID="..">` that describes the frame.
+  pub source_id: u8,
+
+  /// The time of the first sample, microseconds since the Unix epoch or 0 if unknown.
+  pub time: u64,
+}
+
+impl Default for FrameHeaderOptions {
+  fn default() -> Self {
+    FrameHeaderOptions {
+      source_id: x3::Archive::WAV_SOURCE_ID,
+      time: 0,
+    }
+  }
+}
+
+///
+/// Encode one frame on its own, for writing to a container other than an archive.  The
+/// frame header and payload are returned, see `decoder::decode_frame_bytes` to decode it.
+///
+/// ### Arguments
+///
+/// * `wav` - the samples, interleaved if `state` has more than one channel.  There can be
+///   up to 65535 samples of each channel.
+/// * `state` - the state from the previous frame, it is updated when the frame is encoded.
+/// * `params` - The audio parameters.
+/// * `header_opts` - the source id and time for the frame header.
+///
+/// ### Returns
+///
+/// * the bytes of the frame.  `X3Error::FrameLength` is returned if the samples don't fit
+///   in one frame, when many are in pass-through blocks.
+///
+pub fn encode_frame_to(
+  wav: &[i16],
+  state: &mut FrameEncoderState,
+  params: &x3::Parameters,
+  header_opts: &FrameHeaderOptions,
+) -> Result<Vec<u8>, X3Error> {
+  let num_channels = state.last_wavs.len();
+  if num_channels == 0 || !wav.len().is_multiple_of(num_channels) {
+    return Err(X3Error::InvalidChannels);
+  }
+  let channels: Vec<Vec<i16>> =
+    (0..num_channels).map(|c| wav.iter().skip(c).step_by(num_channels).copied().collect()).collect();
+  let channels: Vec<&[i16]> = channels.iter().map(|ch| &ch[..]).collect();
+
+  // The state is only updated once the whole frame is encoded
+  let mut last_wavs = state.last_wavs.clone();
+  let mut stats = state.stats;
+  let mut bp = BitPacker::with_capacity(x3::FrameHeader::LENGTH + 2 * wav.len());
+  let samples = encode_frame_channels(
+    &channels,
+    header_opts.source_id,
+    header_opts.time,
+    &mut last_wavs,
+    &mut bp,
+    params,
+    &mut stats,
+  )?;
+  if samples < wav.len() / num_channels {
+    return Err(X3Error::FrameLength);
+  }
+
+  state.last_wavs = last_wavs;
+  state.stats = stats;
+  Ok(bp.into_vec())
+}
+
+//
+// Check that a block of `block_len` samples of each channel can be added to the frame and
+// keep the payload under `Frame::MAX_LENGTH` bytes, even if they are pass-through blocks.
+//
+#[inline(always)]
+fn block_fits(bp: &BitPacker, block_len: usize, num_channels: usize) -> bool {
+  let payload_len = bp.bookmark_get_offset() - x3::FrameHeader::LENGTH;
+  let block_bytes = num_channels * (BFP_HDR_LEN + x3::Parameters::WAV_BIT_SIZE * block_len).div_ceil(8);
+
+  // Allow for the word alignment at the end of the frame
+  payload_len + block_bytes + 1 < x3::Frame::MAX_LENGTH
 }
 
 //
@@ -243,7 +698,7 @@ fn encode_rice_block(
   }
 
   // 2 bit rice block header
-  bp.write_bits(ftype as usize + 1, 2);
+  bp.write_bits(ftype + 1, 2)?;
   let rc = params.rice_codes[ftype];
   let codes = rc.code;
   let num_bits = rc.num_bits;
@@ -255,8 +710,8 @@ fn encode_rice_block(
     let rc_num_bits = num_bits[ii];
     let num_zeros = rc_num_bits - count_bits(code as u32) as usize;
 
-    bp.write_packed_zeros(num_zeros);
-    bp.write_bits(code, rc_num_bits - num_zeros);
+    bp.write_packed_zeros(num_zeros)?;
+    bp.write_bits(code, rc_num_bits - num_zeros)?;
   }
 
   Ok(rc.nsubs)
@@ -269,34 +724,36 @@ fn encode_bfp_block(
   max_abs_inp_filtd: i32,
 ) -> Result<usize, X3Error> {
   let block_len = wav.len(); // requested block length
-  let ftype: usize; // find which code to use
 
-  // number of bits needed to represent right-justified samples
-  let num_bits = count_bits(max_abs_inp_filtd as u32); // number of bits
+  // number of bits needed to represent right-justified samples, the diffs are written with
+  // one extra sign bit.  The decoder only accepts BFP blocks of at least 6 bits.
+  let num_bits = core::cmp::max(count_bits(max_abs_inp_filtd as u32), BFP_MIN_BITS); // number of bits
 
-  if num_bits >= 15 {
-    bp.write_bits(15, BFP_HDR_LEN);
+  let ftype = if num_bits >= 15 {
+    bp.write_bits(15, BFP_HDR_LEN)?;
     // We write all the bytes out without any compression
     let mut w = i32::from(wav[0]);
     for wd in wav_diff.iter().take(block_len) {
-      w += *wd as i32;
-      bp.write_bits(w as usize, 16);
+      w += *wd;
+      bp.write_bits(w as usize, 16)?;
     }
-    ftype = 5;
+    5
   } else {
-    bp.write_bits(num_bits as usize, BFP_HDR_LEN);
+    bp.write_bits(num_bits as usize, BFP_HDR_LEN)?;
     // Reduce the number of bits only.
     for wd in wav_diff.iter().take(block_len) {
-      bp.write_bits(*wd as usize, num_bits as usize + 1);
+      bp.write_bits(*wd as usize, num_bits as usize + 1)?;
     }
-    ftype = 4;
-  }
+    4
+  };
 
   Ok(ftype)
 }
 
 /// This will encode NSAMPLES of data.
 const BFP_HDR_LEN: usize = 6;
+/// The fewest magnitude bits a BFP block can have.
+const BFP_MIN_BITS: u32 = 5;
 fn x3_encode_block(
   wav: &[i16],
   wav_diff: &mut [i32],
@@ -329,8 +786,12 @@ fn x3_encode_block(
 #[cfg(test)]
 mod tests {
   use crate::bitpacker::BitPacker;
+  use crate::bitreader::BitReader;
+  use crate::decoder::{decode_block, decode_frame_bytes, DecodeMode};
   use crate::encoder;
+  use crate::error::X3Error;
   use crate::encoder::{encode_frame, x3_encode_block};
+  use crate::testutil::{noise, sine};
   use crate::x3;
   use crate::x3::Parameters;
 
@@ -409,7 +870,7 @@ mod tests {
 
     let expected_x3_output: &[u8] = &[
       // Frame header
-      'x' as u8, '3' as u8, // "x3"
+      b'x', b'3', // "x3"
       1, 1, // Source Id, Num Channels
       wlh, wll, // Num samples
       2, 144, // Num encoded bytes
@@ -501,7 +962,7 @@ mod tests {
 
     // Run the code
     x3_encode_block(wav, wav_diff, last_wav, bp, params).unwrap();
-    bp.word_align();
+    bp.word_align().unwrap();
 
     // Check output is okay
     let expected_x3_output: &[u8] = &[202, 56, 106, 202, 124, 8, 122, 249, 136, 173, 202, 23, 80, 0];
@@ -523,9 +984,9 @@ mod tests {
     let wav_diff: &mut [i32] = &mut [0i32; x3::Parameters::MAX_BLOCK_LENGTH];
 
     // Run the code
-    bp.write_packed_zeros(1);
+    bp.write_packed_zeros(1).unwrap();
     x3_encode_block(wav, wav_diff, last_wav, bp, params).unwrap();
-    bp.word_align();
+    bp.word_align().unwrap();
 
     // Check output is okay
     let expected_x3_output: &[u8] = &[105, 111, 24, 196, 18, 125, 42, 40, 203, 219, 178, 194, 206, 0];
@@ -548,7 +1009,7 @@ mod tests {
 
     // Run the code
     x3_encode_block(wav, wav_diff, last_wav, bp, params).unwrap();
-    bp.word_align();
+    bp.word_align().unwrap();
 
     // Check output is okay
     let expected_x3_output: &[u8] = &[
@@ -574,7 +1035,7 @@ mod tests {
 
     // Run the code
     x3_encode_block(wav, wav_diff, last_wav, bp, params).unwrap();
-    bp.word_align();
+    bp.word_align().unwrap();
 
     // Check output is okay
     let expected_x3_output: &[u8] = &[
@@ -584,6 +1045,148 @@ mod tests {
     assert_eq!(expected_x3_output, &x3_output[0..expected_x3_output.len()],);
   }
 
+  //
+  // Encode and decode a block starting at `start` with the `diffs`, returning the 6 bit
+  // block header.
+  //
+  fn bfp_round_trip(start: i16, diffs: &[i32], params: &Parameters) -> usize {
+    let mut wav = vec![start];
+    for diff in diffs {
+      wav.push((i32::from(*wav.last().unwrap()) + diff) as i16);
+    }
+    let last_wav = wav.pop().unwrap();
+
+    let mut bp = BitPacker::with_capacity(0);
+    let wav_diff: &mut [i32] = &mut [0i32; x3::Parameters::MAX_BLOCK_LENGTH];
+    x3_encode_block(&wav, wav_diff, last_wav, &mut bp, params).unwrap();
+    bp.word_align().unwrap();
+    let x3_bytes = bp.into_vec();
+
+    let mut decoded = vec![0i16; wav.len()];
+    let mut decoded_last_wav = start;
+    let mut br = BitReader::new(&x3_bytes);
+    decode_block(&mut br, &mut decoded, &mut decoded_last_wav, params, DecodeMode::Strict).unwrap();
+    assert_eq!(&wav[1..], &decoded[0..(wav.len() - 1)]);
+    assert_eq!(last_wav, decoded_last_wav);
+
+    (x3_bytes[0] >> 2) as usize
+  }
+
+  #[test]
+  fn test_x3_encode_block_bfp_widths() {
+    let params = &Parameters::default();
+    for num_bits in 5..=14 {
+      // The largest magnitude diffs for the width, of both signs
+      let max = (1 << num_bits) - 1;
+      let diffs = [max, -max, 0, -max, max, -1, 1, -max, 3, max, -max, max / 2, -max / 2, 0, 1, -2, max, -max, 7, max];
+      assert_eq!(num_bits, bfp_round_trip(0, &diffs, params));
+    }
+
+    // Diffs that need 16 bits are sent as a pass-through block
+    let diffs = [65535, -65535, 32767, -32768, 0, 1, -1, 100, -100, 32767, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    assert_eq!(15, bfp_round_trip(i16::MIN, &diffs, params));
+  }
+
+  #[test]
+  fn test_x3_encode_block_bfp_min_width() {
+    // A low threshold selects BFP for small diffs, it must still be at least 6 bits wide
+    let params = &Parameters::new(20, 500, [0, 1, 3], [0, 0, 1]).unwrap();
+    let diffs = [2, -2, 1, 0, -1, 2, 2, -2, 1, 0, -1, 2, 2, -2, 1, 0, -1, 2, 0, 1];
+    assert_eq!(5, bfp_round_trip(10, &diffs, params));
+  }
+
+  #[test]
+  fn test_encode_parallel() {
+    // Noisy signal with a mix of rice, BFP and pass-through blocks over several frames
+    let wav: Vec<i16> =
+      noise(35_011, 0x1234_5678).iter().enumerate().map(|(i, n)| n >> [13, 6, 0][(i / 3000) % 3]).collect();
+
+    // The long frames are closed early to keep them under `Frame::MAX_LENGTH` bytes
+    let long_frames = x3::Parameters::builder().block_len(256).blocks_per_frame(255).build().unwrap();
+    for params in [x3::Parameters::default(), long_frames] {
+      let first_channel = x3::Channel::new(0, &wav, 44100, params);
+
+      let mut serial_out = vec![0u8; wav.len() * 3];
+      let serial_len = {
+        let bp = &mut BitPacker::new(&mut serial_out);
+        encoder::encode(&[&first_channel], bp).unwrap();
+        bp.as_bytes().len()
+      };
+
+      for num_threads in &[1, 2, 3, 8] {
+        let mut parallel_out = vec![0u8; wav.len() * 3];
+        let bp = &mut BitPacker::new(&mut parallel_out);
+        encoder::encode_parallel(&[&first_channel], bp, *num_threads).unwrap();
+
+        assert_eq!(&serial_out[0..serial_len], bp.as_bytes());
+      }
+    }
+  }
+
+  #[test]
+  fn test_encode_parallel_invalid_channels() {
+    let wav = [0i16; 100];
+    let a = x3::Channel::new(0, &wav, 8000, x3::Parameters::default());
+    let xml = x3::Channel::new(0, &wav, 8000, x3::Parameters::default()).with_source_id(0);
+
+    let mut bp = BitPacker::with_capacity(0);
+    assert!(matches!(encoder::encode_parallel(&[], &mut bp, 2), Err(X3Error::InvalidChannels)));
+    assert!(matches!(encoder::encode_parallel(&[&a, &a], &mut bp, 2), Err(X3Error::MoreThanOneChannel)));
+    assert!(matches!(encoder::encode_parallel(&[&xml], &mut bp, 2), Err(X3Error::InvalidSourceId)));
+  }
+
+  #[test]
+  fn test_encode_parallel_with_stats() {
+    let wav = noise(10_000, 0x2468_ace0);
+    let first_channel = x3::Channel::new(0, &wav, 44100, x3::Parameters::default());
+
+    let mut bp = BitPacker::with_capacity(0);
+    let serial = encoder::encode_sources_with_stats(&[&first_channel], &mut bp).unwrap();
+    let mut bp = BitPacker::with_capacity(0);
+    let parallel = encoder::encode_parallel_with_stats(&[&first_channel], &mut bp, 3).unwrap();
+
+    assert_eq!(serial, parallel);
+    // One frame, its first sample is in the frame header rather than a block
+    assert_eq!(wav.len() - 1, parallel.iter().sum::<usize>());
+  }
+
+  #[test]
+  fn test_encode_sources_invalid_id() {
+    let wav = [0i16; 100];
+    let a = x3::Channel::new(0, &wav, 8000, x3::Parameters::default());
+    let b = x3::Channel::new(0, &wav, 16000, x3::Parameters::default());
+    let xml = x3::Channel::new(0, &wav, 8000, x3::Parameters::default()).with_source_id(0);
+
+    let mut bp = BitPacker::with_capacity(0);
+    assert!(matches!(encoder::encode_sources(&[&a, &b], &mut bp), Err(X3Error::InvalidSourceId)));
+    assert!(matches!(encoder::encode_sources(&[&xml], &mut bp), Err(X3Error::InvalidSourceId)));
+
+    let b = b.with_source_id(2);
+    encoder::encode_sources(&[&a, &b], &mut bp).unwrap();
+  }
+
+  #[test]
+  fn test_encode_white_noise_to_writer() {
+    // White noise is encoded with pass-through blocks, it is larger than the raw samples
+    let wav = noise(25_003, 0x8765_4321);
+
+    let params = x3::Parameters::default();
+    let first_channel = x3::Channel::new(0, &wav, 44100, params);
+
+    let mut vec_bp = BitPacker::with_capacity(0);
+    encoder::encode(&[&first_channel], &mut vec_bp).unwrap();
+    let expected = vec_bp.into_vec();
+    assert!(expected.len() > wav.len() * 2);
+
+    let mut out: Vec<u8> = Vec::new();
+    {
+      let bp = &mut BitPacker::from_writer(&mut out);
+      encoder::encode(&[&first_channel], bp).unwrap();
+      assert_eq!(expected.len(), bp.bytes_written());
+    }
+    assert_eq!(expected, out);
+  }
+
   #[test]
   fn test_x3_encode_samples() {
     let wav: Vec<i16> = vec![0; 1000];
@@ -606,4 +1209,79 @@ mod tests {
     // Get the bytes
     let _x3_bytes = bp.as_bytes();
   }
+
+  #[test]
+  fn test_encode_frame_to() {
+    let params = &Parameters::default();
+    let wav = sine(2 * 1000, 0.03, 1500.0, 3);
+    let header_opts = encoder::FrameHeaderOptions {
+      source_id: 4,
+      time: 1_600_000_000_000_000,
+    };
+
+    // Two frames of a stereo stream
+    let mut state = encoder::FrameEncoderState::new(2);
+    let frames: Vec<Vec<u8>> = wav
+      .chunks(2 * 500)
+      .map(|f| encoder::encode_frame_to(f, &mut state, params, &header_opts).unwrap())
+      .collect();
+
+    // The first sample of each channel in a frame isn't in a block
+    assert_eq!(2 * 2 * 499, state.stats().iter().sum::<usize>());
+
+    let header = x3::FrameHeader::parse(&frames[1]).unwrap();
+    let values = (header.source_id, header.channels, header.samples, header.time);
+    assert_eq!((4, 2, 500, 1_600_000_000_000_000), values);
+    assert_eq!(frames[1].len(), x3::FrameHeader::LENGTH + header.payload_len);
+    assert_eq!(frames[1][0..x3::FrameHeader::LENGTH], header.to_bytes());
+
+    let decoded: Vec<i16> = frames.iter().flat_map(|f| decode_frame_bytes(f, params).unwrap()).collect();
+    assert_eq!(wav, decoded);
+
+    // Both CRCs are checked
+    let mut bad = frames[0].clone();
+    bad[x3::FrameHeader::LENGTH + 3] ^= 1;
+    let err = decode_frame_bytes(&bad, params).unwrap_err();
+    assert!(matches!(err, X3Error::FrameHeaderInvalidPayloadCRC));
+    bad[x3::FrameHeader::P_TIME] ^= 1;
+    let err = decode_frame_bytes(&bad, params).unwrap_err();
+    assert!(matches!(err, X3Error::FrameHeaderInvalidHeaderCRC));
+
+    // The samples must be whole for each channel
+    let err = encoder::encode_frame_to(&wav[0..3], &mut state, params, &header_opts).unwrap_err();
+    assert!(matches!(err, X3Error::InvalidChannels));
+  }
+
+  #[test]
+  fn test_encode_frames() {
+    // Stereo white noise, a full frame of it is too long so frames are closed early
+    let params = &Parameters::default();
+    let samples = params.samples_per_frame();
+    let wav = noise(2 * samples, 0x2468_ace0);
+
+    let mut state = encoder::FrameEncoderState::new(2);
+    let mut frames = Vec::new();
+    state
+      .encode_frames(&wav, params, 3, |p| p as u64, |frame| {
+        frames.push(frame.to_vec());
+        Ok(())
+      })
+      .unwrap();
+    assert!(frames.len() > 1);
+
+    let mut first = 0;
+    let mut decoded = Vec::new();
+    for frame in &frames {
+      assert!(frame.len() <= x3::Frame::MAX_LENGTH);
+      let header = x3::FrameHeader::parse(frame).unwrap();
+      assert_eq!((3, 2, first as u64), (header.source_id, header.channels, header.time));
+      first += header.samples as usize;
+      decoded.extend(decode_frame_bytes(frame, params).unwrap());
+    }
+    assert_eq!(wav, decoded);
+
+    // A sample missing one of its channels
+    let err = state.encode_frames(&wav[0..3], params, 3, |_| 0, |_| Ok(())).unwrap_err();
+    assert!(matches!(err, X3Error::InvalidChannels));
+  }
 }
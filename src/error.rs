@@ -26,11 +26,16 @@ pub enum X3Error {
   Io(std::io::Error),
   Hound(hound::Error),
   BitPack(crate::bitpacker::BitPackError),
+  Parameters(crate::x3::ParametersError),
 
   // Custom X3 Errors
-  InvalidEncodingThresh, // Threshold must be less than or equal to code.offset
+  #[deprecated(note = "invalid thresholds are now reported as `X3Error::Parameters`")]
+  InvalidEncodingThresh, // Thresholds must increase and be in the range of the rice code
   OutOfBoundsInverse,    // The value is out-of-bounds for the .inv array.
   MoreThanOneChannel,    // FIXME: We need to support more than one channel
+  InvalidSourceId,       // The source id is the XML header's, missing from the archive header, or used twice
+  InvalidChannels,       // The channels of a frame have different lengths, or there are none or too many
+  UnsupportedWav,        // The wav samples are not 16 bit integers
 
   // X3 Archive Header errors
   ArchiveHeaderXMLInvalid,    // XML is poorly structured
@@ -54,6 +59,7 @@ pub enum X3Error {
   FrameDecodeInvalidRiceCode,    // The Rice codes are invalid
   FrameDecodeInvalidBPF,         // The BPF decoder blew up, an invalid value was reached.
   FrameDecodeUnexpectedEnd,      // The BitReader has less bytes than the size of the header, but still expects a frame.
+  FrameDecodeSampleOverflow,     // A decoded sample is outside the 16 bit range, the frame is corrupt.
 }
 
 impl From<std::io::Error> for X3Error {
@@ -73,3 +79,18 @@ impl From<crate::bitpacker::BitPackError> for X3Error {
     X3Error::BitPack(err)
   }
 }
+
+impl From<crate::x3::ParametersError> for X3Error {
+  fn from(err: crate::x3::ParametersError) -> X3Error {
+    X3Error::Parameters(err)
+  }
+}
+
+impl From<X3Error> for std::io::Error {
+  fn from(err: X3Error) -> std::io::Error {
+    match err {
+      X3Error::Io(err) => err,
+      err => std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{:?}", err)),
+    }
+  }
+}
@@ -22,13 +22,27 @@
 extern crate byteorder;
 extern crate hound;
 
+#[cfg(feature = "tokio")]
+pub mod asyncx3a;
 pub mod bitpacker;
 pub mod bitreader;
 pub mod bytereader;
 pub mod crc;
 pub mod decodefile;
 pub mod decoder;
+pub mod dtag;
 pub mod encodefile;
 pub mod encoder;
 pub mod error;
+pub mod metadata;
+pub mod raw;
+#[cfg(feature = "fast-rice")]
+mod ricetable;
+mod scan;
+pub mod sensor;
+pub mod stream;
+pub mod sud;
+#[cfg(test)]
+mod testutil;
+pub mod wavchunks;
 pub mod x3;
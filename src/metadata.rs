@@ -0,0 +1,559 @@
+/**************************************************************************
+ *                                                                        *
+ * Rust implementation of the X3 lossless audio compression protocol.     *
+ *                                                                        *
+ * Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>             *
+ *                                                                        *
+ * This program is free software; you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the           *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.   *
+ *                                                                        *
+ **************************************************************************/
+
+//!
+//! The XML metadata in the header of an X3 archive.  The whole tree is kept, so the
+//! elements this crate doesn't use (device IDs, deployment details, gains, sensor
+//! information or any user elements) survive a decode and re-encode.
+//!
+//! ```text
+//! <X3ARCH PROG="x3-rust" VERSION="2.0" />
+//! <CFG ID="0" FTYPE="XML" />
+//! <CFG ID="1" FTYPE="WAV">
+//!   <FS UNIT="Hz">48000</FS>
+//!   <SUFFIX>wav</SUFFIX>
+//!   <CODEC TYPE="X3" VERS="2">...</CODEC>
+//!   <DEPLOYMENT>...</DEPLOYMENT>
+//! </CFG>
+//! ```
+//!
+
+// externs
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+
+// this crate
+use crate::error::X3Error;
+use crate::x3::{self, Parameters};
+
+///
+/// An element of the archive XML, with its attributes, text and child elements.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct XmlElement {
+  pub name: String,
+  pub attributes: Vec<(String, String)>,
+  pub text: String,
+  pub children: Vec<XmlElement>,
+}
+
+impl XmlElement {
+  pub fn new(name: &str) -> Self {
+    XmlElement {
+      name: name.to_string(),
+      ..Default::default()
+    }
+  }
+
+  /// Add an attribute, for building elements.
+  pub fn with_attribute(mut self, key: &str, value: &str) -> Self {
+    self.set_attribute(key, value);
+    self
+  }
+
+  /// Set the text, for building elements.
+  pub fn with_text(mut self, text: &str) -> Self {
+    self.text = text.to_string();
+    self
+  }
+
+  /// Add a child element, for building elements.
+  pub fn with_child(mut self, child: XmlElement) -> Self {
+    self.children.push(child);
+    self
+  }
+
+  pub fn attribute(&self, key: &str) -> Option<&str> {
+    self.attributes.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+  }
+
+  /// Set the attribute `key`, an existing attribute keeps its position.
+  pub fn set_attribute(&mut self, key: &str, value: &str) {
+    match self.attributes.iter_mut().find(|(k, _)| k == key) {
+      Some((_, v)) => *v = value.to_string(),
+      None => self.attributes.push((key.to_string(), value.to_string())),
+    }
+  }
+
+  /// The first child element called `name`.
+  pub fn child(&self, name: &str) -> Option<&XmlElement> {
+    self.children.iter().find(|c| c.name == name)
+  }
+
+  pub fn child_mut(&mut self, name: &str) -> Option<&mut XmlElement> {
+    self.children.iter_mut().find(|c| c.name == name)
+  }
+
+  /// The first element called `name` in the tree below this element, depth first.
+  pub fn find(&self, name: &str) -> Option<&XmlElement> {
+    self.children.iter().find_map(|c| if c.name == name { Some(c) } else { c.find(name) })
+  }
+
+  //
+  // Add the elements called `name` in the tree from this element to `found`, in the order
+  // they appear.  The elements below a match are not searched.
+  //
+  fn find_all<'a>(&'a self, name: &str, found: &mut Vec<&'a XmlElement>) {
+    if self.name == name {
+      found.push(self);
+      return;
+    }
+    for child in &self.children {
+      child.find_all(name, found);
+    }
+  }
+
+  /// Replace the first child element with the same name, or add it to the end.
+  pub fn set_child(&mut self, child: XmlElement) {
+    match self.child_mut(&child.name) {
+      Some(c) => *c = child,
+      None => self.children.push(child),
+    }
+  }
+
+  //
+  // Write the element as XML, elements without text or children are written as empty
+  // elements.
+  //
+  fn write(&self, xml: &mut String) {
+    xml.push('<');
+    xml.push_str(&self.name);
+    for (key, value) in &self.attributes {
+      xml.push_str(&format!(" {}=\"{}\"", key, escape_str(value)));
+    }
+    if self.text.is_empty() && self.children.is_empty() {
+      xml.push_str(" />");
+      return;
+    }
+    xml.push('>');
+    xml.push_str(&escape_str(&self.text));
+    for child in &self.children {
+      child.write(xml);
+    }
+    xml.push_str(&format!("</{}>", self.name));
+  }
+}
+
+//
+// Escape the characters that can't appear in XML text or attribute values.
+//
+fn escape_str(s: &str) -> String {
+  let mut escaped = String::with_capacity(s.len());
+  for c in s.chars() {
+    match c {
+      '<' => escaped.push_str("&lt;"),
+      '>' => escaped.push_str("&gt;"),
+      '&' => escaped.push_str("&amp;"),
+      '"' => escaped.push_str("&quot;"),
+      '\'' => escaped.push_str("&apos;"),
+      _ => escaped.push(c),
+    }
+  }
+  escaped
+}
+
+///
+/// The XML metadata of an archive.  The archive XML has no root element, `elements` are
+/// the top level elements in the order they appear.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveMetadata {
+  pub elements: Vec<XmlElement>,
+}
+
+impl ArchiveMetadata {
+  /// The program that is written to `<X3ARCH PROG="...">` by default.
+  pub const PROG: &'static str = "x3-rust";
+
+  /// The `<CFG ID="...">` of the configuration that describes the audio.
+  pub const WAV_CFG_ID: &'static str = "1";
+
+  ///
+  /// The metadata for an archive with the default parameters, the sample rate should be set
+  /// before it is written.
+  ///
+  pub fn new() -> Self {
+    let mut metadata = ArchiveMetadata {
+      elements: vec![
+        XmlElement::new("X3ARCH")
+          .with_attribute("PROG", Self::PROG)
+          .with_attribute("VERSION", "2.0"),
+        XmlElement::new("CFG").with_attribute("ID", "0").with_attribute("FTYPE", "XML"),
+      ],
+    };
+    metadata.set_params(&Parameters::default());
+    metadata
+  }
+
+  ///
+  /// Parse the XML from an archive header.
+  ///
+  pub fn from_xml(xml: &str) -> Result<Self, X3Error> {
+    // The text is kept as is, the whitespace between elements is removed below
+    let mut reader = Reader::from_str(xml);
+
+    let mut buf = Vec::new();
+    let mut elements = Vec::new();
+
+    // The elements that are open, the innermost is last
+    let mut stack: Vec<XmlElement> = Vec::new();
+
+    loop {
+      match reader.read_event(&mut buf) {
+        Ok(Event::Start(ref e)) => stack.push(read_element(e)?),
+        Ok(Event::Empty(ref e)) => {
+          let element = read_element(e)?;
+          match stack.last_mut() {
+            Some(parent) => parent.children.push(element),
+            None => elements.push(element),
+          }
+        }
+        Ok(Event::Text(ref e)) | Ok(Event::CData(ref e)) => {
+          if let Some(element) = stack.last_mut() {
+            let text = e.unescaped().map_err(|_| X3Error::ArchiveHeaderXMLInvalid)?;
+            element.text.push_str(&String::from_utf8_lossy(&text));
+          }
+        }
+        Ok(Event::End(_)) => {
+          let mut element = stack.pop().ok_or(X3Error::ArchiveHeaderXMLInvalid)?;
+          if !element.children.is_empty() && element.text.trim().is_empty() {
+            element.text.clear();
+          }
+          match stack.last_mut() {
+            Some(parent) => parent.children.push(element),
+            None => elements.push(element),
+          }
+        }
+        Ok(Event::Eof) => break,
+        Err(_) => return Err(X3Error::ArchiveHeaderXMLInvalid),
+        _ => (), // The declaration, comments and processing instructions are not kept
+      }
+      buf.clear();
+    }
+
+    if !stack.is_empty() {
+      return Err(X3Error::ArchiveHeaderXMLInvalid);
+    }
+
+    Ok(ArchiveMetadata { elements })
+  }
+
+  pub fn to_xml(&self) -> String {
+    let mut xml = String::new();
+    for element in &self.elements {
+      element.write(&mut xml);
+    }
+    xml
+  }
+
+  ///
+  /// All the elements called `name`, in the order they appear.  The elements below a match
+  /// are not searched.
+  ///
+  pub fn find_all(&self, name: &str) -> Vec<&XmlElement> {
+    let mut found = Vec::new();
+    for element in &self.elements {
+      element.find_all(name, &mut found);
+    }
+    found
+  }
+
+  /// The `<X3ARCH>` element that names the program that wrote the archive.
+  pub fn archive(&self) -> Option<&XmlElement> {
+    self.elements.iter().find(|e| e.name == "X3ARCH")
+  }
+
+  /// The `<CFG>` element that describes the audio.
+  pub fn wav_cfg(&self) -> Option<&XmlElement> {
+    self.cfg(x3::Archive::WAV_SOURCE_ID)
+  }
+
+  /// The `<CFG>` element that describes the audio, it is added if it is missing.
+  pub fn wav_cfg_mut(&mut self) -> &mut XmlElement {
+    self.cfg_mut(x3::Archive::WAV_SOURCE_ID)
+  }
+
+  /// The `<CFG>` element that describes the frames with `source_id`.
+  pub fn cfg(&self, source_id: u8) -> Option<&XmlElement> {
+    self.elements.iter().find(|e| is_cfg(e, source_id))
+  }
+
+  ///
+  /// The `<CFG>` element that describes the frames with `source_id`, an audio configuration
+  /// is added if it is missing.
+  ///
+  pub fn cfg_mut(&mut self, source_id: u8) -> &mut XmlElement {
+    let pos = match self.elements.iter().position(|e| is_cfg(e, source_id)) {
+      Some(pos) => pos,
+      None => {
+        let cfg = XmlElement::new("CFG")
+          .with_attribute("ID", &source_id.to_string())
+          .with_attribute("FTYPE", "WAV")
+          .with_child(XmlElement::new("SUFFIX").with_text("wav"));
+        self.elements.push(cfg);
+        self.elements.len() - 1
+      }
+    };
+    &mut self.elements[pos]
+  }
+
+  /// Replace the `<CFG>` element of `source_id` with `cfg`, or add it to the end.
+  pub fn set_cfg(&mut self, source_id: u8, cfg: XmlElement) {
+    match self.elements.iter_mut().find(|e| is_cfg(e, source_id)) {
+      Some(e) => *e = cfg,
+      None => self.elements.push(cfg),
+    }
+  }
+
+  ///
+  /// The source ids of the streams described in the header, in the order they appear.  The
+  /// XML configuration is not a stream, so it isn't included.
+  ///
+  pub fn source_ids(&self) -> Vec<u8> {
+    self
+      .elements
+      .iter()
+      .filter(|e| e.name == "CFG" && e.attribute("FTYPE") != Some("XML"))
+      .filter_map(|e| e.attribute("ID")?.trim().parse().ok())
+      .filter(|&id| id != x3::Archive::XML_SOURCE_ID)
+      .collect()
+  }
+
+  //
+  // The `name` element of the audio configuration, or the first one anywhere for archives
+  // that are laid out differently.
+  //
+  fn wav_element(&self, name: &str) -> Option<&XmlElement> {
+    self.wav_cfg().and_then(|cfg| cfg.child(name)).or_else(|| {
+      self.elements.iter().find_map(|e| if e.name == name { Some(e) } else { e.find(name) })
+    })
+  }
+
+  /// The sample rate from `<FS>`.
+  pub fn sample_rate(&self) -> Option<u32> {
+    self.wav_element("FS")?.text.trim().parse().ok()
+  }
+
+  pub fn set_sample_rate(&mut self, sample_rate: u32) {
+    self.set_source_sample_rate(x3::Archive::WAV_SOURCE_ID, sample_rate);
+  }
+
+  /// The number of channels of the audio from `<NCHS>`, 1 if it isn't given.
+  pub fn channels(&self) -> u8 {
+    self.wav_element("NCHS").and_then(|e| e.text.trim().parse().ok()).unwrap_or(1)
+  }
+
+  pub fn set_channels(&mut self, channels: u8) {
+    self.wav_cfg_mut().set_child(XmlElement::new("NCHS").with_text(&channels.to_string()));
+  }
+
+  /// The encoding parameters from `<CODEC>`.
+  pub fn params(&self) -> Result<Parameters, X3Error> {
+    codec_params(self.wav_element("CODEC"))
+  }
+
+  pub fn set_params(&mut self, params: &Parameters) {
+    self.set_source_params(x3::Archive::WAV_SOURCE_ID, params);
+  }
+
+  /// The sample rate of `source_id`, from the `<FS>` of its `<CFG>`.
+  pub fn source_sample_rate(&self, source_id: u8) -> Option<u32> {
+    self.cfg(source_id)?.child("FS")?.text.trim().parse().ok()
+  }
+
+  pub fn set_source_sample_rate(&mut self, source_id: u8, sample_rate: u32) {
+    let fs = XmlElement::new("FS").with_attribute("UNIT", "Hz").with_text(&sample_rate.to_string());
+    let cfg = self.cfg_mut(source_id);
+    match cfg.child_mut("FS") {
+      Some(c) => *c = fs,
+      // The sample rate comes first
+      None => cfg.children.insert(0, fs),
+    }
+  }
+
+  /// The encoding parameters of `source_id`, from the `<CODEC>` of its `<CFG>`.
+  pub fn source_params(&self, source_id: u8) -> Result<Parameters, X3Error> {
+    codec_params(self.cfg(source_id).and_then(|cfg| cfg.child("CODEC")))
+  }
+
+  pub fn set_source_params(&mut self, source_id: u8, params: &Parameters) {
+    // The XML is generated by us, so it will parse
+    let codec = ArchiveMetadata::from_xml(&params.to_xml()).unwrap().elements.remove(0);
+    self.cfg_mut(source_id).set_child(codec);
+  }
+}
+
+impl Default for ArchiveMetadata {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+//
+// Whether `e` is the `<CFG>` with `source_id`.
+//
+fn is_cfg(e: &XmlElement, source_id: u8) -> bool {
+  e.name == "CFG" && e.attribute("ID").and_then(|id| id.trim().parse().ok()) == Some(source_id)
+}
+
+//
+// The parameters from a `<CODEC>` element.
+//
+fn codec_params(codec: Option<&XmlElement>) -> Result<Parameters, X3Error> {
+  let codec = codec.ok_or(X3Error::ArchiveHeaderXMLInvalid)?;
+
+  let mut xml = String::new();
+  codec.write(&mut xml);
+  Parameters::from_xml(&xml)
+}
+
+//
+// Create an element from the start tag, with its attributes.
+//
+fn read_element(e: &BytesStart) -> Result<XmlElement, X3Error> {
+  let mut element = XmlElement::new(&String::from_utf8_lossy(e.name()));
+  for attr in e.attributes() {
+    let attr = attr.map_err(|_| X3Error::ArchiveHeaderXMLInvalid)?;
+    let value = attr.unescaped_value().map_err(|_| X3Error::ArchiveHeaderXMLInvalid)?;
+    element
+      .attributes
+      .push((String::from_utf8_lossy(attr.key).into_owned(), String::from_utf8_lossy(&value).into_owned()));
+  }
+  Ok(element)
+}
+
+//
+//
+//            #######
+//               #       ######     ####     #####     ####
+//               #       #         #           #      #
+//               #       #####      ####       #       ####
+//               #       #              #      #           #
+//               #       #         #    #      #      #    #
+//               #       ######     ####       #       ####
+//
+//
+
+#[cfg(test)]
+mod tests {
+  use crate::metadata::{ArchiveMetadata, XmlElement};
+  use crate::x3::Parameters;
+
+  #[test]
+  fn test_new_metadata() {
+    let mut metadata = ArchiveMetadata::new();
+    metadata.set_sample_rate(96000);
+    assert_eq!(
+      "<X3ARCH PROG=\"x3-rust\" VERSION=\"2.0\" /><CFG ID=\"0\" FTYPE=\"XML\" /><CFG ID=\"1\" FTYPE=\"WAV\">\
+       <FS UNIT=\"Hz\">96000</FS><SUFFIX>wav</SUFFIX><CODEC TYPE=\"X3\" VERS=\"2\"><BLKLEN>20</BLKLEN>\
+       <CODES N=\"4\">RICE0,RICE1,RICE3,BFP</CODES><FILTER>DIFF</FILTER><NBITS>16</NBITS><T N=\"3\">3,8,20</T>\
+       </CODEC></CFG>",
+      metadata.to_xml()
+    );
+    assert_eq!(Some(96000), metadata.sample_rate());
+    assert_eq!(Parameters::DEFAULT_THRESHOLDS, metadata.params().unwrap().thresholds);
+  }
+
+  #[test]
+  fn test_metadata_round_trip() {
+    let xml = "<?xml version=\"1.0\" encoding=\"US-ASCII\" ?>\
+               <X3ARCH PROG=\"x3new.m\" VERSION=\"2.0\" />\
+               <CFG ID=\"0\" FTYPE=\"XML\" />\
+               <CFG ID=\"1\" FTYPE=\"WAV\">\
+               <FS UNIT=\"Hz\">48000</FS>\
+               <SUFFIX>wav</SUFFIX>\
+               <DEV ID=\"1234\" TYPE=\"D4\">Tag &amp; float</DEV>\
+               <DEPLOY><SITE LAT=\"-41.3\" LON=\"174.8\" /><GAIN UNIT=\"dB\">12.5</GAIN></DEPLOY>\
+               <CODEC TYPE=\"X3\" VERS=\"2\"><BLKLEN>40</BLKLEN><CODES N=\"4\">RICE0,RICE1,RICE3,BFP</CODES>\
+               <FILTER>DIFF</FILTER><NBITS>16</NBITS><T N=\"3\">3,8,20</T></CODEC>\
+               </CFG>";
+
+    let metadata = ArchiveMetadata::from_xml(xml).unwrap();
+    assert_eq!(3, metadata.elements.len());
+    assert_eq!(Some("x3new.m"), metadata.archive().unwrap().attribute("PROG"));
+    assert_eq!(Some(48000), metadata.sample_rate());
+    assert_eq!(40, metadata.params().unwrap().block_len);
+
+    let cfg = metadata.wav_cfg().unwrap();
+    assert_eq!("Tag & float", cfg.child("DEV").unwrap().text);
+    assert_eq!(Some("1234"), cfg.child("DEV").unwrap().attribute("ID"));
+    let deploy = cfg.child("DEPLOY").unwrap();
+    assert_eq!(Some("174.8"), deploy.child("SITE").unwrap().attribute("LON"));
+    assert_eq!("12.5", deploy.child("GAIN").unwrap().text);
+
+    // Writing and reading again gives the same tree, without the declaration
+    let xml = metadata.to_xml();
+    assert!(xml.contains("<DEV ID=\"1234\" TYPE=\"D4\">Tag &amp; float</DEV>"));
+    assert_eq!(metadata, ArchiveMetadata::from_xml(&xml).unwrap());
+  }
+
+  #[test]
+  fn test_metadata_set_values() {
+    let mut metadata = ArchiveMetadata::new();
+    metadata.set_sample_rate(8000);
+    metadata.set_sample_rate(16000);
+    metadata.set_params(&Parameters::builder().block_len(100).build().unwrap());
+    metadata.wav_cfg_mut().set_child(XmlElement::new("NOTE").with_text("<quoted> \"text\""));
+
+    let metadata = ArchiveMetadata::from_xml(&metadata.to_xml()).unwrap();
+    let cfg = metadata.wav_cfg().unwrap();
+    assert_eq!(1, cfg.children.iter().filter(|c| c.name == "FS").count());
+    assert_eq!(1, cfg.children.iter().filter(|c| c.name == "CODEC").count());
+    assert_eq!(Some(16000), metadata.sample_rate());
+    assert_eq!(100, metadata.params().unwrap().block_len);
+    assert_eq!("<quoted> \"text\"", cfg.child("NOTE").unwrap().text);
+  }
+
+  #[test]
+  fn test_metadata_sources() {
+    let mut metadata = ArchiveMetadata::new();
+    metadata.set_sample_rate(96000);
+    metadata.set_source_sample_rate(2, 240000);
+    metadata.set_source_params(2, &Parameters::builder().block_len(50).build().unwrap());
+    assert_eq!(vec![1, 2], metadata.source_ids());
+
+    let metadata = ArchiveMetadata::from_xml(&metadata.to_xml()).unwrap();
+    assert_eq!(Some("WAV"), metadata.cfg(2).unwrap().attribute("FTYPE"));
+    assert_eq!(Some(96000), metadata.sample_rate());
+    assert_eq!(Some(240000), metadata.source_sample_rate(2));
+    assert_eq!(20, metadata.params().unwrap().block_len);
+    assert_eq!(50, metadata.source_params(2).unwrap().block_len);
+    assert!(metadata.cfg(3).is_none());
+    assert!(metadata.source_params(3).is_err());
+  }
+
+  #[test]
+  fn test_metadata_invalid() {
+    assert!(ArchiveMetadata::from_xml("<CFG><FS>1</CFG>").is_err());
+    assert!(ArchiveMetadata::from_xml("<CFG>").is_err());
+    assert!(ArchiveMetadata::from_xml("<CFG ID=\"1\"></CFG>").unwrap().params().is_err());
+
+    // The whitespace between elements is removed, the text is kept as is
+    let metadata = ArchiveMetadata::from_xml("<CFG ID=\"1\">\n  <FS> 8000 </FS>\n  <NOTE>a\r\n</NOTE>\n</CFG>\n").unwrap();
+    assert_eq!("", metadata.wav_cfg().unwrap().text);
+    assert_eq!("a\r\n", metadata.wav_cfg().unwrap().child("NOTE").unwrap().text);
+    assert_eq!(Some(8000), metadata.sample_rate());
+
+    // The audio configuration is usually `<CFG ID="1">`, but any `<FS>` will do
+    let metadata = ArchiveMetadata::from_xml("<CFG ID=\"2\"><FS>500</FS></CFG>").unwrap();
+    assert_eq!(Some(500), metadata.sample_rate());
+  }
+}
@@ -0,0 +1,330 @@
+/**************************************************************************
+ *                                                                        *
+ * Rust implementation of the X3 lossless audio compression protocol.     *
+ *                                                                        *
+ * Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>             *
+ *                                                                        *
+ * This program is free software; you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the           *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.   *
+ *                                                                        *
+ **************************************************************************/
+
+//!
+//! Read and write a bare stream of X3 frames, without the `X3ARCHIV` id and the XML header
+//! of an archive.  The parameters, sample rate and channel count are not in the stream, so
+//! they are given to the reader and writer.  Use `encodefile::raw_to_x3a` to wrap a raw
+//! stream in an archive.
+//!
+
+// std
+use std::io::prelude::*;
+
+// this crate
+use crate::crc::crc16;
+use crate::decoder;
+use crate::encoder::FrameEncoderState;
+use crate::error::X3Error;
+use crate::scan;
+use crate::x3::{self, FrameHeader, Parameters, X3aSpec};
+
+//
+// Check the channel count of a raw stream.
+//
+fn check_channels(channels: u8) -> Result<(), X3Error> {
+  match channels {
+    0 => Err(X3Error::InvalidChannels),
+    _ => Ok(()),
+  }
+}
+
+///
+/// Read the frames of a raw X3 stream.
+///
+pub struct RawX3Reader<R: Read> {
+  reader: R,
+  spec: X3aSpec,
+
+  /// The header and payload of the last frame that was read.
+  read_buf: Vec<u8>,
+
+  /// The count of frames that were skipped because of a bad header or payload CRC.
+  frame_errors: usize,
+}
+
+impl<R: Read> RawX3Reader<R> {
+  ///
+  /// ### Arguments
+  ///
+  /// * `reader` - the raw stream, it is read a frame at a time so it should be buffered.
+  /// * `params` - the parameters the frames were encoded with.
+  /// * `sample_rate` - the number of samples per second.
+  /// * `channels` - the number of channels in each frame.
+  ///
+  pub fn new(reader: R, params: Parameters, sample_rate: u32, channels: u8) -> Result<Self, X3Error> {
+    check_channels(channels)?;
+    Ok(RawX3Reader {
+      reader,
+      spec: X3aSpec {
+        sample_rate,
+        params,
+        channels,
+      },
+      read_buf: Vec::with_capacity(x3::Frame::MAX_LENGTH),
+      frame_errors: 0,
+    })
+  }
+
+  pub fn spec(&self) -> &X3aSpec {
+    &self.spec
+  }
+
+  pub fn frame_errors(&self) -> usize {
+    self.frame_errors
+  }
+
+  ///
+  /// Read the next frame, the frames with a bad header or payload CRC are skipped.  After a
+  /// bad header the stream is searched for the next frame key.  The bytes of the frame are
+  /// available from `frame_bytes`.
+  ///
+  /// ### Returns
+  ///
+  /// * the frame header, or `None` when the end of the stream has been reached.
+  ///
+  pub fn next_frame(&mut self) -> Result<Option<FrameHeader>, X3Error> {
+    let parse = |bytes: &[u8; FrameHeader::LENGTH]| {
+      let header = decoder::parse_frame_header(bytes).ok()?;
+      let payload_len = header.payload_len;
+      Some((header, payload_len))
+    };
+    loop {
+      let block = match scan::next_block(&mut self.reader, parse) {
+        Ok(Some(block)) => block,
+        // A frame cut short by the end of the stream is dropped, the same as `X3aReader`
+        Ok(None) | Err(X3Error::FrameHeaderInvalidPayloadLen) => return Ok(None),
+        Err(err) => return Err(err),
+      };
+      if block.skipped > 0 {
+        // The frame with the bad header
+        self.frame_errors += 1;
+      }
+      if crc16(&block.payload) != block.header.payload_crc {
+        self.frame_errors += 1;
+        continue;
+      }
+
+      self.read_buf.clear();
+      self.read_buf.extend_from_slice(&block.header.to_bytes());
+      self.read_buf.extend_from_slice(&block.payload);
+      return Ok(Some(block.header));
+    }
+  }
+
+  ///
+  /// The header and payload of the frame returned by `next_frame`.
+  ///
+  pub fn frame_bytes(&self) -> &[u8] {
+    &self.read_buf
+  }
+
+  ///
+  /// Decode the next frame into `wav_buf`, the channels are interleaved.  The frames that
+  /// can't be decoded are counted and skipped.
+  ///
+  /// ### Returns
+  ///
+  /// * the number of values decoded, the samples times the channels, or `None` when the
+  ///   end of the stream has been reached.
+  ///
+  pub fn decode_next_frame(&mut self, wav_buf: &mut [i16]) -> Result<Option<usize>, X3Error> {
+    while let Some(header) = self.next_frame()? {
+      if header.channels != self.spec.channels && !(header.channels == 0 && self.spec.channels == 1) {
+        return Err(X3Error::InvalidChannels);
+      }
+      let payload = &self.read_buf[FrameHeader::LENGTH..];
+      let samples = header.samples as usize;
+      let channels = self.spec.channels as usize;
+      match decoder::decode_frame_channels(payload, wav_buf, &self.spec.params, samples, channels) {
+        Ok(Some(values)) => return Ok(Some(values)),
+        Ok(None) => return Ok(Some(0)),
+        Err(_) => self.frame_errors += 1,
+      }
+    }
+    Ok(None)
+  }
+}
+
+///
+/// Write a raw X3 stream.  The samples are buffered until a whole frame can be encoded, so
+/// `finish` must be called to write the last frame.
+///
+pub struct RawX3Writer<W: Write> {
+  writer: W,
+  spec: X3aSpec,
+  source_id: u8,
+
+  /// The time of the first sample in microseconds since the Unix epoch, 0 if unknown.
+  start_time: u64,
+
+  /// The interleaved samples that have not been encoded yet.
+  buf: Vec<i16>,
+
+  /// The samples of each channel that have been encoded.
+  samples_written: usize,
+  state: FrameEncoderState,
+}
+
+impl<W: Write> RawX3Writer<W> {
+  ///
+  /// ### Arguments
+  ///
+  /// * `writer` - where the frames are written, each frame is written with one call.
+  /// * `params` - the parameters to encode with.
+  /// * `sample_rate` - the number of samples per second.
+  /// * `channels` - the number of channels, the samples are interleaved.
+  ///
+  pub fn new(writer: W, params: Parameters, sample_rate: u32, channels: u8) -> Result<Self, X3Error> {
+    check_channels(channels)?;
+    Ok(RawX3Writer {
+      writer,
+      spec: X3aSpec {
+        sample_rate,
+        params,
+        channels,
+      },
+      source_id: x3::Archive::WAV_SOURCE_ID,
+      start_time: 0,
+      buf: Vec::new(),
+      samples_written: 0,
+      state: FrameEncoderState::new(channels),
+    })
+  }
+
+  /// The source id written to the frame headers, 1 by default.
+  pub fn with_source_id(mut self, source_id: u8) -> Self {
+    self.source_id = source_id;
+    self
+  }
+
+  ///
+  /// The time of the first sample in microseconds since the Unix epoch, the frame headers
+  /// have the time of their first sample.
+  ///
+  pub fn with_start_time(mut self, start_time: u64) -> Self {
+    self.start_time = start_time;
+    self
+  }
+
+  pub fn spec(&self) -> &X3aSpec {
+    &self.spec
+  }
+
+  ///
+  /// Add interleaved samples to the stream, each whole frame is encoded and written.
+  ///
+  pub fn write_samples(&mut self, samples: &[i16]) -> Result<(), X3Error> {
+    self.buf.extend_from_slice(samples);
+    let frame_len = self.spec.params.samples_per_frame() * self.spec.channels as usize;
+    while self.buf.len() >= frame_len {
+      self.write_frame(self.spec.params.samples_per_frame())?;
+    }
+    Ok(())
+  }
+
+  ///
+  /// Encode the samples that are left as a short frame, and flush the writer.
+  ///
+  /// ### Returns
+  ///
+  /// * the writer.
+  ///
+  pub fn finish(mut self) -> Result<W, X3Error> {
+    let channels = self.spec.channels as usize;
+    if !self.buf.len().is_multiple_of(channels) {
+      return Err(X3Error::InvalidChannels);
+    }
+    if !self.buf.is_empty() {
+      self.write_frame(self.buf.len() / channels)?;
+    }
+    self.writer.flush()?;
+    Ok(self.writer)
+  }
+
+  //
+  // Encode the first `samples` samples of each channel in the buffer, and write the frames.
+  //
+  fn write_frame(&mut self, samples: usize) -> Result<(), X3Error> {
+    let channels = self.spec.channels as usize;
+    let (start_time, sample_rate, samples_written) = (self.start_time, self.spec.sample_rate, self.samples_written);
+    let time = |p| x3::sample_time(start_time, sample_rate, samples_written + p);
+    let writer = &mut self.writer;
+    let wav = &self.buf[0..samples * channels];
+    self.state.encode_frames(wav, &self.spec.params, self.source_id, time, |frame| Ok(writer.write_all(frame)?))?;
+
+    self.samples_written += samples;
+    self.buf.drain(0..samples * channels);
+    Ok(())
+  }
+}
+
+//
+//
+//            #######
+//               #       ######     ####     #####     ####
+//               #       #         #           #      #
+//               #       #####      ####       #       ####
+//               #       #              #      #           #
+//               #       #         #    #      #      #    #
+//               #       ######     ####       #       ####
+//
+//
+
+#[cfg(test)]
+mod tests {
+  use crate::decoder::parse_frame_header;
+  use crate::raw::{RawX3Reader, RawX3Writer};
+  use crate::testutil::sine;
+  use crate::x3::{FrameHeader, Parameters};
+
+  #[test]
+  fn test_raw_stream() {
+    let params = Parameters::builder().block_len(16).blocks_per_frame(20).build().unwrap();
+    let spf = params.samples_per_frame();
+    let wav = sine(2 * 5_003, 0.01, 2000.0, 7);
+
+    // The samples are written in pieces that don't line up with the frames
+    let mut writer = RawX3Writer::new(Vec::new(), params.clone(), 8000, 2).unwrap().with_start_time(1_000_000);
+    for part in wav.chunks(2 * 999) {
+      writer.write_samples(part).unwrap();
+    }
+    let mut raw = writer.finish().unwrap();
+
+    // The first frame has a bad payload and the second a bad header, so they are skipped
+    let first_len = FrameHeader::LENGTH + parse_frame_header(&raw).unwrap().payload_len;
+    raw[first_len - 1] ^= 1;
+    raw[first_len + 5] ^= 1;
+
+    let mut reader = RawX3Reader::new(&raw[..], params, 8000, 2).unwrap();
+    let mut times = Vec::new();
+    let mut decoded = Vec::new();
+    let mut wav_buf = vec![0i16; 2 * spf];
+    while let Some(values) = reader.decode_next_frame(&mut wav_buf).unwrap() {
+      times.push(parse_frame_header(reader.frame_bytes()).unwrap().time);
+      decoded.extend_from_slice(&wav_buf[0..values]);
+    }
+    assert_eq!(2, reader.frame_errors());
+    assert_eq!(5_003usize.div_ceil(spf) - 2, times.len());
+    assert_eq!(1_000_000 + 2 * spf as u64 * 1_000_000 / 8000, times[0]);
+    assert!(wav[4 * spf..] == decoded[..]);
+  }
+}
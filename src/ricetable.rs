@@ -0,0 +1,272 @@
+/**************************************************************************
+ *                                                                        *
+ * Rust implementation of the X3 lossless audio compression protocol.     *
+ *                                                                        *
+ * Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>             *
+ *                                                                        *
+ * This program is free software; you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the           *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.   *
+ *                                                                        *
+ **************************************************************************/
+
+//!
+//! Table driven Rice code decoding.  The next `TABLE_BITS` bits of the stream are used
+//! to look up all the complete codewords they contain.  Each table entry holds the
+//! running sum of the decoded values, so a block is reconstructed by adding the last
+//! value to each sum, instead of decoding and adding one codeword at a time.
+//!
+
+// std
+use std::sync::OnceLock;
+
+// this crate
+use crate::bitreader::BitReader;
+use crate::decoder::{self, DecodeMode};
+use crate::error::X3Error;
+use crate::x3::RiceCode;
+
+/// The number of bits used to index the table.
+const TABLE_BITS: usize = 12;
+
+/// The number of bits peeked from the reader at a time.
+const WINDOW_BITS: usize = 32;
+
+/// The most codewords that will be decoded with one lookup.
+const MAX_CODEWORDS: usize = 8;
+
+/// The rice codes with more suffix bits than this are decoded without the table, RICE3
+/// through the table was 12 to 30% slower (see the `fast-rice` section of the README).
+const MAX_TABLE_NSUBS: usize = 1;
+
+/// One table entry per `TABLE_BITS` bit window.
+#[derive(Clone, Copy, Default)]
+#[repr(C, align(32))]
+struct Entry {
+  /// The running sum of the decoded values after each codeword.
+  sums: [i16; MAX_CODEWORDS],
+
+  /// The number of bits consumed after each codeword.
+  bits: [u8; MAX_CODEWORDS],
+
+  /// The number of complete codewords found in the window.
+  count: u8,
+}
+
+struct RiceTable {
+  entries: Vec<Entry>,
+}
+
+impl RiceTable {
+  fn new(code: &RiceCode) -> Self {
+    let entries = (0..1usize << TABLE_BITS).map(|window| Self::entry(code, window)).collect();
+    RiceTable { entries }
+  }
+
+  //
+  // Decode as many complete codewords as can be found in `window`.
+  //
+  fn entry(code: &RiceCode, window: usize) -> Entry {
+    let mut entry = Entry::default();
+    let mut pos = 0;
+    let mut sum = 0;
+
+    while (entry.count as usize) < MAX_CODEWORDS {
+      // Count the zeros, the codeword must be terminated by a one within the window
+      let rem = TABLE_BITS - pos;
+      let bits = window & ((1 << rem) - 1);
+      if bits == 0 {
+        break;
+      }
+      let n = rem - (usize::BITS - bits.leading_zeros()) as usize;
+      let len = n + 1 + code.nsubs;
+      if len > rem {
+        break;
+      }
+
+      let suffix = (bits >> (rem - len)) & ((1 << code.nsubs) - 1);
+      let i = (n << code.nsubs) | suffix;
+      if i >= code.inv_len {
+        // Leave invalid codewords to the slow path, it will report the error
+        break;
+      }
+
+      pos += len;
+      sum += code.inv[i];
+      entry.bits[entry.count as usize] = pos as u8;
+      entry.sums[entry.count as usize] = sum;
+      entry.count += 1;
+    }
+
+    entry
+  }
+
+  //
+  // Get the table for `code`, it is created the first time it is needed.
+  //
+  fn get(code: &RiceCode) -> &'static RiceTable {
+    static TABLES: [OnceLock<RiceTable>; MAX_TABLE_NSUBS + 1] = [OnceLock::new(), OnceLock::new()];
+
+    // The number of suffix bits identifies each of the rice codes
+    TABLES[code.nsubs].get_or_init(|| RiceTable::new(code))
+  }
+}
+
+///
+/// Decode a block of Rice codes using the lookup table.  This will produce the same
+/// results as `decoder::decode_ricecode_block_r1` and `decoder::decode_ricecode_block_r2r3`.
+///
+/// ### Arguments
+///
+/// * `br` - the data to decode as a BitReader.
+/// * `wav` - where the wav data will be written to.
+/// * `last_wav` - the last value of the previous block.
+/// * `code` - the rice code used for the block.
+/// * `mode` - how overflowing samples are handled.
+///
+#[inline(always)]
+pub fn decode_ricecode_block(
+  br: &mut BitReader,
+  wav: &mut [i16],
+  last_wav: &mut i16,
+  code: &RiceCode,
+  mode: DecodeMode,
+) -> Result<(), X3Error> {
+  if code.nsubs > MAX_TABLE_NSUBS {
+    // Long codewords, there are too few in a window for the table to be faster
+    return decoder::decode_ricecode_block_r2r3(br, wav, last_wav, code, mode);
+  }
+
+  let table = RiceTable::get(code);
+  let mut lw = *last_wav;
+  let mut b = 0;
+
+  while b < wav.len() {
+    // Decode from a 32 bit window, only updating the reader once the window is used up
+    let mut window = br.peek_nbits(WINDOW_BITS);
+    let mut used = 0;
+
+    while used <= WINDOW_BITS - TABLE_BITS && b < wav.len() {
+      let entry = &table.entries[(window >> (WINDOW_BITS - TABLE_BITS)) as usize];
+      let n = core::cmp::min(entry.count as usize, wav.len() - b);
+      if n == 0 {
+        break;
+      }
+
+      // Independent adds, these can be vectorised.  When there is room all the lanes are
+      // written, the values past `n` are overwritten by the next lookup.  In strict mode
+      // each sample is checked instead.
+      if mode == DecodeMode::Wrapping && b + MAX_CODEWORDS <= wav.len() {
+        for (wav_value, sum) in wav[b..(b + MAX_CODEWORDS)].iter_mut().zip(entry.sums.iter()) {
+          *wav_value = lw.wrapping_add(*sum);
+        }
+      } else {
+        for (wav_value, sum) in wav[b..(b + n)].iter_mut().zip(entry.sums.iter()) {
+          *wav_value = mode.add(lw, *sum)?;
+        }
+      }
+      lw = lw.wrapping_add(entry.sums[n - 1]);
+      b += n;
+
+      let bits = entry.bits[n - 1] as usize;
+      window <<= bits;
+      used += bits;
+    }
+
+    if used > 0 {
+      // `inc_bits` can only skip less than a whole word
+      if used == WINDOW_BITS {
+        br.inc_bits(WINDOW_BITS / 2)?;
+        used -= WINDOW_BITS / 2;
+      }
+      br.inc_bits(used)?;
+    } else if b < wav.len() {
+      // The codeword is too long for the table, decode it the slow way
+      let wav = &mut wav[b..=b];
+      if code.nsubs == 0 {
+        decoder::decode_ricecode_block_r1(br, wav, &mut lw, code, mode)?;
+      } else {
+        decoder::decode_ricecode_block_r2r3(br, wav, &mut lw, code, mode)?;
+      }
+      b += 1;
+    }
+  }
+
+  *last_wav = lw;
+  Ok(())
+}
+
+//
+//
+//            #######
+//               #       ######     ####     #####     ####
+//               #       #         #           #      #
+//               #       #####      ####       #       ####
+//               #       #              #      #           #
+//               #       #         #    #      #      #    #
+//               #       ######     ####       #       ####
+//
+//
+
+#[cfg(test)]
+mod tests {
+  use crate::bitpacker::BitPacker;
+  use crate::bitreader::BitReader;
+  use crate::decoder::{decode_ricecode_block_r1, decode_ricecode_block_r2r3, DecodeMode};
+  use crate::ricetable::decode_ricecode_block;
+  use crate::x3::RiceCodes;
+
+  #[test]
+  fn test_decode_ricecode_block_matches_scalar() {
+    for (c, code) in RiceCodes::get([0, 1, 2]).iter().chain(RiceCodes::get([3, 3, 3]).iter()).enumerate() {
+      // Pack every valid codeword, in a scrambled order, using the encoder tables
+      let num_values = 2 * code.code.len() + 7;
+      let mut x3_out = vec![0u8; num_values * 4];
+      let x3_len = {
+        let bp = &mut BitPacker::new(&mut x3_out);
+        for k in 0..num_values {
+          let ii = (k * 7 + c) % code.code.len();
+          bp.write_bits(code.code[ii], code.num_bits[ii]).unwrap();
+        }
+        bp.word_align().unwrap();
+        bp.as_bytes().len()
+      };
+      let x3_bytes = &x3_out[0..x3_len];
+
+      let mut expected = vec![0i16; num_values];
+      let mut expected_last_wav = -1234;
+      {
+        let br = &mut BitReader::new(x3_bytes);
+        if code.nsubs == 0 {
+          decode_ricecode_block_r1(br, &mut expected, &mut expected_last_wav, code, DecodeMode::Strict).unwrap();
+        } else {
+          decode_ricecode_block_r2r3(br, &mut expected, &mut expected_last_wav, code, DecodeMode::Strict).unwrap();
+        }
+      }
+
+      let mut wav = vec![0i16; num_values];
+      let mut last_wav = -1234;
+      let br = &mut BitReader::new(x3_bytes);
+      decode_ricecode_block(br, &mut wav, &mut last_wav, code, DecodeMode::Wrapping).unwrap();
+
+      assert_eq!(expected, wav);
+      assert_eq!(expected_last_wav, last_wav);
+
+      let mut last_wav = -1234;
+      let br = &mut BitReader::new(x3_bytes);
+      decode_ricecode_block(br, &mut wav, &mut last_wav, code, DecodeMode::Strict).unwrap();
+
+      assert_eq!(expected, wav);
+      assert_eq!(expected_last_wav, last_wav);
+    }
+  }
+}
@@ -0,0 +1,108 @@
+/**************************************************************************
+ *                                                                        *
+ * Rust implementation of the X3 lossless audio compression protocol.     *
+ *                                                                        *
+ * Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>             *
+ *                                                                        *
+ * This program is free software; you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the           *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.   *
+ *                                                                        *
+ **************************************************************************/
+
+//
+// Find the frames of a stream by their header, for the files that hold frames between
+// other data and for the streams that may be corrupt.  Used by `dtag`, `sud` and `raw`.
+//
+
+// std
+use std::io::{prelude::*, ErrorKind};
+use std::path;
+
+// this crate
+use crate::error::X3Error;
+
+// A header that was found, with the payload that follows it.
+pub(crate) struct Block<H> {
+  pub header: H,
+  pub payload: Vec<u8>,
+
+  // The number of bytes that were skipped to find the header
+  pub skipped: usize,
+}
+
+//
+// Read the next `N` byte header that `parse` accepts, and the payload that follows it.
+// `parse` returns the header and the length of its payload.  The bytes that are not part
+// of a header are skipped, a byte at a time.
+//
+// Returns `None` when the end of the input is reached before a header, an input that ends
+// part way through a payload is `X3Error::FrameHeaderInvalidPayloadLen`.
+//
+pub(crate) fn next_block<R: Read, H, const N: usize>(
+  reader: &mut R,
+  parse: impl Fn(&[u8; N]) -> Option<(H, usize)>,
+) -> Result<Option<Block<H>>, X3Error> {
+  let mut header_buf = [0u8; N];
+  let mut filled = 0;
+  let mut skipped = 0;
+  loop {
+    while filled < N {
+      match reader.read(&mut header_buf[filled..])? {
+        0 => return Ok(None),
+        n => filled += n,
+      }
+    }
+
+    let (header, payload_len) = match parse(&header_buf) {
+      Some(parsed) => parsed,
+      None => {
+        // Not a header, move on a byte
+        header_buf.copy_within(1.., 0);
+        filled -= 1;
+        skipped += 1;
+        continue;
+      }
+    };
+
+    let mut payload = vec![0u8; payload_len];
+    match reader.read_exact(&mut payload) {
+      Ok(()) => (),
+      Err(e) if e.kind() == ErrorKind::UnexpectedEof => return Err(X3Error::FrameHeaderInvalidPayloadLen),
+      Err(e) => return Err(e.into()),
+    }
+    return Ok(Some(Block {
+      header,
+      payload,
+      skipped,
+    }));
+  }
+}
+
+//
+// The file for the output of the source `id`, called `<stem>.<suffix>` or
+// `<stem>.<id>.<suffix>` if that name has been taken by another source.
+//
+pub(crate) fn output_filename(
+  out_dir: &path::Path,
+  stem: &str,
+  id: impl std::fmt::Display,
+  suffix: &str,
+  filenames: &[path::PathBuf],
+) -> path::PathBuf {
+  let filename = out_dir.join(format!("{}.{}", stem, suffix));
+  if filenames.contains(&filename) {
+    out_dir.join(format!("{}.{}.{}", stem, id, suffix))
+  } else {
+    filename
+  }
+}
@@ -0,0 +1,188 @@
+/**************************************************************************
+ *                                                                        *
+ * Rust implementation of the X3 lossless audio compression protocol.     *
+ *                                                                        *
+ * Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>             *
+ *                                                                        *
+ * This program is free software; you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the           *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.   *
+ *                                                                        *
+ **************************************************************************/
+
+//!
+//! Low rate sensor streams, such as depth, acceleration or temperature, that are kept in
+//! an archive next to the audio.  These are low entropy integer series too, so they are
+//! encoded with X3 as sources of their own.  Each sensor is described by its `<CFG>`:
+//!
+//! ```text
+//! <CFG ID="2" FTYPE="SENS">
+//!   <FS UNIT="Hz">10</FS>
+//!   <SENSOR NAME="depth" UNIT="cm">Pressure sensor</SENSOR>
+//!   <CODEC TYPE="X3" VERS="2">...</CODEC>
+//! </CFG>
+//! ```
+//!
+//! A sensor with several axes, like an accelerometer, is one source for each axis.
+//!
+//! The samples are 16 bit, the same as the audio.  A reading with a wider range is scaled
+//! to fit before it is encoded, by choosing the unit, e.g. depth in cm rather than mm.
+//!
+
+// this crate
+use crate::metadata::{ArchiveMetadata, XmlElement};
+use crate::x3::{self, Parameters};
+
+///
+/// The description of a sensor stream.  The samples are 16 bit integers in `unit`, so only
+/// the readings from -32768 to 32767 `unit` can be kept.  Choose a unit that fits the
+/// range of the sensor, the wider readings must be scaled or clipped by the caller.
+///
+#[derive(Debug, Clone)]
+pub struct Sensor {
+  /// The source id of the frames, and the `ID` of the `<CFG>`.
+  pub source_id: u8,
+  pub name: String,
+  pub unit: String,
+  pub description: String,
+
+  /// The number of samples per second.
+  pub sample_rate: u32,
+  pub params: Parameters,
+}
+
+impl Sensor {
+  /// The `FTYPE` of the `<CFG>` of a sensor.
+  pub const FTYPE: &'static str = "SENS";
+
+  pub fn new(source_id: u8, name: &str, unit: &str, sample_rate: u32) -> Self {
+    Sensor {
+      source_id,
+      name: name.to_string(),
+      unit: unit.to_string(),
+      description: String::new(),
+      sample_rate,
+      params: Parameters::default(),
+    }
+  }
+
+  pub fn with_description(mut self, description: &str) -> Self {
+    self.description = description.to_string();
+    self
+  }
+
+  pub fn with_params(mut self, params: Parameters) -> Self {
+    self.params = params;
+    self
+  }
+
+  ///
+  /// The channel to encode `samples` of this sensor, see `encodefile::write_x3a`.
+  ///
+  pub fn channel<'a>(&self, samples: &'a [i16]) -> x3::Channel<'a> {
+    x3::Channel::new(0, samples, self.sample_rate, self.params.clone()).with_source_id(self.source_id)
+  }
+
+  ///
+  /// Add the `<CFG>` of the sensor to `metadata`, replacing the one with the same source
+  /// id.
+  ///
+  pub fn set_metadata(&self, metadata: &mut ArchiveMetadata) {
+    let sensor = XmlElement::new("SENSOR")
+      .with_attribute("NAME", &self.name)
+      .with_attribute("UNIT", &self.unit)
+      .with_text(&self.description);
+    let cfg = XmlElement::new("CFG")
+      .with_attribute("ID", &self.source_id.to_string())
+      .with_attribute("FTYPE", Self::FTYPE)
+      .with_child(sensor);
+
+    metadata.set_cfg(self.source_id, cfg);
+    metadata.set_source_sample_rate(self.source_id, self.sample_rate);
+    metadata.set_source_params(self.source_id, &self.params);
+  }
+
+  ///
+  /// The sensor with `source_id` in `metadata`, or `None` if that source isn't a sensor.
+  ///
+  pub fn from_metadata(metadata: &ArchiveMetadata, source_id: u8) -> Option<Self> {
+    let cfg = metadata.cfg(source_id)?;
+    if cfg.attribute("FTYPE") != Some(Self::FTYPE) {
+      return None;
+    }
+    let sensor = cfg.child("SENSOR")?;
+
+    Some(Sensor {
+      source_id,
+      name: sensor.attribute("NAME").unwrap_or_default().to_string(),
+      unit: sensor.attribute("UNIT").unwrap_or_default().to_string(),
+      description: sensor.text.clone(),
+      sample_rate: metadata.source_sample_rate(source_id)?,
+      params: metadata.source_params(source_id).ok()?,
+    })
+  }
+
+  ///
+  /// All the sensors in `metadata`, in the order they appear.
+  ///
+  pub fn all_from_metadata(metadata: &ArchiveMetadata) -> Vec<Self> {
+    metadata.source_ids().into_iter().filter_map(|id| Sensor::from_metadata(metadata, id)).collect()
+  }
+}
+
+//
+//
+//            #######
+//               #       ######     ####     #####     ####
+//               #       #         #           #      #
+//               #       #####      ####       #       ####
+//               #       #              #      #           #
+//               #       #         #    #      #      #    #
+//               #       ######     ####       #       ####
+//
+//
+
+#[cfg(test)]
+mod tests {
+  use crate::metadata::ArchiveMetadata;
+  use crate::sensor::Sensor;
+  use crate::x3::Parameters;
+
+  #[test]
+  fn test_sensor_metadata() {
+    let depth = Sensor::new(2, "depth", "cm", 10).with_description("Pressure & depth");
+    let params = Parameters::builder().block_len(8).build().unwrap();
+    let temp = Sensor::new(3, "temperature", "0.01 degC", 1).with_params(params);
+
+    let mut metadata = ArchiveMetadata::new();
+    metadata.set_sample_rate(96000);
+    depth.set_metadata(&mut metadata);
+    temp.set_metadata(&mut metadata);
+    Sensor::new(2, "depth", "m", 5).set_metadata(&mut metadata);
+    depth.set_metadata(&mut metadata);
+
+    let metadata = ArchiveMetadata::from_xml(&metadata.to_xml()).unwrap();
+    assert_eq!(vec![1, 2, 3], metadata.source_ids());
+    assert!(Sensor::from_metadata(&metadata, 1).is_none());
+
+    let sensors = Sensor::all_from_metadata(&metadata);
+    assert_eq!(2, sensors.len());
+    assert_eq!("depth", sensors[0].name);
+    assert_eq!("cm", sensors[0].unit);
+    assert_eq!("Pressure & depth", sensors[0].description);
+    assert_eq!(10, sensors[0].sample_rate);
+    assert_eq!("temperature", sensors[1].name);
+    assert_eq!("0.01 degC", sensors[1].unit);
+    assert_eq!(1, sensors[1].sample_rate);
+    assert_eq!(8, sensors[1].params.block_len);
+  }
+}
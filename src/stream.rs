@@ -0,0 +1,303 @@
+/**************************************************************************
+ *                                                                        *
+ * Rust implementation of the X3 lossless audio compression protocol.     *
+ *                                                                        *
+ * Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>             *
+ *                                                                        *
+ * This program is free software; you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the           *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.   *
+ *                                                                        *
+ **************************************************************************/
+
+//!
+//! Encode a live stream, such as the audio from a sound card or a TCP connection, as the
+//! samples arrive.  The samples are pushed to a `StreamEncoder`, and each frame is passed on
+//! as soon as it is finished.  A frame is finished when it is full, when it is flushed, or
+//! when its first sample has waited for longer than the maximum latency.
+//!
+
+// std
+use std::io::Write;
+use std::time::{Duration, Instant};
+
+// this crate
+use crate::encoder::FrameEncoderState;
+use crate::error::X3Error;
+use crate::x3::{self, Parameters};
+
+// Where each finished frame goes.
+type FrameSink<'a> = Box<dyn FnMut(&[u8]) -> Result<(), X3Error> + 'a>;
+
+///
+/// Encode samples into X3 frames as they arrive.  The frames are raw frames, see
+/// `raw::RawX3Reader` and `encodefile::raw_to_x3a`.
+///
+pub struct StreamEncoder<'a> {
+  params: Parameters,
+  sample_rate: u32,
+  state: FrameEncoderState,
+  source_id: u8,
+
+  /// The time of the first pushed sample, see `with_start_time`.
+  start_time: u64,
+
+  /// The interleaved samples that have not been encoded yet.
+  buf: Vec<i16>,
+
+  /// The samples of each channel that have been encoded.
+  samples_encoded: usize,
+
+  /// How long a sample can wait before its frame is closed, and when the first sample in
+  /// the buffer arrived.
+  max_latency: Option<Duration>,
+  first_arrival: Option<Instant>,
+
+  on_frame: FrameSink<'a>,
+}
+
+impl<'a> StreamEncoder<'a> {
+  ///
+  /// ### Arguments
+  ///
+  /// * `params` - the parameters to encode with.
+  /// * `sample_rate` - the number of samples per second.
+  /// * `channels` - the number of channels, the samples are interleaved.
+  /// * `on_frame` - called with the bytes of each finished frame.
+  ///
+  pub fn new<F>(params: Parameters, sample_rate: u32, channels: u8, on_frame: F) -> Result<Self, X3Error>
+  where
+    F: FnMut(&[u8]) -> Result<(), X3Error> + 'a,
+  {
+    if channels == 0 {
+      return Err(X3Error::InvalidChannels);
+    }
+    Ok(StreamEncoder {
+      params,
+      sample_rate,
+      state: FrameEncoderState::new(channels),
+      source_id: x3::Archive::WAV_SOURCE_ID,
+      start_time: 0,
+      buf: Vec::new(),
+      samples_encoded: 0,
+      max_latency: None,
+      first_arrival: None,
+      on_frame: Box::new(on_frame),
+    })
+  }
+
+  ///
+  /// A `StreamEncoder` that writes each finished frame to `writer`, see `new`.
+  ///
+  pub fn from_writer<W: Write + 'a>(
+    mut writer: W,
+    params: Parameters,
+    sample_rate: u32,
+    channels: u8,
+  ) -> Result<Self, X3Error> {
+    Self::new(params, sample_rate, channels, move |frame| {
+      writer.write_all(frame)?;
+      Ok(writer.flush()?)
+    })
+  }
+
+  /// The source id written to the frame headers, 1 by default.
+  pub fn with_source_id(mut self, source_id: u8) -> Self {
+    self.source_id = source_id;
+    self
+  }
+
+  ///
+  /// The time of the first sample that is pushed, as for `raw::RawX3Writer::with_start_time`.
+  ///
+  pub fn with_start_time(mut self, start_time: u64) -> Self {
+    self.start_time = start_time;
+    self
+  }
+
+  ///
+  /// Close a frame early once its first sample has waited for `max_latency`.  This is
+  /// checked when samples are pushed, call `poll` from a timer to check it while no
+  /// samples arrive.
+  ///
+  pub fn with_max_latency(mut self, max_latency: Duration) -> Self {
+    self.max_latency = Some(max_latency);
+    self
+  }
+
+  /// The samples of each channel that are waiting to be encoded.
+  pub fn pending_samples(&self) -> usize {
+    self.buf.len() / self.state.channels() as usize
+  }
+
+  ///
+  /// Add interleaved samples, each full frame is encoded and passed on.
+  ///
+  pub fn push(&mut self, samples: &[i16]) -> Result<(), X3Error> {
+    if self.buf.is_empty() && !samples.is_empty() {
+      self.first_arrival = Some(Instant::now());
+    }
+    self.buf.extend_from_slice(samples);
+
+    let samples_per_frame = self.params.samples_per_frame();
+    while self.pending_samples() >= samples_per_frame {
+      self.encode(samples_per_frame)?;
+    }
+    if self.buf.is_empty() {
+      self.first_arrival = None;
+    }
+    self.poll()
+  }
+
+  ///
+  /// Close the frame if its first sample has waited for the maximum latency.
+  ///
+  pub fn poll(&mut self) -> Result<(), X3Error> {
+    match (self.max_latency, self.first_arrival) {
+      (Some(max_latency), Some(first_arrival)) if first_arrival.elapsed() >= max_latency => self.flush(),
+      _ => Ok(()),
+    }
+  }
+
+  ///
+  /// Encode the samples that are waiting as a short frame.  A sample that is missing some
+  /// of its channels is kept for the next frame.
+  ///
+  pub fn flush(&mut self) -> Result<(), X3Error> {
+    let pending = self.pending_samples();
+    if pending > 0 {
+      self.encode(pending)?;
+    }
+    self.first_arrival = match self.buf.is_empty() {
+      true => None,
+      false => Some(Instant::now()),
+    };
+    Ok(())
+  }
+
+  //
+  // Encode the first `samples` samples of each channel in the buffer, and pass on the frames.
+  //
+  fn encode(&mut self, samples: usize) -> Result<(), X3Error> {
+    let channels = self.state.channels() as usize;
+    let (start_time, sample_rate, samples_encoded) = (self.start_time, self.sample_rate, self.samples_encoded);
+    let time = |p| x3::sample_time(start_time, sample_rate, samples_encoded + p);
+    let wav = &self.buf[0..(samples * channels)];
+    self.state.encode_frames(wav, &self.params, self.source_id, time, &mut self.on_frame)?;
+
+    self.samples_encoded += samples;
+    self.buf.drain(0..(samples * channels));
+    Ok(())
+  }
+}
+
+//
+//
+//            #######
+//               #       ######     ####     #####     ####
+//               #       #         #           #      #
+//               #       #####      ####       #       ####
+//               #       #              #      #           #
+//               #       #         #    #      #      #    #
+//               #       ######     ####       #       ####
+//
+//
+
+#[cfg(test)]
+mod tests {
+  use std::time::Duration;
+
+  use crate::decoder::decode_frame_bytes;
+  use crate::stream::StreamEncoder;
+  use crate::testutil::{noise, sine};
+  use crate::x3::{self, FrameHeader, Parameters};
+
+  #[test]
+  fn test_stream_encoder() {
+    let params = Parameters::default();
+    let wav = sine(25_000, 0.07, 5000.0, 13);
+
+    // The frames are passed on as they fill, and the rest when flushed
+    let mut frames = Vec::new();
+    let mut stream = StreamEncoder::new(params.clone(), 10_000, 1, |frame| {
+      frames.push(frame.to_vec());
+      Ok(())
+    })
+    .unwrap()
+    .with_start_time(2_000_000);
+    for part in wav.chunks(3001) {
+      stream.push(part).unwrap();
+    }
+    assert_eq!(5000, stream.pending_samples());
+    stream.flush().unwrap();
+    drop(stream);
+
+    let headers: Vec<FrameHeader> = frames.iter().map(|f| FrameHeader::parse(f).unwrap()).collect();
+    let lens: Vec<u16> = headers.iter().map(|h| h.samples).collect();
+    assert_eq!(vec![10_000, 10_000, 5000], lens);
+    assert_eq!(vec![2_000_000, 3_000_000, 4_000_000], headers.iter().map(|h| h.time).collect::<Vec<u64>>());
+    let decoded: Vec<i16> = frames.iter().flat_map(|f| decode_frame_bytes(f, &params).unwrap()).collect();
+    assert!(wav == decoded);
+
+    // With no latency allowed each push is a frame, the same as writing to a writer
+    let mut out = Vec::new();
+    let mut stream = StreamEncoder::from_writer(&mut out, params.clone(), 10_000, 2)
+      .unwrap()
+      .with_max_latency(Duration::from_secs(0));
+    for part in wav.chunks(2 * 150) {
+      stream.push(part).unwrap();
+    }
+    stream.flush().unwrap();
+    drop(stream);
+
+    let mut p = 0;
+    let mut lens = Vec::new();
+    let mut decoded = Vec::new();
+    while p < out.len() {
+      let header = FrameHeader::parse(&out[p..]).unwrap();
+      assert_eq!(2, header.channels);
+      lens.push(header.samples);
+      let frame_len = FrameHeader::LENGTH + header.payload_len;
+      decoded.extend(decode_frame_bytes(&out[p..(p + frame_len)], &params).unwrap());
+      p += frame_len;
+    }
+    assert_eq!([vec![150; 83], vec![50]].concat(), lens);
+    assert!(wav == decoded);
+  }
+
+  #[test]
+  fn test_stream_encoder_long_frames() {
+    // Two channels of noise don't fit in a frame, so each frame is closed early
+    let params = Parameters::default();
+    let wav = noise(2 * 3 * params.samples_per_frame(), 12345);
+
+    let mut frames = Vec::new();
+    let mut stream = StreamEncoder::new(params.clone(), 10_000, 2, |frame| {
+      frames.push(frame.to_vec());
+      Ok(())
+    })
+    .unwrap();
+    stream.push(&wav).unwrap();
+    assert_eq!(0, stream.pending_samples());
+    drop(stream);
+
+    // The frames are as full as they can be, they don't shrink after the first split
+    let lens: Vec<usize> = frames.iter().map(|f| FrameHeader::parse(f).unwrap().samples as usize).collect();
+    assert_eq!(3 * params.samples_per_frame(), lens.iter().sum::<usize>());
+    assert_eq!(6, lens.len());
+    assert!(lens[0] > params.samples_per_frame() / 2);
+    assert!(lens.chunks(2).all(|pair| pair == &lens[0..2]));
+    assert!(frames.iter().all(|f| f.len() <= FrameHeader::LENGTH + x3::Frame::MAX_LENGTH));
+    let decoded: Vec<i16> = frames.iter().flat_map(|f| decode_frame_bytes(f, &params).unwrap()).collect();
+    assert!(wav == decoded);
+  }
+}
@@ -0,0 +1,653 @@
+/**************************************************************************
+ *                                                                        *
+ * Rust implementation of the X3 lossless audio compression protocol.     *
+ *                                                                        *
+ * Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>             *
+ *                                                                        *
+ * This program is free software; you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the           *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.   *
+ *                                                                        *
+ **************************************************************************/
+
+//!
+//! A reader for the `.sud` files written by Ocean Instruments SoundTrap recorders.  A
+//! `.sud` file is a file header followed by chunks, each with a 20 byte header:
+//!
+//! ```text
+//! <Magic 0xA952: u16> <Id: u16> <Data length: u16> <Samples: u16>
+//! <Time (s): u32> <Time offset (us): u32> <Data CRC: u16> <Header CRC: u16>
+//! ```
+//!
+//! The chunks with id 0 hold XML `<CFG>` elements that describe the chunks with each of the
+//! other ids.  A `<CFG>` with an X3 `<CODEC>` holds audio compressed with the same codec
+//! as the `.x3a` frames, the data of each chunk is a frame payload.  A `<CFG>` with a
+//! `<SRC ID="..">` takes its data from the chunks of that source, like the wav file that
+//! holds the decoded audio.  The other chunks, like the XML log, are written out as is.
+//!
+//! The file header is skipped, the chunks are found by their magic number and header CRC.
+//! The byte order is found from the magic number, the X3 data of a little endian file is
+//! read as little endian 16 bit words.
+//!
+
+// std
+use std::fs::File;
+use std::io::{prelude::*, BufReader, BufWriter};
+use std::path;
+
+// externs
+use crate::byteorder::{BigEndian, ByteOrder, LittleEndian};
+use crate::hound;
+
+// this crate
+use crate::crc::crc16;
+use crate::decoder;
+use crate::error::X3Error;
+use crate::metadata::{ArchiveMetadata, XmlElement};
+use crate::scan;
+use crate::x3::Parameters;
+
+/// The byte order of the chunk headers in a `.sud` file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SudByteOrder {
+  BigEndian,
+  LittleEndian,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SudChunkHeader {
+  /// The id of the `<CFG>` that describes the chunk, 0 for the XML configuration.
+  pub id: u16,
+  pub data_len: usize,
+
+  /// The number of samples in the chunk, for audio chunks.
+  pub samples: u16,
+
+  /// The time of the chunk, seconds since the Unix epoch.
+  pub time_s: u32,
+
+  /// The microseconds after `time_s`.
+  pub time_offset_us: u32,
+  pub data_crc: u16,
+}
+
+impl SudChunkHeader {
+  pub const LENGTH: usize = 20;
+  pub const MAGIC: u16 = 0xa952;
+
+  /// The id of the chunks that hold the XML configuration.
+  pub const CONFIG_ID: u16 = 0;
+
+  /// The position of each value in the header.
+  const P_ID: usize = 2;
+  const P_DATA_LEN: usize = 4;
+  const P_SAMPLES: usize = 6;
+  const P_TIME_S: usize = 8;
+  const P_TIME_OFFSET_US: usize = 12;
+  const P_DATA_CRC: usize = 16;
+  const P_HEADER_CRC: usize = 18;
+
+  ///
+  /// Parse a chunk header, with the byte order of the file.  `None` is returned when the
+  /// magic number or the header CRC is wrong.
+  ///
+  pub fn parse(bytes: &[u8]) -> Option<(Self, SudByteOrder)> {
+    if bytes.len() < Self::LENGTH {
+      return None;
+    }
+    let order = match BigEndian::read_u16(bytes) {
+      Self::MAGIC => SudByteOrder::BigEndian,
+      magic if magic.swap_bytes() == Self::MAGIC => SudByteOrder::LittleEndian,
+      _ => return None,
+    };
+    let read_u16 = |p: usize| match order {
+      SudByteOrder::BigEndian => BigEndian::read_u16(&bytes[p..]),
+      SudByteOrder::LittleEndian => LittleEndian::read_u16(&bytes[p..]),
+    };
+    let read_u32 = |p: usize| match order {
+      SudByteOrder::BigEndian => BigEndian::read_u32(&bytes[p..]),
+      SudByteOrder::LittleEndian => LittleEndian::read_u32(&bytes[p..]),
+    };
+    if read_u16(Self::P_HEADER_CRC) != crc16(&bytes[0..Self::P_HEADER_CRC]) {
+      return None;
+    }
+
+    let header = SudChunkHeader {
+      id: read_u16(Self::P_ID),
+      data_len: read_u16(Self::P_DATA_LEN) as usize,
+      samples: read_u16(Self::P_SAMPLES),
+      time_s: read_u32(Self::P_TIME_S),
+      time_offset_us: read_u32(Self::P_TIME_OFFSET_US),
+      data_crc: read_u16(Self::P_DATA_CRC),
+    };
+    Some((header, order))
+  }
+
+  ///
+  /// Write the chunk header in `order`, with its header CRC.
+  ///
+  pub fn to_bytes(&self, order: SudByteOrder) -> [u8; Self::LENGTH] {
+    let mut bytes = [0u8; Self::LENGTH];
+    let values = [
+      (0, Self::MAGIC),
+      (Self::P_ID, self.id),
+      (Self::P_DATA_LEN, self.data_len as u16),
+      (Self::P_SAMPLES, self.samples),
+      (Self::P_DATA_CRC, self.data_crc),
+    ];
+    for (p, value) in values.iter() {
+      match order {
+        SudByteOrder::BigEndian => BigEndian::write_u16(&mut bytes[*p..], *value),
+        SudByteOrder::LittleEndian => LittleEndian::write_u16(&mut bytes[*p..], *value),
+      }
+    }
+    for (p, value) in [(Self::P_TIME_S, self.time_s), (Self::P_TIME_OFFSET_US, self.time_offset_us)].iter() {
+      match order {
+        SudByteOrder::BigEndian => BigEndian::write_u32(&mut bytes[*p..], *value),
+        SudByteOrder::LittleEndian => LittleEndian::write_u32(&mut bytes[*p..], *value),
+      }
+    }
+    let header_crc = crc16(&bytes[0..Self::P_HEADER_CRC]);
+    match order {
+      SudByteOrder::BigEndian => BigEndian::write_u16(&mut bytes[Self::P_HEADER_CRC..], header_crc),
+      SudByteOrder::LittleEndian => LittleEndian::write_u16(&mut bytes[Self::P_HEADER_CRC..], header_crc),
+    }
+    bytes
+  }
+
+  /// The time of the chunk in microseconds since the Unix epoch.
+  pub fn time(&self) -> u64 {
+    u64::from(self.time_s) * 1_000_000 + u64::from(self.time_offset_us)
+  }
+}
+
+/// A chunk of a `.sud` file.
+#[derive(Debug, Clone)]
+pub struct SudChunk {
+  pub header: SudChunkHeader,
+  pub data: Vec<u8>,
+}
+
+///
+/// The `<CFG>` that describes the chunks with one id.
+///
+#[derive(Debug, Clone)]
+pub struct SudConfig {
+  pub id: u16,
+  pub ftype: String,
+
+  /// The suffix of the file the data is written to, such as `wav` or `log.xml`.
+  pub suffix: String,
+
+  /// The id of the source this takes its data from, from `<SRC ID="..">`.
+  pub src: Option<u16>,
+
+  /// The whole `<CFG>` element.
+  pub element: XmlElement,
+}
+
+impl SudConfig {
+  fn from_element(e: &XmlElement) -> Option<Self> {
+    let suffix = match e.attribute("SUFFIX") {
+      Some(suffix) => suffix.to_string(),
+      None => e.child("SUFFIX").map(|s| s.text.trim().to_string()).unwrap_or_default(),
+    };
+    Some(SudConfig {
+      id: e.attribute("ID")?.trim().parse().ok()?,
+      ftype: e.attribute("FTYPE").unwrap_or_default().to_string(),
+      suffix,
+      src: e.child("SRC").and_then(|s| s.attribute("ID")).and_then(|id| id.trim().parse().ok()),
+      element: e.clone(),
+    })
+  }
+
+  /// Whether the chunks hold X3 compressed audio.
+  pub fn is_x3(&self) -> bool {
+    let codec = self.element.child("CODEC").and_then(|c| c.attribute("TYPE"));
+    self.ftype.to_ascii_uppercase().starts_with("X3") || codec.is_some_and(|t| t.eq_ignore_ascii_case("X3"))
+  }
+
+  /// Whether the chunks hold uncompressed 16 bit samples.
+  pub fn is_wav(&self) -> bool {
+    self.ftype.eq_ignore_ascii_case("WAV")
+  }
+}
+
+///
+/// Read the chunks of a `.sud` file in order.  The configuration is read from the chunks
+/// with id 0 as they are found.
+///
+pub struct SudReader {
+  reader: BufReader<File>,
+  byte_order: Option<SudByteOrder>,
+  configs: Vec<SudConfig>,
+
+  /// The count of chunks that were skipped because of a bad data CRC.
+  crc_errors: usize,
+
+  /// The count of X3 chunks that couldn't be decoded.
+  frame_errors: usize,
+}
+
+impl SudReader {
+  pub fn open<P: AsRef<path::Path>>(sud_filename: P) -> Result<Self, X3Error> {
+    let file = File::open(sud_filename)?;
+    Ok(SudReader {
+      reader: BufReader::with_capacity(64 * 1024, file),
+      byte_order: None,
+      configs: Vec::new(),
+      crc_errors: 0,
+      frame_errors: 0,
+    })
+  }
+
+  /// The byte order of the file, known once the first chunk has been read.
+  pub fn byte_order(&self) -> Option<SudByteOrder> {
+    self.byte_order
+  }
+
+  /// The configuration that has been read so far.
+  pub fn configs(&self) -> &[SudConfig] {
+    &self.configs
+  }
+
+  pub fn config(&self, id: u16) -> Option<&SudConfig> {
+    self.configs.iter().find(|c| c.id == id)
+  }
+
+  pub fn crc_errors(&self) -> usize {
+    self.crc_errors
+  }
+
+  pub fn frame_errors(&self) -> usize {
+    self.frame_errors
+  }
+
+  //
+  // The value of the `name` element of the `<CFG>` of `id`, or of the `<CFG>` that takes
+  // its data from `id`.
+  //
+  fn config_value<T: std::str::FromStr>(&self, id: u16, name: &str) -> Option<T> {
+    let value = |c: &SudConfig| c.element.find(name).and_then(|e| e.text.trim().parse().ok());
+    let consumer = self.configs.iter().find(|c| c.src == Some(id));
+    self.config(id).and_then(value).or_else(|| consumer.and_then(value))
+  }
+
+  /// The sample rate of the audio in the chunks with `id`, from `<FS>`.
+  pub fn sample_rate(&self, id: u16) -> Option<u32> {
+    self.config_value(id, "FS")
+  }
+
+  /// The number of channels of the audio in the chunks with `id`, from `<NCHS>`.
+  pub fn channels(&self, id: u16) -> u16 {
+    self.config_value(id, "NCHS").unwrap_or(1)
+  }
+
+  /// The X3 parameters of the chunks with `id`, from `<CODEC>`.
+  pub fn params(&self, id: u16) -> Result<Parameters, X3Error> {
+    let codec = self.config(id).and_then(|c| c.element.find("CODEC"));
+    let codec = codec.ok_or(X3Error::ArchiveHeaderXMLInvalid)?;
+    let xml = ArchiveMetadata {
+      elements: vec![codec.clone()],
+    }
+    .to_xml();
+    Parameters::from_xml(&xml)
+  }
+
+  ///
+  /// Read the next chunk.  The bytes that are not part of a chunk are skipped, as are the
+  /// chunks with a bad data CRC.  A file that ends part way through a chunk is an error.
+  ///
+  /// ### Returns
+  ///
+  /// * the chunk, or `None` when the end of the file has been reached.
+  ///
+  pub fn next_chunk(&mut self) -> Result<Option<SudChunk>, X3Error> {
+    let parse = |bytes: &[u8; SudChunkHeader::LENGTH]| {
+      let (header, order) = SudChunkHeader::parse(bytes)?;
+      let data_len = header.data_len;
+      Some(((header, order), data_len))
+    };
+    loop {
+      let ((chunk_header, order), data) = match scan::next_block(&mut self.reader, parse)? {
+        Some(block) => (block.header, block.payload),
+        None => return Ok(None),
+      };
+      if crc16(&data) != chunk_header.data_crc {
+        self.crc_errors += 1;
+        continue;
+      }
+
+      self.byte_order.get_or_insert(order);
+      if chunk_header.id == SudChunkHeader::CONFIG_ID {
+        self.read_configs(&data)?;
+      }
+      return Ok(Some(SudChunk {
+        header: chunk_header,
+        data,
+      }));
+    }
+  }
+
+  //
+  // Add the `<CFG>` elements in the XML of a configuration chunk.
+  //
+  fn read_configs(&mut self, data: &[u8]) -> Result<(), X3Error> {
+    let xml = String::from_utf8_lossy(data);
+    let metadata = ArchiveMetadata::from_xml(xml.trim_end_matches('\0'))?;
+
+    for config in metadata.find_all("CFG").into_iter().filter_map(SudConfig::from_element) {
+      self.configs.retain(|c| c.id != config.id);
+      self.configs.push(config);
+    }
+    Ok(())
+  }
+
+  ///
+  /// Decode the X3 audio in `chunk` into `wav_buf`.  A chunk that can't be decoded is
+  /// counted by `frame_errors`.
+  ///
+  /// ### Returns
+  ///
+  /// * the number of samples that were decoded.
+  ///
+  pub fn decode_x3_chunk(&mut self, chunk: &SudChunk, wav_buf: &mut [i16]) -> Result<usize, X3Error> {
+    let result = self.decode_x3(chunk, wav_buf);
+    if result.is_err() {
+      self.frame_errors += 1;
+    }
+    result
+  }
+
+  fn decode_x3(&self, chunk: &SudChunk, wav_buf: &mut [i16]) -> Result<usize, X3Error> {
+    let params = self.params(chunk.header.id)?;
+    let samples = chunk.header.samples as usize;
+    match self.byte_order {
+      Some(SudByteOrder::LittleEndian) => {
+        let mut x3_bytes = chunk.data.clone();
+        for word in x3_bytes.chunks_exact_mut(2) {
+          word.swap(0, 1);
+        }
+        Ok(decoder::decode_frame(&x3_bytes, wav_buf, &params, samples)?.unwrap_or(0))
+      }
+      _ => Ok(decoder::decode_frame(&chunk.data, wav_buf, &params, samples)?.unwrap_or(0)),
+    }
+  }
+}
+
+// Where the data of the chunks with one id is written.
+enum SudOutput {
+  Wav(hound::WavWriter<BufWriter<File>>),
+  Raw(BufWriter<File>),
+  Skip,
+}
+
+///
+/// Unpack a SoundTrap `.sud` file.  The X3 audio is decoded to a wav, and the other chunks,
+/// like the XML log, are written to files with the suffix from their `<CFG>`.  The files
+/// are called `<sud name>.<suffix>`, such as `1234.wav` and `1234.log.xml`.  The audio
+/// without a sample rate, or X3 audio with more than one channel, is skipped, as are the
+/// chunks with a bad CRC and the chunks that can't be decoded.
+///
+/// ### Arguments
+///
+/// * `sud_filename` - the input `.sud` file.
+/// * `out_dir` - the directory to write the files to, existing files will be overwritten.
+///
+/// ### Returns
+///
+/// * the files that were written, and the number of chunks that were skipped because of a
+///   bad CRC or because they couldn't be decoded.
+///
+pub fn sud_to_wav<P: AsRef<path::Path>, Q: AsRef<path::Path>>(
+  sud_filename: P,
+  out_dir: Q,
+) -> Result<(Vec<path::PathBuf>, usize), X3Error> {
+  let stem = sud_filename.as_ref().file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+  let mut reader = SudReader::open(&sud_filename)?;
+
+  let mut filenames = Vec::new();
+  let mut outputs: Vec<(u16, SudOutput)> = Vec::new();
+  let mut wav = vec![0i16; u16::MAX as usize];
+  while let Some(chunk) = reader.next_chunk()? {
+    let id = chunk.header.id;
+    let config = match reader.config(id) {
+      Some(config) if id != SudChunkHeader::CONFIG_ID => config,
+      _ => continue,
+    };
+    let is_x3 = config.is_x3();
+
+    let i = match outputs.iter().position(|(output_id, _)| *output_id == id) {
+      Some(i) => i,
+      None => {
+        let output = create_output(&reader, config, out_dir.as_ref(), &stem, &mut filenames)?;
+        outputs.push((id, output));
+        outputs.len() - 1
+      }
+    };
+
+    match &mut outputs[i].1 {
+      SudOutput::Wav(writer) => {
+        let samples = if is_x3 {
+          match reader.decode_x3_chunk(&chunk, &mut wav) {
+            Ok(samples) => samples,
+            Err(_) => continue,
+          }
+        } else {
+          read_samples(&chunk.data, reader.byte_order(), &mut wav)
+        };
+        for sample in &wav[0..samples] {
+          writer.write_sample(*sample)?;
+        }
+      }
+      SudOutput::Raw(writer) => writer.write_all(&chunk.data)?,
+      SudOutput::Skip => (),
+    }
+  }
+
+  for (_, output) in outputs {
+    match output {
+      SudOutput::Wav(writer) => writer.finalize()?,
+      SudOutput::Raw(mut writer) => writer.flush()?,
+      SudOutput::Skip => (),
+    }
+  }
+
+  Ok((filenames, reader.crc_errors() + reader.frame_errors()))
+}
+
+//
+// Create the file for the chunks described by `config`, called `<stem>.<suffix>` or
+// `<stem>.<id>.<suffix>` if that name is taken.
+//
+fn create_output(
+  reader: &SudReader,
+  config: &SudConfig,
+  out_dir: &path::Path,
+  stem: &str,
+  filenames: &mut Vec<path::PathBuf>,
+) -> Result<SudOutput, X3Error> {
+  let is_audio = config.is_x3() || config.is_wav();
+
+  // The audio is written with the suffix of the wav that takes its data
+  let consumer = reader.configs().iter().find(|c| c.src == Some(config.id));
+  let suffix = match consumer.map(|c| &c.suffix).filter(|s| !s.is_empty()).unwrap_or(&config.suffix) {
+    suffix if !suffix.is_empty() => suffix.clone(),
+    _ if is_audio => "wav".to_string(),
+    _ => config.ftype.to_ascii_lowercase(),
+  };
+  let filename = scan::output_filename(out_dir, stem, config.id, &suffix, filenames);
+
+  if !is_audio {
+    let writer = BufWriter::new(File::create(&filename)?);
+    filenames.push(filename);
+    return Ok(SudOutput::Raw(writer));
+  }
+
+  let channels = reader.channels(config.id);
+  let sample_rate = match reader.sample_rate(config.id) {
+    Some(sample_rate) if !(config.is_x3() && channels > 1) => sample_rate,
+    _ => return Ok(SudOutput::Skip),
+  };
+  let spec = hound::WavSpec {
+    channels,
+    sample_rate,
+    bits_per_sample: 16,
+    sample_format: hound::SampleFormat::Int,
+  };
+  let writer = hound::WavWriter::create(&filename, spec)?;
+  filenames.push(filename);
+  Ok(SudOutput::Wav(writer))
+}
+
+//
+// Read the uncompressed 16 bit samples of a chunk.
+//
+fn read_samples(data: &[u8], order: Option<SudByteOrder>, wav: &mut [i16]) -> usize {
+  let samples = core::cmp::min(data.len() / 2, wav.len());
+  match order {
+    Some(SudByteOrder::LittleEndian) => LittleEndian::read_i16_into(&data[0..samples * 2], &mut wav[0..samples]),
+    _ => BigEndian::read_i16_into(&data[0..samples * 2], &mut wav[0..samples]),
+  }
+  samples
+}
+
+//
+//
+//            #######
+//               #       ######     ####     #####     ####
+//               #       #         #           #      #
+//               #       #####      ####       #       ####
+//               #       #              #      #           #
+//               #       #         #    #      #      #    #
+//               #       ######     ####       #       ####
+//
+//
+
+#[cfg(test)]
+mod tests {
+  use crate::bitpacker::BitPacker;
+  use crate::crc::crc16;
+  use crate::encoder::encode_frame;
+  use crate::error::X3Error;
+  use crate::sud::{sud_to_wav, SudByteOrder, SudChunkHeader, SudReader};
+  use crate::testutil::{sine, temp_path};
+  use crate::x3::{FrameHeader, Parameters};
+
+  const X3_CONFIG: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<CFG ID=\"1\" FTYPE=\"X3V2\">\
+                           <CODEC TYPE=\"X3\"><BLKLEN>16</BLKLEN><CODES N=\"4\">RICE0,RICE1,RICE3,BFP</CODES>\
+                           <T N=\"3\">3,8,20</T></CODEC></CFG>";
+  const WAV_CONFIG: &str = "<SUD><CFG ID=\"2\" FTYPE=\"wav\" SUFFIX=\"wav\"><SRC ID=\"1\" /><FS>96000</FS>\
+                            <NCHS>1</NCHS></CFG><CFG ID=\"3\" FTYPE=\"txt\"><SUFFIX>log.xml</SUFFIX></CFG></SUD>";
+
+  fn chunk(id: u16, samples: u16, time_s: u32, data: &[u8], order: SudByteOrder) -> Vec<u8> {
+    let header = SudChunkHeader {
+      id,
+      data_len: data.len(),
+      samples,
+      time_s,
+      time_offset_us: 500,
+      data_crc: crc16(data),
+    };
+    [&header.to_bytes(order)[..], data].concat()
+  }
+
+  // An X3 frame payload, in 16 bit words of `order`.
+  fn x3_payload(wav: &[i16], params: &Parameters, order: SudByteOrder) -> Vec<u8> {
+    let mut bp = BitPacker::with_capacity(0);
+    assert_eq!(wav.len(), encode_frame(wav, &mut 0, &mut bp, params, &mut [0; 6]).unwrap());
+    let mut payload = bp.into_vec().split_off(FrameHeader::LENGTH);
+    if order == SudByteOrder::LittleEndian {
+      for word in payload.chunks_exact_mut(2) {
+        word.swap(0, 1);
+      }
+    }
+    payload
+  }
+
+  // There is no recording from a SoundTrap to test with yet, so the file is built from the
+  // format in the module docs.  A real `.sud` file should be added as a fixture.
+  fn write_sud(path: &std::path::Path, wav: &[i16], log: &str, order: SudByteOrder) {
+    let params = Parameters::builder().block_len(16).build().unwrap();
+
+    // A file header with a magic number that isn't a chunk
+    let mut sud = b"SUD\x00\x01\xa9\x52 file header".to_vec();
+    sud.extend(chunk(0, 0, 0, X3_CONFIG.as_bytes(), order));
+    sud.extend(chunk(0, 0, 0, WAV_CONFIG.as_bytes(), order));
+    for (i, part) in wav.chunks(4000).enumerate() {
+      sud.extend(chunk(1, part.len() as u16, 1_600_000_000 + i as u32, &x3_payload(part, &params, order), order));
+    }
+
+    // An audio chunk that is too short for its samples is skipped
+    sud.extend(chunk(1, 4000, 1_600_000_003, &[0x12, 0x34], order));
+
+    // A chunk with a bad data CRC is skipped
+    let mut bad = chunk(3, 0, 0, b"lost", order);
+    bad[SudChunkHeader::LENGTH] ^= 1;
+    sud.extend(bad);
+    for part in log.as_bytes().chunks(10) {
+      sud.extend(chunk(3, 0, 0, part, order));
+    }
+    std::fs::write(path, sud).unwrap();
+  }
+
+  #[test]
+  fn test_sud_reader() {
+    let wav = sine(10_001, 0.02, 3000.0, 5);
+    let log = "<LOG><EVENT TIME=\"1600000000\">Start</EVENT></LOG>\n";
+    let dir = std::env::temp_dir();
+
+    for (name, order) in [("sud_be", SudByteOrder::BigEndian), ("sud_le", SudByteOrder::LittleEndian)] {
+      let sud_filename = temp_path(&format!("{}.sud", name));
+      write_sud(&sud_filename, &wav, log, order);
+
+      let mut reader = SudReader::open(&sud_filename).unwrap();
+      let mut times = Vec::new();
+      let mut buf = vec![0; 4000];
+      while let Some(chunk) = reader.next_chunk().unwrap() {
+        if chunk.header.id == 1 && reader.decode_x3_chunk(&chunk, &mut buf).is_ok() {
+          times.push(chunk.header.time());
+        }
+      }
+      assert_eq!(Some(order), reader.byte_order());
+      assert_eq!((1, 1), (reader.crc_errors(), reader.frame_errors()));
+      assert_eq!(vec![1_600_000_000_000_500, 1_600_000_001_000_500, 1_600_000_002_000_500], times);
+      assert_eq!(vec![1, 2, 3], reader.configs().iter().map(|c| c.id).collect::<Vec<u16>>());
+      assert!(reader.config(1).unwrap().is_x3());
+      assert_eq!(Some(96000), reader.sample_rate(1));
+      assert_eq!(16, reader.params(1).unwrap().block_len);
+
+      let (filenames, skipped) = sud_to_wav(&sud_filename, &dir).unwrap();
+      let stem = sud_filename.with_extension("");
+      assert_eq!(vec![stem.with_extension("wav"), stem.with_extension("log.xml")], filenames);
+      assert_eq!(2, skipped);
+
+      let mut wav_reader = hound::WavReader::open(&filenames[0]).unwrap();
+      assert_eq!(96000, wav_reader.spec().sample_rate);
+      let decoded: Vec<i16> = wav_reader.samples::<i16>().map(|s| s.unwrap()).collect();
+      assert!(wav == decoded);
+      assert_eq!(log, std::fs::read_to_string(&filenames[1]).unwrap());
+
+      // A file that ends part way through a chunk is an error
+      let sud = std::fs::read(&sud_filename).unwrap();
+      std::fs::write(&sud_filename, &sud[0..sud.len() - 3]).unwrap();
+      let mut reader = SudReader::open(&sud_filename).unwrap();
+      let result = std::iter::from_fn(|| reader.next_chunk().transpose()).find(|chunk| chunk.is_err());
+      assert!(matches!(result, Some(Err(X3Error::FrameHeaderInvalidPayloadLen))));
+
+      std::fs::remove_file(&sud_filename).unwrap();
+      for filename in filenames {
+        std::fs::remove_file(filename).unwrap();
+      }
+    }
+  }
+}
@@ -0,0 +1,61 @@
+/**************************************************************************
+ *                                                                        *
+ * Rust implementation of the X3 lossless audio compression protocol.     *
+ *                                                                        *
+ * Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>             *
+ *                                                                        *
+ * This program is free software; you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the           *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.   *
+ *                                                                        *
+ **************************************************************************/
+
+//
+// The signals and temporary files for the tests.  The integration tests in `tests` include
+// this file too, so it only uses `std`, and not every test uses every function.
+//
+#![allow(dead_code)]
+
+// std
+use std::path::PathBuf;
+
+//
+// A pseudo random generator, so the signals are the same on every run.
+//
+pub fn lcg(seed: &mut u32) -> i16 {
+  *seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+  (*seed >> 16) as i16
+}
+
+//
+// `len` samples of white noise, it is encoded with pass-through blocks.
+//
+pub fn noise(len: usize, seed: u32) -> Vec<i16> {
+  let mut seed = seed;
+  (0..len).map(|_| lcg(&mut seed)).collect()
+}
+
+//
+// A sine wave, plus a ramp that repeats every `ramp` samples so the blocks are not all
+// the same.  A `ramp` of 1 is a plain sine wave.
+//
+pub fn sine(len: usize, step: f32, amplitude: f32, ramp: usize) -> Vec<i16> {
+  (0..len).map(|i| ((i as f32 * step).sin() * amplitude) as i16 + (i % ramp) as i16).collect()
+}
+
+//
+// A file in the temporary directory.  The name has the process id, so tests that are run
+// at the same time don't overwrite each other's files.
+//
+pub fn temp_path(name: &str) -> PathBuf {
+  std::env::temp_dir().join(format!("x3_test_{}_{}", std::process::id(), name))
+}
@@ -0,0 +1,546 @@
+/**************************************************************************
+ *                                                                        *
+ * Rust implementation of the X3 lossless audio compression protocol.     *
+ *                                                                        *
+ * Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>             *
+ *                                                                        *
+ * This program is free software; you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the           *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.   *
+ *                                                                        *
+ **************************************************************************/
+
+//!
+//! The metadata chunks of a wav file, `LIST/INFO`, `bext` (Broadcast WAV) and `iXML`.
+//! `hound` skips these chunks, so they are read and written here.  In the archive they
+//! are kept in the audio `<CFG>` of the XML metadata:
+//!
+//! ```text
+//! <WAVINFO><INAM>Title</INAM><ICMT>Comment</ICMT></WAVINFO>
+//! <BEXT VERSION="1"><DESCRIPTION>...</DESCRIPTION><ORIGINATOR>...</ORIGINATOR>
+//!   <ORIGINATORREF>...</ORIGINATORREF><DATE>2020-01-31</DATE><TIME>12:30:00</TIME>
+//!   <TIMEREF>0</TIMEREF><UMID>...</UMID><LOUDNESS>...</LOUDNESS><HISTORY>...</HISTORY></BEXT>
+//! <IXML>...</IXML>
+//! ```
+//!
+
+// std
+use std::convert::TryFrom;
+use std::fs::{File, OpenOptions};
+use std::io::{prelude::*, BufReader, SeekFrom};
+use std::path;
+
+// externs
+use byteorder::{ByteOrder, LittleEndian};
+
+// this crate
+use crate::error::X3Error;
+use crate::metadata::{ArchiveMetadata, XmlElement};
+use crate::x3;
+
+/// The length of the `bext` chunk before the coding history.
+const BEXT_LENGTH: usize = 602;
+
+///
+/// The Broadcast WAV extension, see EBU Tech 3285.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Bext {
+  pub description: String,
+  pub originator: String,
+  pub originator_reference: String,
+
+  /// yyyy-mm-dd
+  pub origination_date: String,
+
+  /// hh:mm:ss
+  pub origination_time: String,
+
+  /// The first sample, counted in samples since midnight.
+  pub time_reference: u64,
+  pub version: u16,
+  pub umid: Vec<u8>,
+
+  /// Loudness value, loudness range, max true peak, max momentary and max short term
+  /// loudness, each in 1/100ths.
+  pub loudness: [i16; 5],
+  pub coding_history: String,
+}
+
+impl Bext {
+  fn parse(data: &[u8]) -> Option<Self> {
+    if data.len() < BEXT_LENGTH {
+      return None;
+    }
+    let mut loudness = [0i16; 5];
+    LittleEndian::read_i16_into(&data[412..422], &mut loudness);
+
+    // An all zero UMID isn't set
+    let umid = &data[348..412];
+    let umid = if umid.iter().any(|b| *b != 0) { umid.to_vec() } else { Vec::new() };
+
+    Some(Bext {
+      description: read_str(&data[0..256]),
+      originator: read_str(&data[256..288]),
+      originator_reference: read_str(&data[288..320]),
+      origination_date: read_str(&data[320..330]),
+      origination_time: read_str(&data[330..338]),
+      time_reference: LittleEndian::read_u64(&data[338..346]),
+      version: LittleEndian::read_u16(&data[346..348]),
+      umid,
+      loudness,
+      coding_history: read_str(&data[BEXT_LENGTH..]),
+    })
+  }
+
+  fn to_bytes(&self) -> Vec<u8> {
+    let mut data = vec![0u8; BEXT_LENGTH];
+    write_str(&mut data[0..256], &self.description);
+    write_str(&mut data[256..288], &self.originator);
+    write_str(&mut data[288..320], &self.originator_reference);
+    write_str(&mut data[320..330], &self.origination_date);
+    write_str(&mut data[330..338], &self.origination_time);
+    LittleEndian::write_u64(&mut data[338..346], self.time_reference);
+    LittleEndian::write_u16(&mut data[346..348], self.version);
+    let umid_len = core::cmp::min(self.umid.len(), 64);
+    data[348..(348 + umid_len)].copy_from_slice(&self.umid[0..umid_len]);
+    LittleEndian::write_i16_into(&self.loudness, &mut data[412..422]);
+    data.extend_from_slice(self.coding_history.as_bytes());
+    data
+  }
+
+  ///
+  /// The time of the first sample in microseconds since the Unix epoch, the origination
+  /// date plus the time reference, or the origination date and time if there is no time
+  /// reference.  `None` if the date can't be parsed.
+  ///
+  pub fn start_time(&self, sample_rate: u32) -> Option<u64> {
+    // The standard allows any of '-', '_', ':', ' ' or '.' as separators
+    let numbers = |s: &str| -> Vec<u32> {
+      s.split(|c: char| !c.is_ascii_digit())
+        .filter(|n| !n.is_empty())
+        .filter_map(|n| n.parse().ok())
+        .collect()
+    };
+    let date = numbers(&self.origination_date);
+    if date.len() != 3 {
+      return None;
+    }
+    let year = i32::try_from(date[0]).ok()?;
+    let midnight = chrono::NaiveDate::from_ymd_opt(year, date[1], date[2])?.and_hms_opt(0, 0, 0)?;
+    let epoch = chrono::NaiveDate::from_ymd_opt(1970, 1, 1)?.and_hms_opt(0, 0, 0)?;
+    let midnight = midnight.signed_duration_since(epoch).num_microseconds()?;
+
+    let since_midnight = if self.time_reference > 0 && sample_rate > 0 {
+      i64::try_from(u128::from(self.time_reference) * 1_000_000 / u128::from(sample_rate)).ok()?
+    } else {
+      let time = numbers(&self.origination_time);
+      if time.len() != 3 {
+        return None;
+      }
+      // The values come from the file, so they can be any size
+      let seconds = i64::from(time[0])
+        .checked_mul(3600)?
+        .checked_add(i64::from(time[1]).checked_mul(60)?)?
+        .checked_add(i64::from(time[2]))?;
+      seconds.checked_mul(1_000_000)?
+    };
+
+    u64::try_from(midnight.checked_add(since_midnight)?).ok().filter(|t| *t > 0)
+  }
+
+  fn to_element(&self) -> XmlElement {
+    let loudness: Vec<String> = self.loudness.iter().map(|l| l.to_string()).collect();
+    let umid: String = self.umid.iter().map(|b| format!("{:02X}", b)).collect();
+    XmlElement::new("BEXT")
+      .with_attribute("VERSION", &self.version.to_string())
+      .with_child(XmlElement::new("DESCRIPTION").with_text(&self.description))
+      .with_child(XmlElement::new("ORIGINATOR").with_text(&self.originator))
+      .with_child(XmlElement::new("ORIGINATORREF").with_text(&self.originator_reference))
+      .with_child(XmlElement::new("DATE").with_text(&self.origination_date))
+      .with_child(XmlElement::new("TIME").with_text(&self.origination_time))
+      .with_child(XmlElement::new("TIMEREF").with_text(&self.time_reference.to_string()))
+      .with_child(XmlElement::new("UMID").with_text(&umid))
+      .with_child(XmlElement::new("LOUDNESS").with_text(&loudness.join(",")))
+      .with_child(XmlElement::new("HISTORY").with_text(&self.coding_history))
+  }
+
+  fn from_element(e: &XmlElement) -> Self {
+    let text = |name: &str| e.child(name).map(|c| c.text.clone()).unwrap_or_default();
+    // Pairs of hex digits, the pairs that aren't hex are skipped
+    let umid = text("UMID")
+      .as_bytes()
+      .chunks_exact(2)
+      .filter(|pair| pair.iter().all(u8::is_ascii_hexdigit))
+      .filter_map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+      .collect();
+    let mut loudness = [0i16; 5];
+    for (l, s) in loudness.iter_mut().zip(text("LOUDNESS").split(',')) {
+      *l = s.trim().parse().unwrap_or(0);
+    }
+
+    Bext {
+      description: text("DESCRIPTION"),
+      originator: text("ORIGINATOR"),
+      originator_reference: text("ORIGINATORREF"),
+      origination_date: text("DATE"),
+      origination_time: text("TIME"),
+      time_reference: text("TIMEREF").trim().parse().unwrap_or(0),
+      version: e.attribute("VERSION").and_then(|v| v.parse().ok()).unwrap_or(0),
+      umid,
+      loudness,
+      coding_history: text("HISTORY"),
+    }
+  }
+}
+
+///
+/// The metadata chunks of a wav file.
+///
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WavChunks {
+  /// The `LIST/INFO` entries, such as `INAM` (title) or `ICMT` (comment), in order.
+  pub info: Vec<(String, String)>,
+  pub bext: Option<Bext>,
+  pub ixml: Option<String>,
+}
+
+impl WavChunks {
+  ///
+  /// Read the metadata chunks from a wav file.  The rest of the file is checked by `hound`,
+  /// so a badly formed chunk, or one that is longer than the rest of the file, just ends
+  /// the search.
+  ///
+  pub fn read<P: AsRef<path::Path>>(wav_filename: P) -> Result<Self, X3Error> {
+    let mut reader = BufReader::new(File::open(wav_filename)?);
+    let mut chunks = WavChunks::default();
+
+    let mut riff = [0u8; 12];
+    reader.read_exact(&mut riff)?;
+    if &riff[0..4] != b"RIFF" || &riff[8..12] != b"WAVE" {
+      return Ok(chunks);
+    }
+
+    let mut header = [0u8; 8];
+    while reader.read_exact(&mut header).is_ok() {
+      let len = LittleEndian::read_u32(&header[4..8]) as usize;
+      let padded_len = len + len % 2;
+      match &header[0..4] {
+        b"LIST" | b"bext" | b"iXML" => {
+          // The length isn't trusted until the data has been read
+          let mut data = Vec::new();
+          if (&mut reader).take(len as u64).read_to_end(&mut data)? < len {
+            break;
+          }
+          reader.seek(SeekFrom::Current((padded_len - len) as i64))?;
+          match &header[0..4] {
+            b"LIST" => chunks.info.extend(parse_info(&data)),
+            b"bext" => chunks.bext = Bext::parse(&data),
+            _ => chunks.ixml = Some(read_str(&data)),
+          }
+        }
+        _ => {
+          reader.seek(SeekFrom::Current(padded_len as i64))?;
+        }
+      }
+    }
+
+    Ok(chunks)
+  }
+
+  ///
+  /// Append the chunks to the end of a wav file, and update the RIFF length.
+  ///
+  pub fn append_to<P: AsRef<path::Path>>(&self, wav_filename: P) -> Result<(), X3Error> {
+    let mut data = Vec::new();
+    if !self.info.is_empty() {
+      let mut list = b"INFO".to_vec();
+      for (id, value) in &self.info {
+        let mut value = value.as_bytes().to_vec();
+        value.push(0);
+        append_chunk(&mut list, &info_id(id), &value);
+      }
+      append_chunk(&mut data, b"LIST", &list);
+    }
+    if let Some(bext) = &self.bext {
+      append_chunk(&mut data, b"bext", &bext.to_bytes());
+    }
+    if let Some(ixml) = &self.ixml {
+      append_chunk(&mut data, b"iXML", ixml.as_bytes());
+    }
+    if data.is_empty() {
+      return Ok(());
+    }
+
+    let mut file = OpenOptions::new().read(true).write(true).open(wav_filename)?;
+    let mut riff_len = file.seek(SeekFrom::End(0))?;
+    if riff_len % 2 == 1 {
+      // Chunks start on an even byte
+      file.write_all(&[0])?;
+      riff_len += 1;
+    }
+    file.write_all(&data)?;
+
+    let mut len = [0u8; 4];
+    LittleEndian::write_u32(&mut len, (riff_len + data.len() as u64 - 8) as u32);
+    file.seek(SeekFrom::Start(4))?;
+    file.write_all(&len)?;
+
+    Ok(())
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.info.is_empty() && self.bext.is_none() && self.ixml.is_none()
+  }
+
+  ///
+  /// Add the chunks to the audio `<CFG>` of `metadata`, replacing any that are already
+  /// there.  The chunks that the wav doesn't have are left as they are.
+  ///
+  pub fn set_metadata(&self, metadata: &mut ArchiveMetadata) {
+    self.set_source_metadata(metadata, x3::Archive::WAV_SOURCE_ID);
+  }
+
+  ///
+  /// Add the chunks to the `<CFG>` of `source_id`, the same as `set_metadata`.
+  ///
+  pub fn set_source_metadata(&self, metadata: &mut ArchiveMetadata, source_id: u8) {
+    let cfg = metadata.cfg_mut(source_id);
+    if !self.info.is_empty() {
+      let mut info = XmlElement::new("WAVINFO");
+      for (id, value) in &self.info {
+        info.children.push(XmlElement::new(id).with_text(value));
+      }
+      cfg.set_child(info);
+    }
+    if let Some(bext) = &self.bext {
+      cfg.set_child(bext.to_element());
+    }
+    if let Some(ixml) = &self.ixml {
+      cfg.set_child(XmlElement::new("IXML").with_text(ixml));
+    }
+  }
+
+  ///
+  /// The chunks kept in the audio `<CFG>` of `metadata`.
+  ///
+  pub fn from_metadata(metadata: &ArchiveMetadata) -> Self {
+    Self::from_source_metadata(metadata, x3::Archive::WAV_SOURCE_ID)
+  }
+
+  ///
+  /// The chunks kept in the `<CFG>` of `source_id`.
+  ///
+  pub fn from_source_metadata(metadata: &ArchiveMetadata, source_id: u8) -> Self {
+    let cfg = match metadata.cfg(source_id) {
+      Some(cfg) => cfg,
+      None => return WavChunks::default(),
+    };
+    WavChunks {
+      info: cfg
+        .child("WAVINFO")
+        .map(|info| info.children.iter().map(|c| (c.name.clone(), c.text.clone())).collect())
+        .unwrap_or_default(),
+      bext: cfg.child("BEXT").map(Bext::from_element),
+      ixml: cfg.child("IXML").map(|c| c.text.clone()),
+    }
+  }
+
+  ///
+  /// The time of the first sample from the `bext` chunk, in microseconds since the Unix
+  /// epoch.  0 if it isn't known.
+  ///
+  pub fn start_time(&self, sample_rate: u32) -> u64 {
+    self.bext.as_ref().and_then(|b| b.start_time(sample_rate)).unwrap_or(0)
+  }
+}
+
+//
+// Parse the entries of a `LIST` chunk, only `INFO` lists are used.
+//
+fn parse_info(data: &[u8]) -> Vec<(String, String)> {
+  let mut info = Vec::new();
+  if data.len() < 4 || &data[0..4] != b"INFO" {
+    return info;
+  }
+  let mut p = 4;
+  while p + 8 <= data.len() {
+    let id = read_str(&data[p..(p + 4)]);
+    let len = LittleEndian::read_u32(&data[(p + 4)..(p + 8)]) as usize;
+    p += 8;
+    if p + len > data.len() {
+      break;
+    }
+    info.push((id, read_str(&data[p..(p + len)])));
+    p += len + len % 2;
+  }
+  info
+}
+
+//
+// INFO ids are four characters, longer names are cut and shorter are padded.
+//
+fn info_id(id: &str) -> [u8; 4] {
+  let mut bytes = [b' '; 4];
+  for (b, c) in bytes.iter_mut().zip(id.bytes()) {
+    *b = c;
+  }
+  bytes
+}
+
+fn append_chunk(out: &mut Vec<u8>, id: &[u8], data: &[u8]) {
+  out.extend_from_slice(id);
+  let mut len = [0u8; 4];
+  LittleEndian::write_u32(&mut len, data.len() as u32);
+  out.extend_from_slice(&len);
+  out.extend_from_slice(data);
+  if data.len() % 2 == 1 {
+    out.push(0);
+  }
+}
+
+//
+// Read a string that may be padded with NULs.
+//
+fn read_str(data: &[u8]) -> String {
+  let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+  String::from_utf8_lossy(&data[0..end]).into_owned()
+}
+
+//
+// Write a string into a fixed length field, it is cut to fit.
+//
+fn write_str(field: &mut [u8], s: &str) {
+  let len = core::cmp::min(field.len(), s.len());
+  field[0..len].copy_from_slice(&s.as_bytes()[0..len]);
+}
+
+//
+//
+//            #######
+//               #       ######     ####     #####     ####
+//               #       #         #           #      #
+//               #       #####      ####       #       ####
+//               #       #              #      #           #
+//               #       #         #    #      #      #    #
+//               #       ######     ####       #       ####
+//
+//
+
+#[cfg(test)]
+mod tests {
+  use crate::metadata::ArchiveMetadata;
+  use crate::testutil::temp_path;
+  use crate::wavchunks::{Bext, WavChunks};
+
+  fn example_chunks() -> WavChunks {
+    WavChunks {
+      info: vec![
+        ("INAM".to_string(), "Humpback song".to_string()),
+        ("ICMT".to_string(), "Station 4 & 5".to_string()),
+      ],
+      bext: Some(Bext {
+        description: "Hydrophone deployment".to_string(),
+        originator: "x3 tests".to_string(),
+        originator_reference: "REF0001".to_string(),
+        origination_date: "2020-02-29".to_string(),
+        origination_time: "23:59:58".to_string(),
+        time_reference: 48000 * 3600,
+        version: 1,
+        umid: (0..64).collect(),
+        loudness: [-2300, 500, -100, -1800, -2000],
+        coding_history: "A=PCM,F=48000,W=16,M=mono\r\n".to_string(),
+      }),
+      ixml: Some("<BWFXML><PROJECT>Whales</PROJECT></BWFXML>".to_string()),
+    }
+  }
+
+  #[test]
+  fn test_wav_chunks_file() {
+    let filename = temp_path("wav_chunks.wav");
+    let spec = hound::WavSpec {
+      channels: 1,
+      sample_rate: 48000,
+      bits_per_sample: 16,
+      sample_format: hound::SampleFormat::Int,
+    };
+    let mut writer = hound::WavWriter::create(&filename, spec).unwrap();
+    for i in 0..101 {
+      writer.write_sample(i as i16).unwrap();
+    }
+    writer.finalize().unwrap();
+    assert!(WavChunks::read(&filename).unwrap().is_empty());
+
+    let chunks = example_chunks();
+    chunks.append_to(&filename).unwrap();
+    assert_eq!(chunks, WavChunks::read(&filename).unwrap());
+
+    // The samples can still be read
+    let mut reader = hound::WavReader::open(&filename).unwrap();
+    let samples: Vec<i16> = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+    assert_eq!(101, samples.len());
+
+    // A chunk that claims to be longer than the file ends the search
+    let mut wav = std::fs::read(&filename).unwrap();
+    wav.extend_from_slice(b"iXML\xf0\xff\xff\xff<BWFXML>");
+    std::fs::write(&filename, wav).unwrap();
+    assert_eq!(chunks, WavChunks::read(&filename).unwrap());
+    std::fs::remove_file(&filename).unwrap();
+  }
+
+  #[test]
+  fn test_wav_chunks_metadata() {
+    let chunks = example_chunks();
+    let mut metadata = ArchiveMetadata::new();
+    chunks.set_metadata(&mut metadata);
+
+    let metadata = ArchiveMetadata::from_xml(&metadata.to_xml()).unwrap();
+    assert_eq!(chunks, WavChunks::from_metadata(&metadata));
+    assert!(WavChunks::from_metadata(&ArchiveMetadata::new()).is_empty());
+
+    // The UMID pairs that aren't hex are skipped, even with multi-byte characters
+    let mut metadata = metadata;
+    let bext = metadata.cfg_mut(1).child_mut("BEXT").unwrap();
+    bext.child_mut("UMID").unwrap().text = "0a\u{e9}1Fzz7".to_string();
+    assert_eq!(vec![0x0a, 0x1f], WavChunks::from_metadata(&metadata).bext.unwrap().umid);
+  }
+
+  #[test]
+  fn test_bext_start_time() {
+    // 2020-02-29 00:00:00 UTC, plus one hour of samples
+    let midnight = 1_582_934_400_000_000u64;
+    let mut bext = example_chunks().bext.unwrap();
+    assert_eq!(Some(midnight + 3_600_000_000), bext.start_time(48000));
+
+    // Without a time reference the origination time is used
+    bext.time_reference = 0;
+    assert_eq!(Some(midnight + 86_398_000_000), bext.start_time(48000));
+
+    bext.origination_date = "2020_02_29".to_string();
+    bext.origination_time = "23.59.58".to_string();
+    assert_eq!(Some(midnight + 86_398_000_000), bext.start_time(48000));
+
+    // Times too far past the date to fit in the microseconds are unknown, not a panic
+    bext.origination_time = "4000000:0:0".to_string();
+    assert_eq!(Some(midnight + 4_000_000 * 3_600_000_000), bext.start_time(48000));
+    bext.origination_time = "4294967295:4294967295:4294967295".to_string();
+    assert_eq!(None, bext.start_time(48000));
+    bext.time_reference = 9_223_372_036_854 * 48000;
+    assert_eq!(None, bext.start_time(48000));
+    bext.time_reference = u64::MAX;
+    assert_eq!(None, bext.start_time(1));
+
+    bext.time_reference = 0;
+    bext.origination_date = "".to_string();
+    assert_eq!(None, bext.start_time(48000));
+  }
+}
@@ -19,11 +19,29 @@
  *                                                                        *
  **************************************************************************/
 
+use crate::byteorder::{BigEndian, ByteOrder};
+use crate::crc::crc16;
 use crate::error::X3Error;
+use crate::metadata::ArchiveMetadata;
 
+use quick_xml::events::Event;
+use quick_xml::Reader;
+
+///
+/// Decode an .x3a (X3 Archive) that is held in memory, such as a memory-mapped file.  The
+/// frames are borrowed from the input, see `decodefile::decode_x3a_bytes`.
+///
 pub struct Decoder<'a> {
-  pub channels: &'a [Channel<'a>],
-  pub x3_inp: &'a mut [u8],
+  pub(crate) x3_inp: &'a [u8],
+
+  /// The source id and spec of each stream, and the index of the one being decoded.
+  pub(crate) sources: Vec<(u8, X3aSpec)>,
+  pub(crate) selected: usize,
+  pub(crate) metadata: ArchiveMetadata,
+
+  /// The length of the archive id and the XML header frame.
+  pub(crate) header_len: usize,
+  pub(crate) frame_errors: usize,
 }
 
 pub struct Channel<'a> {
@@ -31,6 +49,8 @@ pub struct Channel<'a> {
   pub wav: &'a [i16],     // The raw wave data
   pub sample_rate: u32,   // The sample rate in Hz
   pub params: Parameters, // X3 encoding parameters
+  pub start_time: u64,    // The time of the first sample (microseconds since the Unix epoch), 0 if unknown
+  pub source_id: u8,      // The source id written to each frame header, the `<CFG ID="..">` of the stream
 }
 
 impl<'a> Channel<'a> {
@@ -40,10 +60,59 @@ impl<'a> Channel<'a> {
       wav,
       sample_rate,
       params,
+      start_time: 0,
+      source_id: Archive::WAV_SOURCE_ID,
     }
   }
+
+  ///
+  /// Set the source id of the frames, so several streams can be interleaved in one archive.
+  /// Each source is described by the `<CFG>` with the same `ID` in the archive header.
+  ///
+  pub fn with_source_id(mut self, source_id: u8) -> Self {
+    self.source_id = source_id;
+    self
+  }
+
+  ///
+  /// Set the time of the first sample, in microseconds since the Unix epoch.  Each frame
+  /// header will hold the time of its first sample.
+  ///
+  pub fn with_start_time(mut self, start_time: u64) -> Self {
+    self.start_time = start_time;
+    self
+  }
+
+  ///
+  /// The time of `wav[sample]` in microseconds since the Unix epoch, or 0 if the start time
+  /// is unknown.
+  ///
+  pub fn sample_time(&self, sample: usize) -> u64 {
+    sample_time(self.start_time, self.sample_rate, sample)
+  }
+}
+
+///
+/// The time of a sample in microseconds since the Unix epoch, for the frame headers.
+///
+/// ### Arguments
+///
+/// * `start_time` - the time of the first sample, 0 if it is unknown.
+/// * `sample_rate` - the number of samples per second.
+/// * `sample` - the number of samples since the first one.
+///
+/// ### Returns
+///
+/// * the time, or 0 if the start time or sample rate is unknown.
+///
+pub fn sample_time(start_time: u64, sample_rate: u32, sample: usize) -> u64 {
+  if start_time == 0 || sample_rate == 0 {
+    return 0;
+  }
+  start_time + (sample as u128 * 1_000_000 / u128::from(sample_rate)) as u64
 }
 
+#[derive(Debug, Clone)]
 pub struct X3aSpec {
   /// The number of samples per second.
   pub sample_rate: u32,
@@ -54,6 +123,7 @@ pub struct X3aSpec {
   /// The number of channels in use
   pub channels: u8,
 }
+#[derive(Debug, Clone)]
 pub struct Parameters {
   pub block_len: usize,
   pub blocks_per_frame: usize,
@@ -62,8 +132,21 @@ pub struct Parameters {
   pub rice_codes: [&'static RiceCode; 3],
 }
 
+///
+/// The reasons `Parameters` can be rejected.
+///
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParametersError {
+  InvalidBlockLength(usize),     // The block length must be from 1 to `Parameters::MAX_BLOCK_LENGTH`
+  InvalidBlocksPerFrame(usize),  // There must be at least one block in a frame
+  TooManySamplesPerFrame(usize), // The frame header can only hold `u16::MAX` samples
+  InvalidRiceCode(usize),        // Only RICE0 to RICE3 exist
+  ThresholdOutOfRange(usize),    // The threshold at this index is outside the range of its rice code
+  ThresholdsNotIncreasing,       // Each threshold must be at least as large as the one before
+}
+
 impl Parameters {
-  pub const MAX_BLOCK_LENGTH: usize = 60;
+  pub const MAX_BLOCK_LENGTH: usize = 256;
   pub const WAV_BIT_SIZE: usize = 16;
 
   pub const DEFAULT_BLOCK_LENGTH: usize = 20;
@@ -77,13 +160,33 @@ impl Parameters {
     codes: [usize; 3],
     thresholds: [usize; 3],
   ) -> Result<Self, X3Error> {
+    if block_len == 0 || block_len > Self::MAX_BLOCK_LENGTH {
+      return Err(ParametersError::InvalidBlockLength(block_len).into());
+    }
+    if blocks_per_frame == 0 {
+      return Err(ParametersError::InvalidBlocksPerFrame(blocks_per_frame).into());
+    }
+
+    // The number of samples is a u16 in the frame header.  The encoder closes frames early
+    // to keep them under `Frame::MAX_LENGTH` bytes.
+    let samples_per_frame = block_len.saturating_mul(blocks_per_frame);
+    if samples_per_frame > u16::MAX as usize {
+      return Err(ParametersError::TooManySamplesPerFrame(samples_per_frame).into());
+    }
+
+    if let Some(code) = codes.iter().find(|c| **c >= RiceCodes::CODE.len()) {
+      return Err(ParametersError::InvalidRiceCode(*code).into());
+    }
     let rice_codes = RiceCodes::get(codes);
 
-    // setup the codes
-    for k in 0..2 {
-      let rc = rice_codes[k];
-      if thresholds[k] > rc.offset {
-        return Err(X3Error::InvalidEncodingThresh);
+    // setup the codes, each threshold must be in the range of its rice code, and the
+    // thresholds must increase
+    for k in 0..3 {
+      if thresholds[k] > rice_codes[k].max_abs() {
+        return Err(ParametersError::ThresholdOutOfRange(k).into());
+      }
+      if k > 0 && thresholds[k] < thresholds[k - 1] {
+        return Err(ParametersError::ThresholdsNotIncreasing.into());
       }
     }
 
@@ -96,7 +199,122 @@ impl Parameters {
     })
   }
 
-  pub fn default() -> Self {
+  ///
+  /// Start building a set of parameters from the defaults.
+  ///
+  pub fn builder() -> ParametersBuilder {
+    ParametersBuilder::default()
+  }
+
+  ///
+  /// The number of samples in a full frame.
+  ///
+  pub fn samples_per_frame(&self) -> usize {
+    self.block_len * self.blocks_per_frame
+  }
+
+  ///
+  /// The `<CODEC>` element of the archive header XML that describes these parameters.
+  ///
+  pub fn to_xml(&self) -> String {
+    [
+      "<CODEC TYPE=\"X3\" VERS=\"2\">",
+      &format!("<BLKLEN>{}</BLKLEN>", self.block_len),
+      &format!(
+        "<CODES N=\"4\">RICE{},RICE{},RICE{},BFP</CODES>",
+        self.codes[0], self.codes[1], self.codes[2]
+      ),
+      "<FILTER>DIFF</FILTER>",
+      &format!("<NBITS>{}</NBITS>", Self::WAV_BIT_SIZE),
+      &format!(
+        "<T N=\"3\">{},{},{}</T>",
+        self.thresholds[0], self.thresholds[1], self.thresholds[2]
+      ),
+      "</CODEC>",
+    ]
+    .concat()
+  }
+
+  ///
+  /// Read the parameters from the `<CODEC>` element of the archive header XML, `xml` may
+  /// be the element itself or the whole header.  The number of blocks per frame is not
+  /// stored in the XML, so the largest valid number up to the default is used.
+  ///
+  pub fn from_xml(xml: &str) -> Result<Self, X3Error> {
+    let mut reader = Reader::from_str(xml);
+    reader.trim_text(true);
+
+    let mut buf = Vec::new();
+    let mut block_len = None;
+    let mut codes = None;
+    let mut thresholds = None;
+
+    loop {
+      match reader.read_event(&mut buf) {
+        Ok(Event::Start(ref e)) => {
+          let value = match e.name() {
+            b"BLKLEN" => &mut block_len,
+            b"CODES" => &mut codes,
+            b"T" => &mut thresholds,
+            _ => continue,
+          };
+          let text = reader.read_text(e.name(), &mut Vec::new());
+          *value = Some(text.map_err(|_| X3Error::ArchiveHeaderXMLInvalid)?);
+        }
+        Ok(Event::Eof) => break,
+        Err(_) => return Err(X3Error::ArchiveHeaderXMLInvalid),
+        _ => (),
+      }
+      buf.clear();
+    }
+
+    let block_len = block_len
+      .and_then(|s| s.trim().parse::<usize>().ok())
+      .ok_or(X3Error::ArchiveHeaderXMLInvalid)?;
+
+    // The block types are listed in order, the last is always BFP
+    let mut rice_codes = [0; 3];
+    let words: Vec<&str> = codes.as_deref().ok_or(X3Error::ArchiveHeaderXMLInvalid)?.split(',').collect();
+    if words.len() != 4 || words[3].trim() != "BFP" {
+      return Err(X3Error::ArchiveHeaderXMLRiceCode);
+    }
+    for (code, word) in rice_codes.iter_mut().zip(words.iter()) {
+      *code = match word.trim() {
+        "RICE0" => 0,
+        "RICE1" => 1,
+        "RICE2" => 2,
+        "RICE3" => 3,
+        _ => return Err(X3Error::ArchiveHeaderXMLRiceCode),
+      };
+    }
+
+    let mut th = [0; 3];
+    let words: Vec<&str> = thresholds.as_deref().ok_or(X3Error::ArchiveHeaderXMLInvalid)?.split(',').collect();
+    if words.len() != 3 {
+      return Err(X3Error::ArchiveHeaderXMLInvalid);
+    }
+    for (t, word) in th.iter_mut().zip(words.iter()) {
+      *t = word.trim().parse::<usize>().map_err(|_| X3Error::ArchiveHeaderXMLInvalid)?;
+    }
+
+    Parameters::builder()
+      .block_len(block_len)
+      .blocks_per_frame(Self::max_blocks_per_frame(block_len))
+      .codes(rice_codes)
+      .thresholds(th)
+      .build()
+  }
+
+  //
+  // The largest number of blocks per frame, up to the default, that gives a valid frame.
+  //
+  fn max_blocks_per_frame(block_len: usize) -> usize {
+    Self::DEFAULT_BLOCKS_PER_FRAME.min(u16::MAX as usize / block_len.max(1)).max(1)
+  }
+}
+
+impl Default for Parameters {
+  fn default() -> Self {
     Parameters {
       block_len: Self::DEFAULT_BLOCK_LENGTH,
       blocks_per_frame: Self::DEFAULT_BLOCKS_PER_FRAME,
@@ -107,18 +325,98 @@ impl Parameters {
   }
 }
 
+///
+/// Builds `Parameters`, any value that isn't set keeps its default.  The parameters are
+/// validated by `build`.
+///
+/// ```
+/// use x3::x3::Parameters;
+///
+/// let params = Parameters::builder().block_len(40).thresholds([2, 6, 18]).build().unwrap();
+/// assert_eq!(40 * Parameters::DEFAULT_BLOCKS_PER_FRAME, params.samples_per_frame());
+/// ```
+///
+#[derive(Debug, Clone)]
+pub struct ParametersBuilder {
+  block_len: usize,
+  blocks_per_frame: usize,
+  codes: [usize; 3],
+  thresholds: [usize; 3],
+}
+
+impl ParametersBuilder {
+  /// The number of samples in each block.
+  pub fn block_len(mut self, block_len: usize) -> Self {
+    self.block_len = block_len;
+    self
+  }
+
+  /// The number of blocks in a full frame.
+  pub fn blocks_per_frame(mut self, blocks_per_frame: usize) -> Self {
+    self.blocks_per_frame = blocks_per_frame;
+    self
+  }
+
+  /// The rice code (0 to 3) used for each of the three block types.
+  pub fn codes(mut self, codes: [usize; 3]) -> Self {
+    self.codes = codes;
+    self
+  }
+
+  /// The largest absolute diff encoded with each of the rice codes.
+  pub fn thresholds(mut self, thresholds: [usize; 3]) -> Self {
+    self.thresholds = thresholds;
+    self
+  }
+
+  pub fn build(self) -> Result<Parameters, X3Error> {
+    Parameters::new(self.block_len, self.blocks_per_frame, self.codes, self.thresholds)
+  }
+}
+
+impl Default for ParametersBuilder {
+  fn default() -> Self {
+    ParametersBuilder {
+      block_len: Parameters::DEFAULT_BLOCK_LENGTH,
+      blocks_per_frame: Parameters::DEFAULT_BLOCKS_PER_FRAME,
+      codes: Parameters::DEFAULT_RICE_CODES,
+      thresholds: Parameters::DEFAULT_THRESHOLDS,
+    }
+  }
+}
+
 pub struct Archive {}
 impl Archive {
   /// <Archive Id>
   pub const ID: &'static [u8] = &[0x58, 0x33, 0x41, 0x52, 0x43, 0x48, 0x49, 0x56]; // 'X3ARCHIV'
   pub const ID_LEN: usize = 8;
+
+  /// The source id of the header frame, that holds the XML metadata.
+  pub const XML_SOURCE_ID: u8 = 0;
+
+  /// The source id of the audio, when there is only one stream.
+  pub const WAV_SOURCE_ID: u8 = 1;
 }
 
 pub struct Frame {}
 impl Frame {
   pub const MAX_LENGTH: usize = 0x7fe0;
+
+  ///
+  /// The maximum number of bytes a frame of `num_samples` samples can be encoded to, this
+  /// is when every block is a pass-through block.
+  ///
+  pub fn max_length(num_samples: usize, block_len: usize) -> usize {
+    // Each pass-through block has a 2 bit ftype and a 4 bit BFP header
+    let num_blocks = num_samples.div_ceil(block_len);
+    let payload_bits = Parameters::WAV_BIT_SIZE * (num_samples + 1) + 6 * num_blocks;
+
+    // Allow for the word alignment at the end of the frame
+    FrameHeader::LENGTH + payload_bits / 8 + 2
+  }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FrameHeader {
   /// Source Id of the stream
   pub source_id: u8,
@@ -132,6 +430,9 @@ pub struct FrameHeader {
   /// The length of the frame (bytes)
   pub payload_len: usize,
 
+  /// The time of the first sample (microseconds since the Unix epoch), 0 if unknown
+  pub time: u64,
+
   /// The CRC16 value for the payload
   pub payload_crc: u16,
 }
@@ -155,9 +456,65 @@ impl FrameHeader {
   /// CRC of the encoded payload, all the frames
   pub const P_HEADER_CRC: usize = 16;
   pub const P_PAYLOAD_CRC: usize = 18;
+
+  ///
+  /// Parse a frame header, the key and the header CRC are checked.  Frames with more than
+  /// one channel are accepted.
+  ///
+  pub fn parse(bytes: &[u8]) -> Result<FrameHeader, X3Error> {
+    if bytes.len() < Self::LENGTH {
+      return Err(X3Error::FrameDecodeUnexpectedEnd);
+    }
+    // Calc header CRC
+    let header_crc = crc16(&bytes[0..Self::P_HEADER_CRC]);
+    let expected_header_crc = BigEndian::read_u16(&bytes[Self::P_HEADER_CRC..]);
+    if expected_header_crc != header_crc {
+      return Err(X3Error::FrameHeaderInvalidHeaderCRC);
+    }
+
+    // Read <Frame Key>
+    let x3_archive_key = BigEndian::read_u16(&bytes[Self::P_KEY..]);
+    if x3_archive_key != Self::KEY {
+      return Err(X3Error::FrameHeaderInvalidKey);
+    }
+
+    // <Payload Length>
+    let payload_len = BigEndian::read_u16(&bytes[Self::P_PAYLOAD_SIZE..]) as usize;
+    if payload_len >= Frame::MAX_LENGTH {
+      return Err(X3Error::FrameLength);
+    }
+
+    Ok(FrameHeader {
+      source_id: bytes[Self::P_SOURCE_ID],
+      samples: BigEndian::read_u16(&bytes[Self::P_SAMPLES..]),
+      channels: bytes[Self::P_CHANNELS],
+      payload_len,
+      time: BigEndian::read_u64(&bytes[Self::P_TIME..]),
+      payload_crc: BigEndian::read_u16(&bytes[Self::P_PAYLOAD_CRC..]),
+    })
+  }
+
+  ///
+  /// The bytes of the header, with its header CRC.  `payload_crc` should already be set
+  /// from the payload.
+  ///
+  pub fn to_bytes(&self) -> [u8; Self::LENGTH] {
+    let mut bytes = [0u8; Self::LENGTH];
+    BigEndian::write_u16(&mut bytes[Self::P_KEY..], Self::KEY);
+    bytes[Self::P_SOURCE_ID] = self.source_id;
+    bytes[Self::P_CHANNELS] = self.channels;
+    BigEndian::write_u16(&mut bytes[Self::P_SAMPLES..], self.samples);
+    BigEndian::write_u16(&mut bytes[Self::P_PAYLOAD_SIZE..], self.payload_len as u16);
+    BigEndian::write_u64(&mut bytes[Self::P_TIME..], self.time);
+    let header_crc = crc16(&bytes[0..Self::P_HEADER_CRC]);
+    BigEndian::write_u16(&mut bytes[Self::P_HEADER_CRC..], header_crc);
+    BigEndian::write_u16(&mut bytes[Self::P_PAYLOAD_CRC..], self.payload_crc);
+    bytes
+  }
 }
 
 #[allow(dead_code)]
+#[derive(Debug)]
 pub struct RiceCode {
   pub nsubs: usize,  // number of subcode (suffix) bits
   pub offset: usize, // table offset
@@ -167,6 +524,16 @@ pub struct RiceCode {
   pub inv_len: usize, // The length of inv that is used for this.
 }
 
+impl RiceCode {
+  ///
+  /// The largest absolute diff that can be encoded, the codes can represent one more
+  /// value on one side of zero than the other.
+  ///
+  pub fn max_abs(&self) -> usize {
+    core::cmp::min(self.offset, self.code.len() - 1 - self.offset)
+  }
+}
+
 pub struct RiceCodes {}
 
 // The inverse rice code lookup table is the same for all rice codes, although the lower valued
@@ -233,3 +600,105 @@ impl RiceCodes {
     ]
   }
 }
+
+//
+//
+//            #######
+//               #       ######     ####     #####     ####
+//               #       #         #           #      #
+//               #       #####      ####       #       ####
+//               #       #              #      #           #
+//               #       #         #    #      #      #    #
+//               #       ######     ####       #       ####
+//
+//
+
+#[cfg(test)]
+mod tests {
+  use crate::error::X3Error;
+  use crate::x3::{Parameters, ParametersError};
+
+  fn params_error(result: Result<Parameters, X3Error>) -> ParametersError {
+    match result {
+      Err(X3Error::Parameters(err)) => err,
+      other => panic!("expected a parameters error, got {:?}", other),
+    }
+  }
+
+  #[test]
+  fn test_parameters_validation() {
+    use ParametersError::*;
+
+    let builder = Parameters::builder();
+    assert!(builder.clone().build().is_ok());
+    assert_eq!(InvalidBlockLength(0), params_error(builder.clone().block_len(0).build()));
+    assert_eq!(InvalidBlockLength(257), params_error(builder.clone().block_len(257).build()));
+    assert_eq!(InvalidBlocksPerFrame(0), params_error(builder.clone().blocks_per_frame(0).build()));
+    assert_eq!(
+      TooManySamplesPerFrame(65540),
+      params_error(builder.clone().block_len(20).blocks_per_frame(3277).build())
+    );
+    assert!(builder.clone().block_len(20).blocks_per_frame(3276).build().is_ok());
+    assert_eq!(InvalidRiceCode(4), params_error(builder.clone().codes([0, 1, 4]).build()));
+    assert_eq!(ThresholdOutOfRange(0), params_error(builder.clone().thresholds([7, 8, 20]).build()));
+    assert_eq!(ThresholdOutOfRange(2), params_error(builder.clone().thresholds([3, 8, 28]).build()));
+    assert_eq!(ThresholdsNotIncreasing, params_error(builder.thresholds([3, 2, 20]).build()));
+  }
+
+  #[test]
+  fn test_parameters_xml() {
+    let params = Parameters::builder()
+      .block_len(40)
+      .blocks_per_frame(250)
+      .codes([1, 2, 3])
+      .thresholds([5, 9, 27])
+      .build()
+      .unwrap();
+    let xml = params.to_xml();
+    assert_eq!(
+      "<CODEC TYPE=\"X3\" VERS=\"2\"><BLKLEN>40</BLKLEN><CODES N=\"4\">RICE1,RICE2,RICE3,BFP</CODES>\
+       <FILTER>DIFF</FILTER><NBITS>16</NBITS><T N=\"3\">5,9,27</T></CODEC>",
+      xml
+    );
+
+    let decoded = Parameters::from_xml(&xml).unwrap();
+    assert_eq!(params.block_len, decoded.block_len);
+    assert_eq!(params.codes, decoded.codes);
+    assert_eq!(params.thresholds, decoded.thresholds);
+
+    // The frame size isn't stored, long blocks get fewer blocks per frame
+    let xml = Parameters::builder().block_len(200).blocks_per_frame(100).build().unwrap().to_xml();
+    let decoded = Parameters::from_xml(&xml).unwrap();
+    assert_eq!(200, decoded.block_len);
+    assert!(decoded.blocks_per_frame < Parameters::DEFAULT_BLOCKS_PER_FRAME);
+  }
+
+  #[test]
+  fn test_parameters_xml_invalid() {
+    let xml = Parameters::default().to_xml();
+    assert!(matches!(
+      Parameters::from_xml(&xml.replace("RICE3", "RICE9")),
+      Err(X3Error::ArchiveHeaderXMLRiceCode)
+    ));
+    assert!(matches!(
+      Parameters::from_xml(&xml.replace(",BFP", "")),
+      Err(X3Error::ArchiveHeaderXMLRiceCode)
+    ));
+    assert!(matches!(
+      Parameters::from_xml(&xml.replace("<BLKLEN>20</BLKLEN>", "")),
+      Err(X3Error::ArchiveHeaderXMLInvalid)
+    ));
+    assert!(matches!(
+      Parameters::from_xml(&xml.replace("3,8,20", "3,8")),
+      Err(X3Error::ArchiveHeaderXMLInvalid)
+    ));
+    assert!(matches!(
+      Parameters::from_xml(&xml.replace("3,8,20", "3,8,x")),
+      Err(X3Error::ArchiveHeaderXMLInvalid)
+    ));
+    assert!(matches!(
+      Parameters::from_xml(&xml.replace("3,8,20", "3,2,20")),
+      Err(X3Error::Parameters(ParametersError::ThresholdsNotIncreasing))
+    ));
+  }
+}
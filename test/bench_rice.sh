@@ -0,0 +1,80 @@
+#!/bin/bash
+
+#############################################################################
+#                                                                           #
+#   Rust implementation of the X3 lossless audio compression protocol.      #
+#                                                                           #
+#   Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>              #
+#                                                                           #
+#   This program is free software; you can redistribute it and/or modify    #
+#   it under the terms of the GNU General Public License as published by    #
+#   the Free Software Foundation, either version 3 of the License, or       #
+#   (at your option) any later version.                                     #
+#                                                                           #
+#   This program is distributed in the hope that it will be useful,         #
+#   but WITHOUT ANY WARRANTY; without even the implied warranty of          #
+#   MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the            #
+#   GNU General Public License for more details.                            #
+#                                                                           #
+#   You should have received a copy of the GNU General Public License       #
+#   along with this program. If not, see <http://www.gnu.org/licenses/>.    #
+#                                                                           #
+############################################################################# 
+
+#
+# Compare the decoding time of the default Rice decoder with the table driven
+# decoder (the `fast-rice` feature).  All the .wav files in the directory are
+# encoded, then decoded by both builds.  The output has the same columns as
+# `bench.sh`, with the build as the test params.
+#
+
+SOUND_DIR=$1
+
+TEMP_DIR=$(mktemp -d)
+trap "rm -rf $TEMP_DIR" 0 2 3 15
+
+if [ -z "${SOUND_DIR}" ] || [ ! -d ${SOUND_DIR} ]; then
+    echo
+    echo "Usage:"
+    echo "  bench_rice.sh DIRECTORY"
+    echo
+    echo "Where:"
+    echo "  DIRECTORY is the directory with the source .wav files"
+    exit 1
+fi
+
+# This is not the usual bash/shell time command
+TIME="$(which time) -f %e,%M"
+
+# Build both decoders
+cargo build --release
+cp ../target/release/x3 ${TEMP_DIR}/x3-default
+cargo build --release --features fast-rice
+cp ../target/release/x3 ${TEMP_DIR}/x3-fast-rice
+
+echo "in file,test,in file size (bytes),test params,time,max mem usage (kB),out file size (bytes)"
+
+for WAV in $(ls ${SOUND_DIR}/*.wav)
+do
+    X3A=${TEMP_DIR}/$(basename ${WAV}).x3a
+    ${TEMP_DIR}/x3-default --input ${WAV} --output ${X3A} > /dev/null
+
+    for BUILD in default fast-rice
+    do
+        # Get the file size, at the same time as loading the file to memory - hoping it stays there
+        SIZE=$(cat ${X3A} | wc -c)
+
+        echo -n "$(basename ${X3A}),x3a_to_wav,${SIZE},${BUILD},"
+
+        OUT_FILE=${TEMP_DIR}/$(basename ${WAV})
+        TIMING="$(${TIME} ${TEMP_DIR}/x3-${BUILD} --input ${X3A} --output ${OUT_FILE} 2>&1 > /dev/null)"
+        echo -n "${TIMING},"
+
+        SIZE=$(cat ${OUT_FILE} | wc -c)
+        echo ${SIZE}
+
+        rm ${OUT_FILE}
+    done
+
+    rm ${X3A}
+done
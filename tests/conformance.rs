@@ -0,0 +1,569 @@
+/**************************************************************************
+ *                                                                        *
+ * Rust implementation of the X3 lossless audio compression protocol.     *
+ *                                                                        *
+ * Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>             *
+ *                                                                        *
+ * This program is free software; you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the           *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.   *
+ *                                                                        *
+ **************************************************************************/
+
+//!
+//! Conformance tests.  Synthetic signals are round tripped through `wav_to_x3a` and
+//! `x3a_to_wav`, and the `.x3a` vectors in `tests/vectors` are decoded and compared with
+//! their golden `.wav` files.
+//!
+//! The vectors in `tests/vectors` were written by this crate.  The vectors from the
+//! reference Matlab or C tools go in `tests/vectors/reference`, there are none yet so
+//! `test_reference_vectors` is ignored, see `tests/vectors/README.md`.
+//!
+
+use std::f64::consts::PI;
+use std::fs;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use x3::decodefile::{
+  decode_x3a_bytes, x3a_sensors_to_csv, x3a_source_to_wav, x3a_to_wav, x3a_to_wav_parallel, X3aReader,
+  X3_WRITE_BUFFER_SIZE,
+};
+use x3::decoder::read_frame_header;
+use x3::encodefile::{
+  encode_x3a_to_vec, raw_to_x3a, wav_to_x3a, wav_to_x3a_parallel, wav_to_x3a_with_sensors, wavs_to_x3a,
+  wavs_to_x3a_with_metadata,
+};
+use x3::error::X3Error;
+use x3::metadata::ArchiveMetadata;
+use x3::raw::RawX3Writer;
+use x3::sensor::Sensor;
+use x3::wavchunks::{Bext, WavChunks};
+use x3::x3::{Archive, Decoder, FrameHeader, Parameters, X3aSpec};
+
+#[path = "../src/testutil.rs"]
+mod testutil;
+use testutil::noise;
+
+const SAMPLE_RATE: u32 = 48000;
+
+/// The number of samples in a frame with the default parameters.
+const FRAME_LEN: usize = 10000;
+
+fn silence(len: usize) -> Vec<i16> {
+  vec![0; len]
+}
+
+fn sine(len: usize, freq: f64, amplitude: f64) -> Vec<i16> {
+  (0..len)
+    .map(|i| {
+      let v = amplitude * (2.0 * PI * freq * i as f64 / SAMPLE_RATE as f64).sin();
+      v.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16
+    })
+    .collect()
+}
+
+fn chirp(len: usize) -> Vec<i16> {
+  // Sweep from 10 Hz to 20 kHz
+  let (f0, f1) = (10.0, 20000.0);
+  let duration = len as f64 / SAMPLE_RATE as f64;
+  (0..len)
+    .map(|i| {
+      let t = i as f64 / SAMPLE_RATE as f64;
+      let phase = 2.0 * PI * (f0 * t + (f1 - f0) * t * t / (2.0 * duration));
+      (20000.0 * phase.sin()) as i16
+    })
+    .collect()
+}
+
+fn dc_steps(len: usize) -> Vec<i16> {
+  let levels = [0, i16::MAX, i16::MIN, -1, 1, 12345, -23456];
+  (0..len).map(|i| levels[(i / 777) % levels.len()]).collect()
+}
+
+fn temp_path(name: &str, ext: &str) -> PathBuf {
+  testutil::temp_path(&format!("conformance_{}.{}", name, ext))
+}
+
+fn write_wav(path: &Path, samples: &[i16]) {
+  write_wav_with_rate(path, samples, SAMPLE_RATE);
+}
+
+fn write_wav_with_rate(path: &Path, samples: &[i16], sample_rate: u32) {
+  let spec = hound::WavSpec {
+    channels: 1,
+    sample_rate,
+    bits_per_sample: 16,
+    sample_format: hound::SampleFormat::Int,
+  };
+  let mut writer = hound::WavWriter::create(path, spec).unwrap();
+  for sample in samples {
+    writer.write_sample(*sample).unwrap();
+  }
+  writer.finalize().unwrap();
+}
+
+fn read_wav(path: &Path) -> (hound::WavSpec, Vec<i16>) {
+  let mut reader = hound::WavReader::open(path).unwrap();
+  let samples = reader.samples::<i16>().map(|s| s.unwrap()).collect();
+  (reader.spec(), samples)
+}
+
+//
+// Round trip `samples` through the .x3a format, serially and with several threads.
+//
+fn assert_round_trip(name: &str, samples: &[i16]) {
+  let wav_filename = temp_path(name, "wav");
+  let x3a_filename = temp_path(name, "x3a");
+  let out_filename = temp_path(&format!("{}_out", name), "wav");
+  write_wav(&wav_filename, samples);
+
+  wav_to_x3a(&wav_filename, &x3a_filename).unwrap();
+  let x3a_bytes = fs::read(&x3a_filename).unwrap();
+
+  // The parallel encoder must produce the same archive
+  wav_to_x3a_parallel(&wav_filename, &x3a_filename, 3).unwrap();
+  assert_eq!(x3a_bytes, fs::read(&x3a_filename).unwrap(), "{}: parallel encode", name);
+
+  for num_threads in &[1, 3] {
+    x3a_to_wav_parallel(&x3a_filename, &out_filename, *num_threads).unwrap();
+    let (spec, decoded) = read_wav(&out_filename);
+    assert_eq!(SAMPLE_RATE, spec.sample_rate, "{}", name);
+    assert_eq!(1, spec.channels, "{}", name);
+    assert_eq!(samples.len(), decoded.len(), "{}: {} threads", name, num_threads);
+    assert!(samples == &decoded[..], "{}: {} threads, samples differ", name, num_threads);
+  }
+
+  // The archive can be decoded and encoded in memory
+  let (spec, decoded) = decode_x3a_bytes(&x3a_bytes).unwrap();
+  assert_eq!(SAMPLE_RATE, spec.sample_rate, "{}", name);
+  assert!(samples == &decoded[..], "{}: in memory, samples differ", name);
+  assert_eq!(x3a_bytes, encode_x3a_to_vec(samples, &spec).unwrap(), "{}: in memory encode", name);
+
+  fs::remove_file(&wav_filename).unwrap();
+  fs::remove_file(&x3a_filename).unwrap();
+  fs::remove_file(&out_filename).unwrap();
+}
+
+#[test]
+fn test_silence() {
+  assert_round_trip("silence", &silence(3 * FRAME_LEN));
+}
+
+#[test]
+fn test_sine() {
+  assert_round_trip("sine_quiet", &sine(25_000, 440.0, 30.0));
+  assert_round_trip("sine_loud", &sine(25_000, 1000.0, 30000.0));
+  assert_round_trip("sine_high", &sine(25_000, 15000.0, 10000.0));
+}
+
+#[test]
+fn test_chirp() {
+  assert_round_trip("chirp", &chirp(4 * SAMPLE_RATE as usize));
+}
+
+#[test]
+fn test_white_noise() {
+  // Each block is a pass-through block, the archive is larger than the wav
+  assert_round_trip("white_noise", &noise(2 * FRAME_LEN + 17, 0x1234_5678));
+}
+
+#[test]
+fn test_clipping() {
+  // A sine that is clipped at the full scale values
+  assert_round_trip("clipping", &sine(3 * FRAME_LEN, 100.0, 60000.0));
+}
+
+#[test]
+fn test_dc_steps() {
+  assert_round_trip("dc_steps", &dc_steps(2 * FRAME_LEN + 1));
+}
+
+#[test]
+fn test_short_and_odd_frames() {
+  for len in &[1, 2, 3, 19, 20, 21, 22, 41, 999, FRAME_LEN - 1, FRAME_LEN, FRAME_LEN + 1, 2 * FRAME_LEN + 19] {
+    assert_round_trip(&format!("short_{}", len), &noise(*len, *len as u32));
+    assert_round_trip(&format!("short_sine_{}", len), &sine(*len, 300.0, 1000.0));
+  }
+}
+
+#[test]
+fn test_wav_chunks() {
+  let wav_filename = temp_path("chunks", "wav");
+  let x3a_filename = temp_path("chunks", "x3a");
+  let out_filename = temp_path("chunks_out", "wav");
+  let samples = sine(3 * FRAME_LEN + 7, 440.0, 1000.0);
+  write_wav(&wav_filename, &samples);
+
+  // 2021-06-01 plus 10 seconds of samples
+  let chunks = WavChunks {
+    info: vec![("INAM".to_string(), "Dawn chorus".to_string())],
+    bext: Some(Bext {
+      description: "Recorder 7".to_string(),
+      origination_date: "2021-06-01".to_string(),
+      origination_time: "00:00:10".to_string(),
+      time_reference: 10 * SAMPLE_RATE as u64,
+      version: 1,
+      ..Default::default()
+    }),
+    ixml: Some("<BWFXML><NOTE>Low tide</NOTE></BWFXML>\n".to_string()),
+  };
+  chunks.append_to(&wav_filename).unwrap();
+  let start_time = 1_622_505_610_000_000u64;
+
+  for num_threads in &[1, 3] {
+    wav_to_x3a_parallel(&wav_filename, &x3a_filename, *num_threads).unwrap();
+    assert_eq!(chunks, WavChunks::from_metadata(X3aReader::open(&x3a_filename).unwrap().metadata()));
+
+    // Each frame has the time of its first sample
+    let x3a_bytes = fs::read(&x3a_filename).unwrap();
+    let mut p = Archive::ID.len();
+    let mut times = Vec::new();
+    while p < x3a_bytes.len() {
+      let header = read_frame_header(&x3a_bytes[p..]).unwrap();
+      times.push(header.time);
+      p += FrameHeader::LENGTH + header.payload_len;
+    }
+    let expected: Vec<u64> = (0..4).map(|i| start_time + i * FRAME_LEN as u64 * 1_000_000 / SAMPLE_RATE as u64).collect();
+    assert_eq!(0, times[0], "the XML header has no time");
+    assert_eq!(expected, times[1..]);
+
+    x3a_to_wav_parallel(&x3a_filename, &out_filename, *num_threads).unwrap();
+    assert_eq!(chunks, WavChunks::read(&out_filename).unwrap());
+    assert!(samples == read_wav(&out_filename).1);
+  }
+
+  fs::remove_file(&wav_filename).unwrap();
+  fs::remove_file(&x3a_filename).unwrap();
+  fs::remove_file(&out_filename).unwrap();
+}
+
+#[test]
+fn test_multiple_sources() {
+  let wav_filenames = [temp_path("source_1", "wav"), temp_path("source_2", "wav")];
+  let x3a_filename = temp_path("sources", "x3a");
+  let out_filename = temp_path("sources_out", "wav");
+
+  // A second of a 96 kHz hydrophone and a 240 kHz hydrophone, with its own parameters
+  let rates = [96_000u32, 240_000];
+  let wavs = [sine(96_000, 440.0, 2000.0), noise(240_000, 7).iter().map(|s| s / 64).collect()];
+  for i in 0..2 {
+    write_wav_with_rate(&wav_filenames[i], &wavs[i], rates[i]);
+  }
+  let mut metadata = ArchiveMetadata::new();
+  metadata.set_source_params(2, &Parameters::builder().block_len(50).build().unwrap());
+  wavs_to_x3a_with_metadata(&wav_filenames, x3a_filename.clone(), &metadata).unwrap();
+
+  // The frames are interleaved in time order, each with its source id and one channel
+  let x3a_bytes = fs::read(&x3a_filename).unwrap();
+  let header = read_frame_header(&x3a_bytes[Archive::ID.len()..]).unwrap();
+  assert_eq!((0, 0), (header.source_id, header.channels));
+  let mut p = Archive::ID.len() + FrameHeader::LENGTH + header.payload_len;
+  let mut positions = [0usize; 2];
+  let mut last_time = 0.0;
+  while p < x3a_bytes.len() {
+    let header = read_frame_header(&x3a_bytes[p..]).unwrap();
+    assert_eq!(1, header.channels);
+    let i = header.source_id as usize - 1;
+    let time = positions[i] as f64 / rates[i] as f64;
+    assert!(time >= last_time);
+    last_time = time;
+    positions[i] += header.samples as usize;
+    p += FrameHeader::LENGTH + header.payload_len;
+  }
+  assert_eq!([96_000, 240_000], positions);
+
+  // Each source can be decoded on its own
+  for (i, source_id) in [1u8, 2].iter().enumerate() {
+    for num_threads in &[1, 3] {
+      x3a_source_to_wav(x3a_filename.clone(), *source_id, out_filename.clone(), *num_threads).unwrap();
+      let (spec, decoded) = read_wav(&out_filename);
+      assert_eq!(rates[i], spec.sample_rate);
+      assert!(wavs[i] == decoded, "source {}: {} threads, samples differ", source_id, num_threads);
+    }
+  }
+  assert!(x3a_source_to_wav(x3a_filename.clone(), 3, out_filename.clone(), 1).is_err());
+
+  // Or demultiplexed in one pass
+  let mut reader = X3aReader::open(&x3a_filename).unwrap();
+  assert_eq!(vec![1, 2], reader.source_ids());
+  assert_eq!(50, reader.source_spec(2).unwrap().params.block_len);
+  let mut decoded = [Vec::new(), Vec::new()];
+  let mut wav = [0i16; X3_WRITE_BUFFER_SIZE];
+  while let Some((source_id, samples)) = reader.decode_next_source_frame(&mut wav).unwrap() {
+    decoded[source_id as usize - 1].extend_from_slice(&wav[0..samples]);
+  }
+  assert!(wavs[0] == decoded[0] && wavs[1] == decoded[1]);
+
+  // Only the selected source is decoded by `x3a_to_wav`
+  x3a_to_wav(&x3a_filename, &out_filename).unwrap();
+  assert!(wavs[0] == read_wav(&out_filename).1);
+
+  for filename in wav_filenames.iter().chain(&[x3a_filename, out_filename]) {
+    fs::remove_file(filename).unwrap();
+  }
+}
+
+#[test]
+fn test_sensors() {
+  let wav_filename = temp_path("sensors", "wav");
+  let x3a_filename = temp_path("sensors", "x3a");
+  let out_filename = temp_path("sensors_out", "wav");
+
+  // 10 seconds of audio, with depth, a 3-axis accelerometer and temperature
+  let audio = sine(10 * SAMPLE_RATE as usize, 440.0, 1000.0);
+  write_wav(&wav_filename, &audio);
+  let depth: Vec<i16> = (0..100).map(|i| 1000 + i * 3).collect();
+  let accel: Vec<Vec<i16>> = (0..3).map(|axis| sine(500, 2.0 + axis as f64, 2000.0)).collect();
+  let temperature = vec![1523i16, 1522, 1522, 1521, 1519, 1519, 1518, 1518, 1517, 1517];
+
+  let sensors = [
+    Sensor::new(2, "depth", "cm", 10).with_description("Pressure sensor"),
+    Sensor::new(3, "accel_x", "mg", 50),
+    Sensor::new(4, "accel_y", "mg", 50),
+    Sensor::new(5, "accel_z", "mg", 50),
+    Sensor::new(6, "temperature", "0.01 degC", 1),
+  ];
+  let samples: [&[i16]; 5] = [&depth, &accel[0], &accel[1], &accel[2], &temperature];
+  let streams: Vec<(&Sensor, &[i16])> = sensors.iter().zip(samples.iter().copied()).collect();
+  wav_to_x3a_with_sensors(&wav_filename, &x3a_filename, &streams).unwrap();
+
+  // The audio is decoded as before
+  x3a_to_wav(&x3a_filename, &out_filename).unwrap();
+  assert!(audio == read_wav(&out_filename).1);
+
+  let reader = X3aReader::open(&x3a_filename).unwrap();
+  assert_eq!(vec![1, 2, 3, 4, 5, 6], reader.source_ids());
+  assert_eq!("Pressure sensor", Sensor::all_from_metadata(reader.metadata())[0].description);
+
+  // Each sensor is exported to CSV
+  let csv_filenames = x3a_sensors_to_csv(&x3a_filename, std::env::temp_dir()).unwrap();
+  assert_eq!(temp_path("sensors_depth", "csv"), csv_filenames[0]);
+  for ((sensor, samples), csv_filename) in streams.iter().zip(&csv_filenames) {
+    let csv = fs::read_to_string(csv_filename).unwrap();
+    let mut lines = csv.lines();
+    assert_eq!(format!("time (s),{} ({})", sensor.name, sensor.unit), lines.next().unwrap());
+    let rows: Vec<(f64, i16)> = lines
+      .map(|line| {
+        let (time, value) = line.split_once(',').unwrap();
+        (time.parse().unwrap(), value.parse().unwrap())
+      })
+      .collect();
+    assert_eq!(samples.len(), rows.len());
+    for (i, (time, value)) in rows.iter().enumerate() {
+      assert_eq!(i as f64 / sensor.sample_rate as f64, *time);
+      assert_eq!(samples[i], *value);
+    }
+    fs::remove_file(csv_filename).unwrap();
+  }
+
+  fs::remove_file(&wav_filename).unwrap();
+  fs::remove_file(&x3a_filename).unwrap();
+  fs::remove_file(&out_filename).unwrap();
+}
+
+#[test]
+fn test_reader_adapters() {
+  let samples = sine(2 * FRAME_LEN + 123, 1000.0, 8000.0);
+  let wav_filename = temp_path("adapters", "wav");
+  let x3a_filename = temp_path("adapters", "x3a");
+  write_wav(&wav_filename, &samples);
+  wav_to_x3a(&wav_filename, &x3a_filename).unwrap();
+
+  let mut x3a_reader = X3aReader::open(&x3a_filename).unwrap();
+  let decoded: Vec<i16> = x3a_reader.samples().map(|s| s.unwrap()).collect();
+  assert!(samples == decoded);
+
+  let mut x3a_reader = X3aReader::open(&x3a_filename).unwrap();
+  let lens: Vec<usize> = x3a_reader.frames().map(|f| f.unwrap().header.samples as usize).collect();
+  assert_eq!(vec![FRAME_LEN, FRAME_LEN, 123], lens);
+
+  // The PCM is read in pieces that don't line up with the frames
+  let mut pcm_reader = X3aReader::open(&x3a_filename).unwrap().into_pcm_reader();
+  let mut pcm = Vec::new();
+  let mut buf = [0u8; 999];
+  loop {
+    match pcm_reader.read(&mut buf).unwrap() {
+      0 => break,
+      n => pcm.extend_from_slice(&buf[0..n]),
+    }
+  }
+  let expected: Vec<u8> = samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+  assert!(expected == pcm);
+
+  fs::remove_file(&wav_filename).unwrap();
+  fs::remove_file(&x3a_filename).unwrap();
+}
+
+#[test]
+fn test_unsupported_wavs() {
+  let x3a_filename = temp_path("unsupported", "x3a");
+  let formats = [
+    (1, 24, hound::SampleFormat::Int),
+    (1, 32, hound::SampleFormat::Float),
+    (2, 16, hound::SampleFormat::Int),
+  ];
+  for (i, (channels, bits_per_sample, sample_format)) in formats.iter().enumerate() {
+    let wav_filename = temp_path(&format!("unsupported_{}", i), "wav");
+    let spec = hound::WavSpec {
+      channels: *channels,
+      sample_rate: SAMPLE_RATE,
+      bits_per_sample: *bits_per_sample,
+      sample_format: *sample_format,
+    };
+    let mut writer = hound::WavWriter::create(&wav_filename, spec).unwrap();
+    for _ in 0..100 {
+      match sample_format {
+        hound::SampleFormat::Int => writer.write_sample(1000).unwrap(),
+        hound::SampleFormat::Float => writer.write_sample(0.5f32).unwrap(),
+      }
+    }
+    writer.finalize().unwrap();
+
+    // The wav is rejected, rather than panicking
+    let results = [
+      wavs_to_x3a(&[&wav_filename], &x3a_filename),
+      wav_to_x3a_with_sensors(&wav_filename, &x3a_filename, &[]),
+    ];
+    for result in results {
+      match channels {
+        1 => assert!(matches!(result, Err(X3Error::UnsupportedWav)), "{:?}", spec),
+        _ => assert!(matches!(result, Err(X3Error::MoreThanOneChannel)), "{:?}", spec),
+      }
+    }
+    fs::remove_file(&wav_filename).unwrap();
+  }
+
+  // As is a file that isn't a wav
+  let wav_filename = temp_path("unsupported_text", "wav");
+  fs::write(&wav_filename, "not a wav file").unwrap();
+  assert!(matches!(wav_to_x3a(&wav_filename, &x3a_filename), Err(X3Error::Hound(_))));
+  fs::remove_file(&wav_filename).unwrap();
+  let _ = fs::remove_file(&x3a_filename);
+}
+
+#[test]
+fn test_raw_channels() {
+  // Two channels, interleaved
+  let left = sine(2 * FRAME_LEN + 321, 440.0, 12000.0);
+  let right = noise(left.len(), 77);
+  let samples: Vec<i16> = left.iter().zip(&right).flat_map(|(l, r)| [*l, *r]).collect();
+
+  let mut writer = RawX3Writer::new(Vec::new(), Parameters::default(), SAMPLE_RATE, 2).unwrap();
+  writer.write_samples(&samples).unwrap();
+  let raw_filename = temp_path("raw_channels", "bin");
+  let x3a_filename = temp_path("raw_channels", "x3a");
+  let out_filename = temp_path("raw_channels_out", "wav");
+  fs::write(&raw_filename, writer.finish().unwrap()).unwrap();
+
+  assert_eq!(0, raw_to_x3a(&raw_filename, &x3a_filename, &Parameters::default(), SAMPLE_RATE, 2).unwrap());
+  let x3a_reader = X3aReader::open(&x3a_filename).unwrap();
+  assert_eq!(2, x3a_reader.spec().channels);
+
+  x3a_to_wav(&x3a_filename, &out_filename).unwrap();
+  let (spec, decoded) = read_wav(&out_filename);
+  assert_eq!((SAMPLE_RATE, 2), (spec.sample_rate, spec.channels));
+  assert!(samples == decoded);
+
+  x3a_to_wav_parallel(&x3a_filename, &out_filename, 3).unwrap();
+  let (_, decoded) = read_wav(&out_filename);
+  assert!(samples == decoded);
+
+  fs::remove_file(&raw_filename).unwrap();
+  fs::remove_file(&x3a_filename).unwrap();
+  fs::remove_file(&out_filename).unwrap();
+}
+
+#[test]
+fn test_in_memory_frames() {
+  let samples = chirp(3 * FRAME_LEN + 7);
+  let spec = X3aSpec {
+    sample_rate: SAMPLE_RATE,
+    params: Parameters::default(),
+    channels: 1,
+  };
+  let x3a_bytes = encode_x3a_to_vec(&samples, &spec).unwrap();
+
+  // The payloads are borrowed from the archive
+  let x3_decoder = Decoder::new(&x3a_bytes).unwrap();
+  let frames: Vec<_> = x3_decoder.frames().map(|frame| frame.unwrap()).collect();
+  assert_eq!(4, frames.len());
+  let lens: Vec<usize> = frames.iter().map(|f| f.header.samples as usize).collect();
+  assert_eq!(vec![FRAME_LEN, FRAME_LEN, FRAME_LEN, 7], lens);
+  let range = x3a_bytes.as_ptr_range();
+  assert!(frames.iter().all(|f| range.contains(&f.payload.as_ptr())));
+
+  // A frame with a bad payload is counted and skipped
+  let mut bad = x3a_bytes.clone();
+  let last = bad.len() - 1;
+  bad[last] ^= 1;
+  let mut x3_decoder = Decoder::new(&bad).unwrap();
+  assert!(samples[0..3 * FRAME_LEN] == x3_decoder.decode().unwrap()[..]);
+  assert_eq!(1, x3_decoder.frame_errors());
+
+  // A bad frame header is an error
+  bad[x3a_bytes.len() - frames[3].payload.len() - FrameHeader::LENGTH + 4] ^= 1;
+  assert!(decode_x3a_bytes(&bad).is_err());
+}
+
+//
+// Decode each `<name>.x3a` in `dir` and compare it with `<name>.wav`, returns the number
+// of vectors.
+//
+fn check_vectors(dir: &Path) -> usize {
+  let mut x3a_files: Vec<PathBuf> = fs::read_dir(dir)
+    .unwrap()
+    .map(|entry| entry.unwrap().path())
+    .filter(|path| path.extension().is_some_and(|ext| ext == "x3a"))
+    .collect();
+  x3a_files.sort();
+
+  for x3a_filename in &x3a_files {
+    let name = x3a_filename.file_stem().unwrap().to_str().unwrap().to_string();
+    let golden_filename = x3a_filename.with_extension("wav");
+    let out_filename = temp_path(&format!("vector_{}", name), "wav");
+
+    x3a_to_wav(x3a_filename, &out_filename).unwrap();
+    let (golden_spec, golden) = read_wav(&golden_filename);
+    let (spec, decoded) = read_wav(&out_filename);
+    fs::remove_file(&out_filename).unwrap();
+
+    assert_eq!(golden_spec.sample_rate, spec.sample_rate, "{}", name);
+    assert_eq!(golden.len(), decoded.len(), "{}", name);
+    assert!(golden == decoded, "{}: samples differ from the golden PCM", name);
+
+    let (_, decoded) = decode_x3a_bytes(&fs::read(x3a_filename).unwrap()).unwrap();
+    assert!(golden == decoded, "{}: in memory, samples differ from the golden PCM", name);
+  }
+
+  x3a_files.len()
+}
+
+//
+// The vectors written by this crate, these only catch changes to the format it writes.
+//
+#[test]
+fn test_regression_vectors() {
+  let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("vectors");
+  assert_eq!(2, check_vectors(&dir));
+}
+
+//
+// The vectors written by the Matlab `x3new.m` or the C `x3` tools.  None are checked in
+// yet, so this fails until they are, see `tests/vectors/reference/README.md`.
+//
+#[test]
+#[ignore = "no vectors from the reference X3 tools are checked in yet"]
+fn test_reference_vectors() {
+  let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests").join("vectors").join("reference");
+  assert!(check_vectors(&dir) > 0, "no reference vectors in {}", dir.display());
+}
@@ -0,0 +1,199 @@
+/**************************************************************************
+ *                                                                        *
+ * Rust implementation of the X3 lossless audio compression protocol.     *
+ *                                                                        *
+ * Copyright (C) 2019 Simon M. Werner <simonwerner@gmail.com>             *
+ *                                                                        *
+ * This program is free software; you can redistribute it and/or modify   *
+ * it under the terms of the GNU General Public License as published by   *
+ * the Free Software Foundation, either version 3 of the License, or      *
+ * (at your option) any later version.                                    *
+ *                                                                        *
+ * This program is distributed in the hope that it will be useful,        *
+ * but WITHOUT ANY WARRANTY; without even the implied warranty of         *
+ * MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE. See the           *
+ * GNU General Public License for more details.                           *
+ *                                                                        *
+ * You should have received a copy of the GNU General Public License      *
+ * along with this program. If not, see <http://www.gnu.org/licenses/>.   *
+ *                                                                        *
+ **************************************************************************/
+
+//!
+//! Property based tests, `decode_frame(encode_frame(x)) == x` for arbitrary samples and
+//! every set of parameters that `Parameters::new` accepts.
+//!
+
+use proptest::prelude::*;
+
+use x3::bitpacker::BitPacker;
+use x3::decoder::{decode_frame, read_frame_header};
+use x3::encoder::encode_frame;
+use x3::x3::{Frame, FrameHeader, Parameters, RiceCodes};
+
+#[path = "../src/testutil.rs"]
+mod testutil;
+use testutil::{noise, sine};
+
+//
+// Encode `wav` as frames, then decode them again.  Long frames of noise are split so the
+// payload stays under `Frame::MAX_LENGTH`, each frame must pass the header checks.
+//
+fn round_trip(wav: &[i16], params: &Parameters) -> Vec<i16> {
+  let mut decoded = vec![0i16; wav.len()];
+  let mut p = 0;
+  while p < wav.len() {
+    let mut bp = BitPacker::with_capacity(0);
+    let samples = encode_frame(&wav[p..], &mut 0, &mut bp, params, &mut [0; 6]).unwrap();
+    assert!(samples > 0);
+    let frame = bp.into_vec();
+
+    let header = read_frame_header(&frame).unwrap();
+    assert_eq!(samples, header.samples as usize);
+    assert_eq!(frame.len(), FrameHeader::LENGTH + header.payload_len);
+
+    let wav_buf = &mut decoded[p..(p + samples)];
+    let decoded_samples = decode_frame(&frame[FrameHeader::LENGTH..], wav_buf, params, samples).unwrap();
+    assert_eq!(Some(samples), decoded_samples);
+    p += samples;
+  }
+  decoded
+}
+
+//
+// Any parameters that `Parameters::new` accepts.
+//
+fn parameters() -> impl Strategy<Value = Parameters> {
+  let max_thresh = RiceCodes::get([3, 3, 3])[0].max_abs();
+  (
+    1..=Parameters::MAX_BLOCK_LENGTH,
+    1..=20usize,
+    [0..4usize, 0..4usize, 0..4usize],
+    [0..=max_thresh, 0..=max_thresh, 0..=max_thresh],
+  )
+    .prop_filter_map("rejected by Parameters::new", |(block_len, blocks_per_frame, codes, thresholds)| {
+      Parameters::new(block_len, blocks_per_frame, codes, thresholds).ok()
+    })
+}
+
+//
+// Samples with a mix of small and large diffs, so every block type is used.
+//
+fn samples(len: impl Strategy<Value = usize>) -> impl Strategy<Value = Vec<i16>> {
+  len.prop_flat_map(|len| {
+    prop_oneof![
+      prop::collection::vec(any::<i16>(), len),
+      prop::collection::vec(-40i16..40, len).prop_map(|diffs| integrate(&diffs)),
+      prop::collection::vec((any::<i16>(), 0..16u32), len).prop_map(|values| {
+        let diffs: Vec<i16> = values.iter().map(|(v, shift)| v >> shift).collect();
+        integrate(&diffs)
+      }),
+    ]
+  })
+}
+
+fn integrate(diffs: &[i16]) -> Vec<i16> {
+  let mut value = 0i16;
+  diffs
+    .iter()
+    .map(|diff| {
+      value = value.wrapping_add(*diff);
+      value
+    })
+    .collect()
+}
+
+proptest! {
+  #[test]
+  fn prop_round_trip(params in parameters(), wav in samples(1..600usize)) {
+    prop_assert_eq!(&wav, &round_trip(&wav, &params));
+  }
+
+  #[test]
+  fn prop_round_trip_block_edges(params in parameters(), edge in 0..8usize, seed in any::<i16>()) {
+    // Lengths either side of a whole block, and of a whole frame
+    let block_len = params.block_len;
+    let frame_len = block_len * params.blocks_per_frame;
+    let edges = [1, block_len - 1, block_len, block_len + 1, block_len + 2, frame_len - 1, frame_len, frame_len + 1];
+    let len = edges[edge];
+    let wav: Vec<i16> = (0..len).map(|i| seed.wrapping_mul(i as i16) >> (i % 16)).collect();
+    prop_assert_eq!(&wav, &round_trip(&wav, &params));
+  }
+
+  #[test]
+  fn prop_round_trip_single_sample(params in parameters(), sample in any::<i16>()) {
+    prop_assert_eq!(vec![sample], round_trip(&[sample], &params));
+  }
+}
+
+proptest! {
+  #![proptest_config(ProptestConfig::with_cases(8))]
+
+  #[test]
+  fn prop_round_trip_max_samples(params in parameters(), wav in samples(Just(u16::MAX as usize))) {
+    // The most samples a frame header can hold, noise is split into several frames
+    prop_assert_eq!(&wav, &round_trip(&wav, &params));
+  }
+}
+
+#[test]
+fn test_encode_frame_too_long() {
+  let wav = vec![0i16; u16::MAX as usize + 1];
+  let mut bp = BitPacker::with_capacity(0);
+  let params = Parameters::default();
+  assert!(encode_frame(&wav, &mut 0, &mut bp, &params, &mut [0; 6]).is_err());
+}
+
+#[test]
+fn test_encode_frame_empty() {
+  let mut bp = BitPacker::with_capacity(0);
+  let params = Parameters::default();
+  encode_frame(&[], &mut 0, &mut bp, &params, &mut [0; 6]).unwrap();
+  assert!(bp.into_vec().is_empty());
+}
+
+#[test]
+fn test_round_trip_long_blocks() {
+  // A quiet, stationary signal, longer blocks need fewer block headers
+  let wav = sine(10_000, 0.002, 200.0, 1);
+
+  let mut frame_lens = Vec::new();
+  for block_len in &[20, 100, Parameters::MAX_BLOCK_LENGTH] {
+    let params = Parameters::builder().block_len(*block_len).blocks_per_frame(40).build().unwrap();
+    assert_eq!(wav, round_trip(&wav, &params));
+
+    let mut bp = BitPacker::with_capacity(0);
+    encode_frame(&wav, &mut 0, &mut bp, &params, &mut [0; 6]).unwrap();
+    frame_lens.push(bp.into_vec().len());
+  }
+  assert!(frame_lens[0] > frame_lens[1] && frame_lens[1] > frame_lens[2], "{:?}", frame_lens);
+}
+
+#[test]
+fn test_decode_frame_block_len_too_long() {
+  let wav = [1i16, 2, 3];
+  let mut params = Parameters::default();
+  let mut bp = BitPacker::with_capacity(0);
+  encode_frame(&wav, &mut 0, &mut bp, &params, &mut [0; 6]).unwrap();
+  let frame = bp.into_vec();
+
+  params.block_len = Parameters::MAX_BLOCK_LENGTH + 1;
+  let mut decoded = [0i16; 3];
+  assert!(decode_frame(&frame[FrameHeader::LENGTH..], &mut decoded, &params, wav.len()).is_err());
+}
+
+#[test]
+fn test_encode_frame_byte_budget() {
+  // White noise is all pass-through blocks, a full frame would be about twice `Frame::MAX_LENGTH`
+  let wav = noise(u16::MAX as usize, 0x2545_f491);
+  let params = Parameters::builder().block_len(Parameters::MAX_BLOCK_LENGTH).blocks_per_frame(255).build().unwrap();
+
+  let mut bp = BitPacker::with_capacity(0);
+  let samples = encode_frame(&wav, &mut 0, &mut bp, &params, &mut [0; 6]).unwrap();
+  let frame = bp.into_vec();
+  assert!(samples < wav.len());
+  assert!(frame.len() - FrameHeader::LENGTH < Frame::MAX_LENGTH);
+  assert!(frame.len() > Frame::MAX_LENGTH - 2 * Parameters::MAX_BLOCK_LENGTH - FrameHeader::LENGTH);
+
+  assert_eq!(wav, round_trip(&wav, &params));
+}